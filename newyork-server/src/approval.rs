@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use curv::BigInt;
+use rocket::http::Status;
+use web3::types::U256;
+
+use crate::auth::guards::ValidatedUser;
+use crate::policy::{self, ApprovalRule};
+use crate::routes::ecdsa::EcdsaStruct;
+use crate::storage::backend::Storage;
+use crate::storage::{db, gc};
+use crate::utils::errors::HttpError;
+use crate::utils::transaction::UnsignedTx;
+use crate::AppConfig;
+
+/// How long a parked signature waits for its approvals. The ephemeral key of
+/// the signing session expires on the same schedule. Once an approver
+/// decided, the request is kept for good as the trail of that decision.
+pub const APPROVAL_TTL: std::time::Duration = gc::PROTOCOL_STATE_TTL;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum ApprovalStatus {
+    PendingApproval,
+    Approved,
+    Rejected,
+    Expired,
+    Signed,
+}
+
+/// One approver's vote, the audit trail of a parked signature.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ApprovalDecision {
+    pub by: String,
+    pub approved: bool,
+    pub at: u64,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+/// The message and child key a signature is approved for.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SignTarget {
+    pub message: BigInt,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+}
+
+/// A `sign_second` call parked by the approval rule of the wallet's policy,
+/// stored under the signing session id. The maker repeats the identical call
+/// once it is `Approved` to get the signature.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PendingApproval {
+    /// The signing session id.
+    pub approval_id: String,
+    pub user_id: String,
+    pub wallet_id: String,
+    #[serde(flatten)]
+    pub target: SignTarget,
+    pub tx: Option<UnsignedTx>,
+    pub cost: U256,
+    pub required: u32,
+    pub approvers: Vec<String>,
+    pub decisions: Vec<ApprovalDecision>,
+    pub status: ApprovalStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl PendingApproval {
+    fn sign_id(&self) -> String {
+        format!("{}_{}", self.wallet_id, self.approval_id)
+    }
+
+    /// `PendingApproval` turns into `Expired` once `expires_at` is reached,
+    /// when the sweeper may purge the signing session.
+    fn refresh_status(mut self, now: u64) -> PendingApproval {
+        if self.status == ApprovalStatus::PendingApproval && now >= self.expires_at {
+            self.status = ApprovalStatus::Expired;
+        }
+        self
+    }
+
+    fn may_decide(&self, approver: &str) -> bool {
+        approver != self.user_id
+            && (self.approvers.is_empty() || self.approvers.iter().any(|a| a == approver))
+    }
+
+    fn approvals(&self) -> usize {
+        self.decisions.iter().filter(|d| d.approved).count()
+    }
+}
+
+async fn load(db: &dyn Storage, user_id: &str, sign_id: &str) -> Result<Option<PendingApproval>> {
+    let pending: Option<PendingApproval> =
+        db::get(db, user_id, sign_id, &EcdsaStruct::PendingApproval).await?;
+    Ok(pending.map(|pending| pending.refresh_status(gc::now())))
+}
+
+fn conflict(message: String) -> anyhow::Error {
+    anyhow!(HttpError::conflict(message))
+}
+
+/// Whether the signing session `sign_id` was approved for exactly `target`.
+/// Rejected, expired or mismatching sessions are refused.
+pub async fn approved_for(
+    db: &dyn Storage,
+    user_id: &str,
+    sign_id: &str,
+    target: &SignTarget,
+) -> Result<bool> {
+    let pending = match load(db, user_id, sign_id).await? {
+        Some(pending) => pending,
+        None => return Ok(false),
+    };
+    if &pending.target != target {
+        return Err(conflict(format!(
+            "Signing session {} is parked for a different message",
+            pending.approval_id
+        )));
+    }
+    match pending.status {
+        ApprovalStatus::Approved => Ok(true),
+        ApprovalStatus::PendingApproval => Ok(false),
+        ApprovalStatus::Signed => Err(conflict(format!(
+            "Approved signature {} was already produced",
+            pending.approval_id
+        ))),
+        ApprovalStatus::Rejected => Err(anyhow!(HttpError::new(
+            Status::Forbidden,
+            format!("Signature {} was rejected", pending.approval_id)
+        ))),
+        ApprovalStatus::Expired => Err(conflict(format!(
+            "Approval {} expired, start a new signing session",
+            pending.approval_id
+        ))),
+    }
+}
+
+/// Parks the signature, or returns it if it already waits. The signing
+/// session and its ephemeral key are kept until the approval expires, however
+/// late in their own TTL it was asked for.
+pub async fn park(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    session_id: &str,
+    target: &SignTarget,
+    tx: &UnsignedTx,
+    rule: ApprovalRule,
+) -> Result<PendingApproval> {
+    let sign_id = format!("{}_{}", id, session_id);
+    if let Some(pending) = load(db, user_id, &sign_id).await? {
+        return Ok(pending);
+    }
+
+    let now = gc::now();
+    let pending = PendingApproval {
+        approval_id: session_id.to_string(),
+        user_id: user_id.to_string(),
+        wallet_id: id.to_string(),
+        target: target.clone(),
+        tx: Some(tx.clone()),
+        cost: policy::max_cost(tx),
+        required: rule.required,
+        approvers: rule.approvers,
+        decisions: vec![],
+        status: ApprovalStatus::PendingApproval,
+        created_at: now,
+        expires_at: now + APPROVAL_TTL.as_secs(),
+    };
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, &sign_id, &EcdsaStruct::PendingApproval, &pending)?;
+    batch.index_approval(session_id, user_id, id, Some(APPROVAL_TTL))?;
+    batch.index_pending_approval(session_id, user_id, id, APPROVAL_TTL)?;
+    batch.expire_at(
+        user_id,
+        &sign_id,
+        &EcdsaStruct::PendingApproval,
+        pending.expires_at,
+    );
+    for record in [
+        EcdsaStruct::Session,
+        EcdsaStruct::EphEcKeyPair,
+        EcdsaStruct::EphKeyGenFirstMsg,
+    ] {
+        if db::exists(db, user_id, &sign_id, &record).await? {
+            batch.expire_at(user_id, &sign_id, &record, pending.expires_at);
+        }
+    }
+    db::insert_many(db, batch).await?;
+    Ok(pending)
+}
+
+/// Marks an approved signature as produced, in the batch finishing it.
+pub async fn mark_signed(
+    db: &dyn Storage,
+    batch: &mut db::Batch,
+    user_id: &str,
+    sign_id: &str,
+) -> Result<()> {
+    if let Some(mut pending) = load(db, user_id, sign_id).await? {
+        pending.status = ApprovalStatus::Signed;
+        keep(batch, &pending)?;
+    }
+    Ok(())
+}
+
+/// Writes back a request approvers decided on, without the TTL so the
+/// sweeper leaves the decisions alone. Only requests still awaiting
+/// decisions stay listed.
+fn keep(batch: &mut db::Batch, pending: &PendingApproval) -> Result<()> {
+    let sign_id = pending.sign_id();
+    let record = &EcdsaStruct::PendingApproval;
+    batch.insert(&pending.user_id, &sign_id, record, pending)?;
+    batch.expire(&pending.user_id, &sign_id, record, None);
+    if pending.status != ApprovalStatus::PendingApproval {
+        batch.unindex_pending_approval(&pending.approval_id);
+    }
+    batch.index_approval(
+        &pending.approval_id,
+        &pending.user_id,
+        &pending.wallet_id,
+        None,
+    )
+}
+
+fn visible_to(pending: &PendingApproval, user: &ValidatedUser, approver_role: &str) -> bool {
+    pending.user_id == user.user_id
+        || (user.roles.iter().any(|role| role == approver_role)
+            && pending.may_decide(&user.user_id))
+}
+
+/// The caller's own signatures awaiting approval plus, for approvers, those
+/// they may decide on. Decided ones are fetched by id with `get`.
+pub async fn list(state: &AppConfig, user: &ValidatedUser) -> Result<Vec<PendingApproval>> {
+    let db = state.db.as_ref();
+    let mut visible = vec![];
+    for (session_id, user_id, id) in db::pending_approvals(db).await? {
+        let sign_id = format!("{}_{}", id, session_id);
+        if let Some(pending) = load(db, &user_id, &sign_id).await? {
+            if pending.status == ApprovalStatus::PendingApproval
+                && visible_to(&pending, user, &state.approver_role)
+            {
+                visible.push(pending);
+            }
+        }
+    }
+    visible.sort_by_key(|pending| pending.created_at);
+    Ok(visible)
+}
+
+pub async fn get(
+    state: &AppConfig,
+    user: &ValidatedUser,
+    approval_id: &str,
+) -> Result<PendingApproval> {
+    let not_found = || {
+        anyhow!(HttpError::new(
+            Status::NotFound,
+            format!("No approval {}", approval_id)
+        ))
+    };
+    let db = state.db.as_ref();
+    let (user_id, id) = db::approval_owner(db, approval_id)
+        .await?
+        .ok_or_else(not_found)?;
+    let pending = load(db, &user_id, &format!("{}_{}", id, approval_id))
+        .await?
+        .ok_or_else(not_found)?;
+    if !visible_to(&pending, user, &state.approver_role) {
+        return Err(not_found());
+    }
+    Ok(pending)
+}
+
+/// Records an approver's vote. One rejection rejects, `required` approvals
+/// approve. Serialized with the policy checks of the wallet by its policy
+/// lock, the approval is read again once it is held.
+pub async fn decide(
+    state: &AppConfig,
+    approver: &ValidatedUser,
+    approval_id: &str,
+    approved: bool,
+    comment: Option<String>,
+) -> Result<PendingApproval> {
+    let wallet = get(state, approver, approval_id).await?;
+    let _guard = state
+        .policy_locks
+        .lock(&wallet.user_id, &wallet.wallet_id)
+        .await;
+    let mut pending = get(state, approver, approval_id).await?;
+    if !pending.may_decide(&approver.user_id) {
+        return Err(anyhow!(HttpError::new(
+            Status::Forbidden,
+            format!("{} is not an approver of {}", approver.user_id, approval_id)
+        )));
+    }
+    if pending.status != ApprovalStatus::PendingApproval {
+        return Err(conflict(format!(
+            "Approval {} is {:?}",
+            approval_id, pending.status
+        )));
+    }
+    if pending.decisions.iter().any(|d| d.by == approver.user_id) {
+        return Err(conflict(format!(
+            "{} already decided on {}",
+            approver.user_id, approval_id
+        )));
+    }
+
+    pending.decisions.push(ApprovalDecision {
+        by: approver.user_id.clone(),
+        approved,
+        at: gc::now(),
+        comment,
+    });
+    if !approved {
+        pending.status = ApprovalStatus::Rejected;
+    } else if pending.approvals() >= pending.required as usize {
+        pending.status = ApprovalStatus::Approved;
+    }
+    let mut batch = db::Batch::new();
+    keep(&mut batch, &pending)?;
+    db::insert_many(state.db.as_ref(), batch).await?;
+    Ok(pending)
+}
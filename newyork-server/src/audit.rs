@@ -0,0 +1,389 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use curv::arithmetic::traits::Converter;
+use curv::BigInt;
+use rocket::http::Status;
+use web3::signing::keccak256;
+use web3::types::H256;
+
+use crate::storage::backend::{BatchOp, Storage};
+use crate::storage::gc;
+use crate::storage::keys::{self, KeyClass};
+use crate::utils::errors::HttpError;
+use crate::AppConfig;
+
+/// Entries live in the audit column family as `entry/<seq u64 BE>`, so a
+/// scan returns them in chain order. `user/<user_id>/<seq>` indexes them per
+/// user and `head` holds the last entry.
+const ENTRY: &[u8] = b"entry";
+const USER: &[u8] = b"user";
+const HEAD: &[u8] = b"head";
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+fn entry_key(seq: u64) -> Vec<u8> {
+    keys::encode(KeyClass::Audit, &[ENTRY, &seq.to_be_bytes()])
+}
+
+fn user_prefix(user_id: &str) -> Vec<u8> {
+    keys::encode(KeyClass::Audit, &[USER, user_id.as_bytes()])
+}
+
+fn user_key(user_id: &str, seq: u64) -> Vec<u8> {
+    keys::encode(
+        KeyClass::Audit,
+        &[USER, user_id.as_bytes(), &seq.to_be_bytes()],
+    )
+}
+
+fn head_key() -> Vec<u8> {
+    keys::encode(KeyClass::Audit, &[HEAD])
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditOp {
+    KeyGenFirst,
+    KeyGenSecond,
+    KeyGenThird,
+    KeyGenFourth,
+    ChainCodeFirst,
+    ChainCodeSecond,
+    SignFirst,
+    SignSecond,
+    RotateFirst,
+    RotateSecond,
+    RotateThird,
+    RotateFinalize,
+    RotateRollback,
+    Recover,
+    AllocateChild,
+    RegisterChild,
+    ListChildren,
+    PublicKey,
+    ListWallets,
+    GetWallet,
+    PolicySet,
+    PolicyRemoved,
+    ApprovalDecided,
+    TxParams,
+    TxBuild,
+    TxAssemble,
+    TxSend,
+}
+
+/// What the wallet's policy made of a signing request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum PolicyOutcome {
+    Granted,
+    /// Granted after the approvers agreed.
+    Approved,
+    PendingApproval,
+    Refused {
+        reason: String,
+    },
+}
+
+/// One operation, as the handler reports it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub user_id: String,
+    pub wallet_id: Option<String>,
+    pub operation: AuditOp,
+    /// Hex of the signed message, or of the transaction hash.
+    pub message_hash: Option<String>,
+    pub policy: Option<PolicyOutcome>,
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(user_id: &str, wallet_id: Option<&str>, operation: AuditOp) -> AuditEvent {
+        AuditEvent {
+            user_id: user_id.to_string(),
+            wallet_id: wallet_id.map(str::to_string),
+            operation,
+            message_hash: None,
+            policy: None,
+            detail: None,
+        }
+    }
+
+    pub fn message(mut self, message: &BigInt) -> AuditEvent {
+        self.message_hash = Some(format!("0x{}", hex::encode(BigInt::to_bytes(message))));
+        self
+    }
+
+    pub fn hash(mut self, hash: H256) -> AuditEvent {
+        self.message_hash = Some(format!("{:?}", hash));
+        self
+    }
+
+    pub fn policy(mut self, outcome: PolicyOutcome) -> AuditEvent {
+        self.policy = Some(outcome);
+        self
+    }
+
+    pub fn detail(mut self, detail: String) -> AuditEvent {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+/// An event chained to its predecessor: `hash` is the keccak256 of the JSON
+/// of `seq`, `at`, the event and `prev_hash`. The first entry links to zero.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    pub prev_hash: H256,
+    pub hash: H256,
+}
+
+#[derive(Serialize)]
+struct Chained<'a> {
+    seq: u64,
+    at: u64,
+    event: &'a AuditEvent,
+    prev_hash: &'a H256,
+}
+
+impl AuditEntry {
+    pub fn compute_hash(&self) -> Result<H256> {
+        let body = serde_json::to_vec(&Chained {
+            seq: self.seq,
+            at: self.at,
+            event: &self.event,
+            prev_hash: &self.prev_hash,
+        })?;
+        Ok(H256(keccak256(&body)))
+    }
+}
+
+/// The last entry of the log, so truncating it is noticed too.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub hash: H256,
+}
+
+async fn read<T: serde::de::DeserializeOwned>(db: &dyn Storage, key: &[u8]) -> Result<Option<T>> {
+    match db.get(key).await? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Appends that lose the race for the head this often in a row give up.
+const APPEND_ATTEMPTS: usize = 16;
+
+/// Chains `event` onto the log. The entry is only written while the head is
+/// still the one it was chained to, so every entry gets the next sequence
+/// number; an append that lost the race chains onto the new head.
+pub async fn append(state: &AppConfig, event: AuditEvent) -> Result<AuditEntry> {
+    let db = state.db.as_ref();
+    for _ in 0..APPEND_ATTEMPTS {
+        let current = db.get(&head_key()).await?;
+        let (seq, prev_hash) = match &current {
+            Some(value) => {
+                let head: AuditHead = serde_json::from_slice(value)?;
+                (head.seq + 1, head.hash)
+            }
+            None => (0, H256::zero()),
+        };
+        let mut entry = AuditEntry {
+            seq,
+            at: gc::now(),
+            event: event.clone(),
+            prev_hash,
+            hash: H256::zero(),
+        };
+        entry.hash = entry.compute_hash()?;
+        let head = AuditHead {
+            seq,
+            hash: entry.hash,
+        };
+        let ops = vec![
+            BatchOp::Put {
+                key: entry_key(seq),
+                value: serde_json::to_vec(&entry)?,
+            },
+            BatchOp::Put {
+                key: user_key(&entry.event.user_id, seq),
+                value: vec![],
+            },
+            BatchOp::Put {
+                key: head_key(),
+                value: serde_json::to_vec(&head)?,
+            },
+        ];
+        if db.batch_if(vec![(head_key(), current)], ops).await? {
+            return Ok(entry);
+        }
+    }
+    Err(anyhow!(
+        "Audit log head moved {} times in a row, giving up",
+        APPEND_ATTEMPTS
+    ))
+}
+
+/// `append` for reads and refused requests: a failure to log them is
+/// reported rather than failing the request.
+pub async fn record(state: &AppConfig, event: AuditEvent) {
+    if let Err(e) = append(state, event.clone()).await {
+        error!("Failed to append to the audit log: {:#} - {:?}", e, event);
+    }
+}
+
+/// `append` for signing and rotation, fail closed: a signature or key
+/// version the log does not show is never handed out.
+pub async fn record_or_fail(state: &AppConfig, event: AuditEvent) -> Result<()> {
+    if let Err(e) = append(state, event.clone()).await {
+        error!("Failed to append to the audit log: {:#} - {:?}", e, event);
+        return Err(anyhow!(HttpError::new(
+            Status::ServiceUnavailable,
+            "The audit log is unavailable, try again later".to_string()
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    /// Pass as `after` for the next page, unset on the last one.
+    pub next: Option<u64>,
+}
+
+/// The entries of `user_id` with a sequence number above `after`, oldest
+/// first.
+pub async fn page(
+    db: &dyn Storage,
+    user_id: &str,
+    after: Option<u64>,
+    limit: usize,
+) -> Result<AuditPage> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let mut seqs = vec![];
+    for (key, _) in db.scan(&user_prefix(user_id)).await? {
+        let seq = match keys::decode(&key)
+            .as_ref()
+            .map(|(_, parts)| parts.as_slice())
+        {
+            Some([_, _, seq]) => u64::from_be_bytes(
+                (*seq)
+                    .try_into()
+                    .map_err(|_| anyhow!("Malformed audit index key"))?,
+            ),
+            _ => return Err(anyhow!("Malformed audit index key")),
+        };
+        if let Some(after) = after {
+            if seq <= after {
+                continue;
+            }
+        }
+        seqs.push(seq);
+        if seqs.len() > limit {
+            break;
+        }
+    }
+
+    let next = if seqs.len() > limit {
+        seqs.truncate(limit);
+        seqs.last().copied()
+    } else {
+        None
+    };
+    let mut entries = vec![];
+    for seq in seqs {
+        let entry = read(db, &entry_key(seq))
+            .await?
+            .ok_or_else(|| anyhow!("Audit entry {} is indexed but missing", seq))?;
+        entries.push(entry);
+    }
+    Ok(AuditPage { entries, next })
+}
+
+/// The whole log and its head, for `verify`.
+pub async fn read_all(db: &dyn Storage) -> Result<(Vec<AuditEntry>, Option<AuditHead>)> {
+    let entries = db
+        .scan(&keys::encode(KeyClass::Audit, &[ENTRY]))
+        .await?
+        .into_iter()
+        .map(|(_, value)| -> Result<AuditEntry> { Ok(serde_json::from_slice(&value)?) })
+        .collect::<Result<Vec<AuditEntry>>>()?;
+    let head = read(db, &head_key()).await?;
+    Ok((entries, head))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChainError {
+    /// An entry is missing or out of place.
+    Gap { expected: u64, found: u64 },
+    /// The entry does not link to the hash of its predecessor.
+    BrokenLink { seq: u64 },
+    /// The entry's content does not match its hash.
+    Tampered { seq: u64 },
+    /// The log does not end where the head says.
+    HeadMismatch {
+        head: Option<AuditHead>,
+        last: Option<u64>,
+    },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Gap { expected, found } => {
+                write!(f, "expected entry {}, found {}", expected, found)
+            }
+            ChainError::BrokenLink { seq } => {
+                write!(f, "entry {} does not link to its predecessor", seq)
+            }
+            ChainError::Tampered { seq } => write!(f, "entry {} does not match its hash", seq),
+            ChainError::HeadMismatch { head, last } => {
+                write!(f, "log ends at entry {:?} but the head is {:?}", last, head)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// Checks the hash chain from the first entry to the head and returns the
+/// number of entries.
+pub fn verify(entries: &[AuditEntry], head: Option<&AuditHead>) -> Result<u64, ChainError> {
+    let mut prev_hash = H256::zero();
+    for (expected, entry) in (0u64..).zip(entries) {
+        if entry.seq != expected {
+            return Err(ChainError::Gap {
+                expected,
+                found: entry.seq,
+            });
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(ChainError::BrokenLink { seq: entry.seq });
+        }
+        if entry.compute_hash().ok() != Some(entry.hash) {
+            return Err(ChainError::Tampered { seq: entry.seq });
+        }
+        prev_hash = entry.hash;
+    }
+
+    let last = entries.last();
+    let consistent = match (head, last) {
+        (None, None) => true,
+        (Some(head), Some(last)) => head.seq == last.seq && head.hash == last.hash,
+        _ => false,
+    };
+    if !consistent {
+        return Err(ChainError::HeadMismatch {
+            head: head.cloned(),
+            last: last.map(|entry| entry.seq),
+        });
+    }
+    Ok(entries.len() as u64)
+}
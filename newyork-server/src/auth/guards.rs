@@ -2,6 +2,10 @@ use rocket::http::Status;
 use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest, Request};
 
+use crate::utils::errors::HttpError;
+use crate::utils::requests::authenticate;
+use crate::AppConfig;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AuthPayload {
     pub token: String,
@@ -9,22 +13,16 @@ pub struct AuthPayload {
 }
 const TOKEN_TYPE: &str = "Bearer";
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for AuthPayload {
-    type Error = ();
-
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let authorization_header: &str = match request.headers().get_one("Authorization") {
-            Some(header) => header,
-            None => return Outcome::Failure((Status::Unauthorized, ())),
-        };
+impl AuthPayload {
+    /// Bearer token plus the optional `user_id` header, which only has to
+    /// match the token when present.
+    fn from_headers(request: &Request<'_>) -> Option<AuthPayload> {
+        let authorization_header: &str = request.headers().get_one("Authorization")?;
 
         let mut header_parts = authorization_header.split_whitespace();
-        let token_type = header_parts.next();
-
-        if let Some(tk_type) = token_type {
+        if let Some(tk_type) = header_parts.next() {
             if !tk_type.eq(TOKEN_TYPE) {
-                return Outcome::Failure((Status::Unauthorized, ()));
+                return None;
             }
         }
 
@@ -33,13 +31,128 @@ impl<'r> FromRequest<'r> for AuthPayload {
 
         debug!("Auth token - user id: {} - {}", token, user_id);
 
-        if token.is_empty() || user_id.is_empty() {
-            return Outcome::Failure((Status::Unauthorized, ()));
+        if token.is_empty() {
+            return None;
         }
 
-        Outcome::Success(AuthPayload {
+        Some(AuthPayload {
             token: token.to_owned(),
             user_id: user_id.to_owned(),
         })
     }
 }
+
+/// The caller's validated identity. Validation happens in the guard itself,
+/// so a route taking a `ValidatedUser` cannot run for an unauthenticated call.
+#[derive(Debug, Clone)]
+pub struct ValidatedUser {
+    pub user_id: String,
+    pub token: String,
+    pub roles: Vec<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ValidatedUser {
+    type Error = anyhow::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let auth_payload = match AuthPayload::from_headers(request) {
+            Some(auth_payload) => auth_payload,
+            None => {
+                return Outcome::Failure((
+                    Status::Unauthorized,
+                    anyhow::anyhow!("Missing bearer token"),
+                ))
+            }
+        };
+        let state = match request.rocket().state::<AppConfig>() {
+            Some(state) => state,
+            None => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    anyhow::anyhow!("AppConfig is not managed"),
+                ))
+            }
+        };
+
+        match authenticate(state, &auth_payload).await {
+            Ok(identity) => Outcome::Success(ValidatedUser {
+                user_id: identity.user_id,
+                token: auth_payload.token,
+                roles: identity.roles,
+            }),
+            Err(e) => {
+                let status = match e.downcast_ref::<HttpError>() {
+                    Some(http_error) => http_error.status,
+                    None => Status::InternalServerError,
+                };
+                warn!("Rejected request to {}: {:#}", request.uri(), e);
+                Outcome::Failure((status, e))
+            }
+        }
+    }
+}
+
+/// The `ValidatedUser` of `request` if it holds the role `role_of` picks
+/// from the config.
+async fn with_role(
+    request: &Request<'_>,
+    role_of: fn(&AppConfig) -> &str,
+) -> request::Outcome<ValidatedUser, anyhow::Error> {
+    let user = match request.guard::<ValidatedUser>().await {
+        Outcome::Success(user) => user,
+        Outcome::Failure(failure) => return Outcome::Failure(failure),
+        Outcome::Forward(forward) => return Outcome::Forward(forward),
+    };
+    let role = match request.rocket().state::<AppConfig>() {
+        Some(state) => role_of(state),
+        None => {
+            return Outcome::Failure((
+                Status::InternalServerError,
+                anyhow::anyhow!("AppConfig is not managed"),
+            ))
+        }
+    };
+    if !user.roles.iter().any(|held| held == role) {
+        warn!(
+            "User {} without the {} role called {}",
+            user.user_id,
+            role,
+            request.uri()
+        );
+        return Outcome::Failure((Status::Forbidden, anyhow::anyhow!("{} role required", role)));
+    }
+    Outcome::Success(user)
+}
+
+/// A `ValidatedUser` holding the configured `approver_role`, for the
+/// approval endpoints.
+#[derive(Debug, Clone)]
+pub struct Approver(pub ValidatedUser);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Approver {
+    type Error = anyhow::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        with_role(request, |state| state.approver_role.as_str())
+            .await
+            .map(Approver)
+    }
+}
+
+/// A `ValidatedUser` holding the configured `policy_admin_role`, the only
+/// one who sets or removes wallet policies.
+#[derive(Debug, Clone)]
+pub struct PolicyAdmin(pub ValidatedUser);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PolicyAdmin {
+    type Error = anyhow::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        with_role(request, |state| state.policy_admin_role.as_str())
+            .await
+            .map(PolicyAdmin)
+    }
+}
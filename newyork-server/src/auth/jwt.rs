@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{
+    decode, decode_header, get_current_timestamp, Algorithm, DecodingKey, Validation,
+};
+use tokio::sync::RwLock;
+
+/// Unknown key ids trigger a JWKS reload at most this often, so garbage
+/// tokens cannot be used to hammer the key endpoint.
+const MIN_REFRESH_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: u64,
+    pub iss: String,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl Claims {
+    /// String value of `sub` or any other top level claim, e.g. `email`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "sub" => Some(&self.sub),
+            "iss" => Some(&self.iss),
+            _ => self.extra.get(name)?.as_str(),
+        }
+    }
+
+    /// A claim holding either one string or a list of them, e.g. the
+    /// `cognito:groups` of a user.
+    pub fn strings(&self, name: &str) -> Vec<String> {
+        match self.extra.get(name) {
+            Some(serde_json::Value::String(value)) => vec![value.clone()],
+            Some(serde_json::Value::Array(values)) => values
+                .iter()
+                .filter_map(|value| Some(value.as_str()?.to_string()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Reads the claims of a token without checking its signature, only for
+/// tokens that are validated elsewhere (the remote HCMC check). Expired
+/// tokens are still rejected.
+pub fn unverified_claims(token: &str) -> Result<Claims> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    Ok(decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation)?.claims)
+}
+
+/// How bearer tokens are checked. `Remote` is the original behaviour of asking
+/// HCMC on every request.
+pub enum AuthMode {
+    Remote,
+    Local(Arc<JwtVerifier>),
+    /// Local verification, asking HCMC only while the JWKS cannot be loaded.
+    LocalWithRemoteFallback(Arc<JwtVerifier>),
+}
+
+impl AuthMode {
+    pub fn verifier(&self) -> Option<Arc<JwtVerifier>> {
+        match self {
+            AuthMode::Remote => None,
+            AuthMode::Local(verifier) | AuthMode::LocalWithRemoteFallback(verifier) => {
+                Some(verifier.clone())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum JwksSource {
+    File(String),
+    Url(String),
+}
+
+/// The key set could not be loaded, as opposed to a token that failed
+/// verification. Only this error may fall back to the remote HCMC check.
+#[derive(Debug)]
+pub struct JwksUnavailable(pub String);
+
+impl fmt::Display for JwksUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "JWKS unavailable: {}", self.0)
+    }
+}
+
+impl std::error::Error for JwksUnavailable {}
+
+/// Verifies RS256 / ES256 bearer tokens against a cached JWKS (e.g. the
+/// Cognito user pool keys), checking signature, `exp`, `iss` and `aud`.
+pub struct JwtVerifier {
+    source: JwksSource,
+    issuer: String,
+    audience: String,
+    keys: RwLock<HashMap<String, Jwk>>,
+    last_refresh: AtomicU64,
+}
+
+impl JwtVerifier {
+    pub fn new(source: JwksSource, issuer: String, audience: String) -> JwtVerifier {
+        JwtVerifier {
+            source,
+            issuer,
+            audience,
+            keys: RwLock::new(HashMap::new()),
+            last_refresh: AtomicU64::new(0),
+        }
+    }
+
+    async fn fetch(&self) -> Result<JwkSet> {
+        let jwks = match &self.source {
+            JwksSource::File(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+            JwksSource::Url(url) => {
+                reqwest::get(url)
+                    .await?
+                    .error_for_status()?
+                    .json::<JwkSet>()
+                    .await?
+            }
+        };
+        Ok(jwks)
+    }
+
+    /// Reloads the key set, keeping the cached keys if loading fails.
+    /// Returns how many keys are cached.
+    pub async fn refresh(&self) -> Result<usize> {
+        self.last_refresh
+            .store(get_current_timestamp(), Ordering::Relaxed);
+        let jwks = self
+            .fetch()
+            .await
+            .map_err(|e| anyhow!(JwksUnavailable(format!("{:?}: {}", self.source, e))))?;
+        let keys: HashMap<String, Jwk> = jwks
+            .keys
+            .into_iter()
+            .filter_map(|jwk| Some((jwk.common.key_id.clone()?, jwk)))
+            .collect();
+        let count = keys.len();
+        *self.keys.write().await = keys;
+        Ok(count)
+    }
+
+    async fn key(&self, kid: &str) -> Result<Jwk> {
+        if let Some(jwk) = self.keys.read().await.get(kid) {
+            return Ok(jwk.clone());
+        }
+        // Key rotated on the issuer side, or nothing loaded yet.
+        if get_current_timestamp()
+            >= self.last_refresh.load(Ordering::Relaxed) + MIN_REFRESH_INTERVAL_SECS
+        {
+            self.refresh().await?;
+        }
+        let keys = self.keys.read().await;
+        match keys.get(kid) {
+            Some(jwk) => Ok(jwk.clone()),
+            None if keys.is_empty() => Err(anyhow!(JwksUnavailable("no keys loaded".to_string()))),
+            None => Err(anyhow!("Unknown token key id {}", kid)),
+        }
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<Claims> {
+        let header = decode_header(token)?;
+        let kid = header
+            .kid
+            .ok_or_else(|| anyhow!("Token header has no key id"))?;
+        let jwk = self.key(&kid).await?;
+
+        match (header.alg, &jwk.algorithm) {
+            (Algorithm::RS256, AlgorithmParameters::RSA(_))
+            | (Algorithm::ES256, AlgorithmParameters::EllipticCurve(_)) => {}
+            (alg, _) => {
+                return Err(anyhow!(
+                    "Token algorithm {:?} not allowed for key {}",
+                    alg,
+                    kid
+                ))
+            }
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        let data = decode::<Claims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+        Ok(data.claims)
+    }
+}
+
+/// Refetches the issuer's JWKS every `interval` (`JWKS_REFRESH_INTERVAL_SECS`),
+/// so signing keys the issuer rotates in are known before tokens use them.
+pub async fn refresh_task(verifier: Arc<JwtVerifier>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match verifier.refresh().await {
+            Ok(count) => debug!("Loaded {} JWKS keys", count),
+            Err(e) => error!("JWKS refresh failed: {:#?}", e),
+        }
+    }
+}
@@ -12,17 +12,37 @@ extern crate log;
 #[macro_use]
 extern crate time_test;
 
+pub mod approval;
+pub mod audit;
 pub mod auth;
+pub mod pdl;
+pub mod policy;
+pub mod rotation;
 pub mod routes;
 pub mod server;
+pub mod session;
 pub mod storage;
 pub mod tests;
 pub mod utils;
 
 pub struct AppConfig {
-    pub db: storage::db::DB,
+    pub db: std::sync::Arc<dyn storage::backend::Storage>,
     pub hcmc_api: String,
+    pub auth: auth::jwt::AuthMode,
+    pub user_claim: String,
+    pub role_claim: String,
+    pub approver_role: String,
+    pub policy_admin_role: String,
     pub alchemy_api: String,
+    pub gc_stats: std::sync::Arc<storage::gc::GcStats>,
+    /// Held from a protocol step's session check until its batch is written.
+    pub session_locks: utils::locks::KeyedLocks,
+    /// Per wallet, serialize child allocation.
+    pub hd_locks: utils::locks::KeyedLocks,
+    /// Per wallet, serialize policy checks with the spends they book.
+    pub policy_locks: utils::locks::KeyedLocks,
+    /// Per wallet, serialize the key version swaps of rotations.
+    pub rotation_locks: utils::locks::KeyedLocks,
 }
 
-pub type AnyhowError = rocket::response::Debug<anyhow::Error>;
+pub use utils::errors::AnyhowError;
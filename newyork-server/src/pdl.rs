@@ -0,0 +1,268 @@
+//! Interactive proof that party 1's `c_key` encrypts the discrete log of its
+//! public share `Q1 = x1 * G`, the PDL rounds of Lindell 2017 keygen.
+//!
+//! 1. Party 2 picks `a < q`, `b < q^2`, sends `c' = a * c_key + Enc(b)` and a
+//!    commitment `c''` to `a + b * q`, and keeps `Q' = a * Q1 + b * G`.
+//! 2. Party 1 decrypts `alpha = Dec(c')` and commits to `Q^ = alpha * G`.
+//! 3. Party 2 opens `a` and `b`.
+//! 4. Party 1 checks `alpha = a * x1 + b` and opens `Q^`, party 2 checks
+//!    `Q^ = Q'`.
+//!
+//! A `c_key` encrypting anything but `x1` decrypts to an `alpha` whose `Q^`
+//! is not `Q'`. Party 1 only reveals `Q^` once `c'` turned out to be formed
+//! honestly, so party 2 learns nothing about `x1` from a crafted `c'`.
+
+use std::fmt;
+
+use anyhow::Result;
+use curv::arithmetic::traits::{Modulo, Samplable};
+use curv::cryptographic_primitives::commitments::hash_commitment::HashCommitment;
+use curv::cryptographic_primitives::commitments::traits::Commitment;
+use curv::elliptic::curves::secp256_k1::{Secp256k1Scalar, FE, GE};
+use curv::elliptic::curves::traits::{ECPoint, ECScalar};
+use curv::BigInt;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+
+const BLINDNESS_BITS: usize = 256;
+
+/// Party 2's challenge, `c_tag = a * c_key + Enc(b)` and `c_tag_tag`, the
+/// commitment to `a + b * q`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Party2PDLFirstMsg {
+    pub c_tag: BigInt,
+    pub c_tag_tag: BigInt,
+}
+
+/// Party 1's commitment to `Q^ = Dec(c_tag) * G`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Party1PDLFirstMsg {
+    pub c_hat: BigInt,
+}
+
+/// Party 2's opening of `c_tag_tag`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Party2PDLSecondMsg {
+    pub a: BigInt,
+    pub b: BigInt,
+    pub blindness: BigInt,
+}
+
+/// Party 1's opening of `c_hat`, kept between the rounds and sent as its
+/// second message.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PDLDecommit {
+    pub q_hat: GE,
+    pub blindness: BigInt,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PDLError {
+    /// `a` and `b` do not open party 2's commitment or are out of range.
+    ChallengeMismatch,
+    /// `c_tag` does not decrypt to `a * x1 + b`.
+    AlphaMismatch,
+    /// `Q^` does not open party 1's commitment.
+    CommitmentMismatch,
+    /// `Q^` is not `a * Q1 + b * G`: `c_key` does not encrypt `x1`.
+    QHatMismatch,
+}
+
+impl fmt::Display for PDLError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            PDLError::ChallengeMismatch => "a and b do not open the challenge commitment",
+            PDLError::AlphaMismatch => "the challenge does not decrypt to a * x1 + b",
+            PDLError::CommitmentMismatch => "Q^ does not open the commitment",
+            PDLError::QHatMismatch => "c_key does not encrypt the discrete log of Q1",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for PDLError {}
+
+/// Paillier secret key, as serialized in `party_one::PaillierKeyPair`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PaillierSecret {
+    p: BigInt,
+    q: BigInt,
+}
+
+#[derive(Deserialize)]
+struct PaillierKeyPairSecret {
+    dk: PaillierSecret,
+}
+
+#[derive(Deserialize)]
+struct EcKeyPairSecret {
+    secret_share: FE,
+}
+
+/// What party 1 needs for the PDL rounds, stored at `/ecdsa/keygen/<id>/second`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PDLProver {
+    x1: BigInt,
+    dk: PaillierSecret,
+}
+
+impl PDLProver {
+    /// kms keeps `x1` and the Paillier secret key private, they are read back
+    /// from the records' serialized form.
+    pub fn new(
+        ec_key_pair: &party_one::EcKeyPair,
+        paillier_key_pair: &party_one::PaillierKeyPair,
+    ) -> Result<PDLProver> {
+        let ec_key_pair: EcKeyPairSecret =
+            serde_json::from_value(serde_json::to_value(ec_key_pair)?)?;
+        let paillier_key_pair: PaillierKeyPairSecret =
+            serde_json::from_value(serde_json::to_value(paillier_key_pair)?)?;
+        Ok(PDLProver {
+            x1: ec_key_pair.secret_share.to_big_int(),
+            dk: paillier_key_pair.dk,
+        })
+    }
+
+    /// Decrypts `alpha` and commits to `alpha * G`. `alpha` and the
+    /// decommitment are kept for `second_message`.
+    pub fn first_message(
+        &self,
+        challenge: &Party2PDLFirstMsg,
+    ) -> (Party1PDLFirstMsg, PDLDecommit, BigInt) {
+        let alpha = decrypt(&self.dk, &challenge.c_tag);
+        let q_hat = GE::generator().scalar_mul(&scalar(&alpha).get_element());
+        let blindness = BigInt::sample(BLINDNESS_BITS);
+        let c_hat = HashCommitment::create_commitment_with_user_defined_randomness(
+            &q_hat.bytes_compressed_to_big_int(),
+            &blindness,
+        );
+        (
+            Party1PDLFirstMsg { c_hat },
+            PDLDecommit { q_hat, blindness },
+            alpha,
+        )
+    }
+
+    /// Opens `Q^` once `a` and `b` show that `c_tag` was `a * c_key + Enc(b)`.
+    pub fn second_message(
+        &self,
+        challenge: &Party2PDLFirstMsg,
+        opening: &Party2PDLSecondMsg,
+        alpha: &BigInt,
+        decommit: PDLDecommit,
+    ) -> Result<PDLDecommit, PDLError> {
+        let q = Secp256k1Scalar::q();
+        let zero = BigInt::from(0);
+        if opening.a < zero
+            || opening.a >= q
+            || opening.b < zero
+            || opening.b >= q.clone() * q.clone()
+            || challenge_commitment(&opening.a, &opening.b, &opening.blindness)
+                != challenge.c_tag_tag
+        {
+            return Err(PDLError::ChallengeMismatch);
+        }
+        if opening.a.clone() * self.x1.clone() + opening.b.clone() != *alpha {
+            return Err(PDLError::AlphaMismatch);
+        }
+        Ok(decommit)
+    }
+}
+
+/// Party 2's side, held by the client between the rounds.
+#[derive(Debug, Clone)]
+pub struct PDLVerifier {
+    a: BigInt,
+    b: BigInt,
+    blindness: BigInt,
+    q_tag: GE,
+    first_message: Party2PDLFirstMsg,
+}
+
+impl PDLVerifier {
+    /// Challenges `c_key`, encrypted under the Paillier modulus `n`, to be
+    /// the encryption of the discrete log of `q1`.
+    pub fn new(n: &BigInt, c_key: &BigInt, q1: &GE) -> PDLVerifier {
+        let q = Secp256k1Scalar::q();
+        let a = BigInt::sample_below(&q);
+        let b = BigInt::sample_below(&(q.clone() * q));
+        let blindness = BigInt::sample(BLINDNESS_BITS);
+        let nn = n.clone() * n.clone();
+        let c_tag = BigInt::mod_mul(&BigInt::mod_pow(c_key, &a, &nn), &encrypt(n, &b), &nn);
+        let c_tag_tag = challenge_commitment(&a, &b, &blindness);
+        let q_tag = q1.scalar_mul(&scalar(&a).get_element()).add_point(
+            &GE::generator()
+                .scalar_mul(&scalar(&b).get_element())
+                .get_element(),
+        );
+        PDLVerifier {
+            a,
+            b,
+            blindness,
+            q_tag,
+            first_message: Party2PDLFirstMsg { c_tag, c_tag_tag },
+        }
+    }
+
+    pub fn first_message(&self) -> Party2PDLFirstMsg {
+        self.first_message.clone()
+    }
+
+    pub fn second_message(&self) -> Party2PDLSecondMsg {
+        Party2PDLSecondMsg {
+            a: self.a.clone(),
+            b: self.b.clone(),
+            blindness: self.blindness.clone(),
+        }
+    }
+
+    /// Checks party 1's opening against its commitment and `Q'`.
+    pub fn verify(
+        &self,
+        commitment: &Party1PDLFirstMsg,
+        decommit: &PDLDecommit,
+    ) -> Result<(), PDLError> {
+        let c_hat = HashCommitment::create_commitment_with_user_defined_randomness(
+            &decommit.q_hat.bytes_compressed_to_big_int(),
+            &decommit.blindness,
+        );
+        if c_hat != commitment.c_hat {
+            return Err(PDLError::CommitmentMismatch);
+        }
+        if decommit.q_hat != self.q_tag {
+            return Err(PDLError::QHatMismatch);
+        }
+        Ok(())
+    }
+}
+
+fn challenge_commitment(a: &BigInt, b: &BigInt, blindness: &BigInt) -> BigInt {
+    let ab = a.clone() + b.clone() * Secp256k1Scalar::q();
+    HashCommitment::create_commitment_with_user_defined_randomness(&ab, blindness)
+}
+
+fn scalar(n: &BigInt) -> FE {
+    ECScalar::from(&BigInt::modulus(n, &Secp256k1Scalar::q()))
+}
+
+/// `Enc(m) = (1 + n)^m * r^n mod n^2`.
+fn encrypt(n: &BigInt, m: &BigInt) -> BigInt {
+    let nn = n.clone() * n.clone();
+    let r = BigInt::sample_below(n);
+    BigInt::mod_mul(
+        &BigInt::mod_pow(&(n.clone() + BigInt::from(1)), m, &nn),
+        &BigInt::mod_pow(&r, n, &nn),
+        &nn,
+    )
+}
+
+/// `Dec(c) = L(c^lambda mod n^2) * lambda^-1 mod n`, `L(u) = (u - 1) / n`,
+/// with `lambda = (p - 1)(q - 1)`.
+fn decrypt(dk: &PaillierSecret, c: &BigInt) -> BigInt {
+    let one = BigInt::from(1);
+    let n = dk.p.clone() * dk.q.clone();
+    let nn = n.clone() * n.clone();
+    let lambda = (dk.p.clone() - one.clone()) * (dk.q.clone() - one.clone());
+    let u = BigInt::mod_pow(c, &lambda, &nn);
+    let l = (u - one) / n.clone();
+    BigInt::mod_mul(&l, &BigInt::mod_inv(&lambda, &n), &n)
+}
@@ -0,0 +1,428 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use rocket::http::Status;
+use web3::types::{Address, Bytes, U256};
+
+use crate::storage::backend::Storage;
+use crate::storage::keys::KeyClass;
+use crate::storage::{db, gc};
+use crate::utils::errors::HttpError;
+use crate::utils::transaction::UnsignedTx;
+use crate::AppConfig;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug)]
+pub enum PolicyStruct {
+    Policy,
+    /// Spend ledger of the limits, `Vec<Spend>`.
+    Spent,
+}
+
+impl db::MPCStruct for PolicyStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    fn class(&self) -> KeyClass {
+        KeyClass::Policy
+    }
+}
+
+impl PolicyStruct {
+    const ALL: [PolicyStruct; 2] = [PolicyStruct::Policy, PolicyStruct::Spent];
+
+    /// Key class of the record called `name`, for the key schema migration.
+    pub fn class_of(name: &str) -> Option<KeyClass> {
+        PolicyStruct::ALL
+            .iter()
+            .find(|record| db::MPCStruct::to_string(*record) == name)
+            .map(|record| db::MPCStruct::class(record))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RollingLimit {
+    pub wei: U256,
+    pub window_secs: u64,
+}
+
+/// `[start_minute, end_minute)` of the UTC day, wrapping past midnight when
+/// `end_minute < start_minute`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeWindow {
+    pub start_minute: u16,
+    pub end_minute: u16,
+}
+
+impl TimeWindow {
+    fn contains(&self, minute: u16) -> bool {
+        if self.start_minute <= self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+/// Co-signing rules of a wallet. Every rule is optional, a wallet without a
+/// policy is signed for unconditionally. Amounts count the most a
+/// transaction can cost, `value + gas * max_fee_per_gas`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Policy {
+    /// Wei per UTC day.
+    #[serde(default)]
+    pub daily_limit: Option<U256>,
+    #[serde(default)]
+    pub rolling_limit: Option<RollingLimit>,
+    /// Only these recipients, any if unset.
+    #[serde(default)]
+    pub allowed_recipients: Option<Vec<Address>>,
+    #[serde(default)]
+    pub denied_recipients: Vec<Address>,
+    /// 4-byte method selectors contract calls may use, any if unset. Plain
+    /// transfers without calldata are always allowed.
+    #[serde(default)]
+    pub allowed_selectors: Option<Vec<Bytes>>,
+    /// Cap on `max_fee_per_gas` (the gas price of legacy transactions).
+    #[serde(default)]
+    pub max_gas_price: Option<U256>,
+    /// UTC windows signatures are allowed in, any time if empty.
+    #[serde(default)]
+    pub time_windows: Vec<TimeWindow>,
+    /// Raw hashes cannot be inspected, so they are refused unless this is set.
+    #[serde(default)]
+    pub allow_raw_messages: bool,
+    /// Transactions costing more than this need human approval.
+    #[serde(default)]
+    pub approval: Option<ApprovalRule>,
+}
+
+/// Maker / checker rule: signatures above `threshold` wei are parked until
+/// `required` approvers agreed. Like the rest of the policy it is set by a
+/// policy admin, so owners cannot pick their own approvers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApprovalRule {
+    pub threshold: U256,
+    #[serde(default = "ApprovalRule::default_required")]
+    pub required: u32,
+    /// User ids allowed to approve, any holder of the approver role if empty.
+    /// They still need the approver role to decide, and the wallet owner
+    /// never approves their own signatures.
+    #[serde(default)]
+    pub approvers: Vec<String>,
+}
+
+impl ApprovalRule {
+    fn default_required() -> u32 {
+        1
+    }
+}
+
+impl Policy {
+    /// Rejects policies that could never be evaluated as intended.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(selectors) = &self.allowed_selectors {
+            if let Some(selector) = selectors.iter().find(|selector| selector.0.len() != 4) {
+                return Err(format!(
+                    "Selector 0x{} is not 4 bytes",
+                    hex::encode(&selector.0)
+                ));
+            }
+        }
+        for window in &self.time_windows {
+            if window.start_minute >= MINUTES_PER_DAY
+                || window.end_minute >= MINUTES_PER_DAY
+                || window.start_minute == window.end_minute
+            {
+                return Err(format!(
+                    "Invalid time window {}-{}, minutes of the day are 0-{} and a window cannot be empty",
+                    window.start_minute,
+                    window.end_minute,
+                    MINUTES_PER_DAY - 1
+                ));
+            }
+        }
+        if let Some(limit) = &self.rolling_limit {
+            if limit.window_secs == 0 {
+                return Err("Rolling limit window cannot be 0".to_string());
+            }
+        }
+        if let Some(rule) = &self.approval {
+            if rule.required == 0 {
+                return Err("An approval rule needs at least 1 approval".to_string());
+            }
+            if !rule.approvers.is_empty() && rule.required as usize > rule.approvers.len() {
+                return Err(format!(
+                    "{} approvals required but only {} approvers designated",
+                    rule.required,
+                    rule.approvers.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// How long spends have to be kept for the limits.
+    fn retention_secs(&self) -> u64 {
+        match &self.rolling_limit {
+            Some(limit) => limit.window_secs.max(SECS_PER_DAY),
+            None => SECS_PER_DAY,
+        }
+    }
+
+    fn limits_spend(&self) -> bool {
+        self.daily_limit.is_some() || self.rolling_limit.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Spend {
+    pub at: u64,
+    pub wei: U256,
+    /// Signing session the spend is booked for, `None` for spends booked
+    /// before it was recorded.
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
+impl Spend {
+    fn is_for(&self, session_id: &str) -> bool {
+        self.session_id.as_deref() == Some(session_id)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    RawMessage,
+    RecipientDenied(Address),
+    RecipientNotAllowed(Address),
+    SelectorNotAllowed(String),
+    GasPriceTooHigh {
+        max: U256,
+        given: U256,
+    },
+    OutsideTimeWindow {
+        minute: u16,
+    },
+    DailyLimit {
+        limit: U256,
+        spent: U256,
+        cost: U256,
+    },
+    RollingLimit {
+        limit: U256,
+        spent: U256,
+        cost: U256,
+    },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::RawMessage => write!(
+                f,
+                "raw message signing is disabled, sign a transaction (tx or tx_id)"
+            ),
+            Rejection::RecipientDenied(to) => write!(f, "recipient {:?} is denied", to),
+            Rejection::RecipientNotAllowed(to) => {
+                write!(f, "recipient {:?} is not on the allowlist", to)
+            }
+            Rejection::SelectorNotAllowed(selector) => {
+                write!(f, "method selector {} is not allowed", selector)
+            }
+            Rejection::GasPriceTooHigh { max, given } => {
+                write!(f, "max fee per gas {} exceeds the cap of {}", given, max)
+            }
+            Rejection::OutsideTimeWindow { minute } => write!(
+                f,
+                "{:02}:{:02} UTC is outside the signing windows",
+                minute / 60,
+                minute % 60
+            ),
+            Rejection::DailyLimit { limit, spent, cost } => write!(
+                f,
+                "{} wei would exceed the daily limit of {} wei, {} wei spent today",
+                cost, limit, spent
+            ),
+            Rejection::RollingLimit { limit, spent, cost } => write!(
+                f,
+                "{} wei would exceed the rolling limit of {} wei, {} wei spent in the window",
+                cost, limit, spent
+            ),
+        }
+    }
+}
+
+/// Most wei `tx` can move: its value plus the gas it may burn.
+pub fn max_cost(tx: &UnsignedTx) -> U256 {
+    tx.value
+        .saturating_add(tx.gas.saturating_mul(tx.max_fee_per_gas))
+}
+
+fn sum(spends: impl Iterator<Item = U256>) -> U256 {
+    spends.fold(U256::zero(), |total, wei| total.saturating_add(wei))
+}
+
+/// Checks a signing request against `policy` at `now`, `spent` being the
+/// wallet's recent spends.
+pub fn evaluate(
+    policy: &Policy,
+    tx: Option<&UnsignedTx>,
+    spent: &[Spend],
+    now: u64,
+) -> Result<(), Rejection> {
+    let minute = ((now % SECS_PER_DAY) / 60) as u16;
+    if !policy.time_windows.is_empty()
+        && !policy
+            .time_windows
+            .iter()
+            .any(|window| window.contains(minute))
+    {
+        return Err(Rejection::OutsideTimeWindow { minute });
+    }
+
+    let tx = match tx {
+        Some(tx) => tx,
+        None if policy.allow_raw_messages => return Ok(()),
+        None => return Err(Rejection::RawMessage),
+    };
+
+    if policy.denied_recipients.contains(&tx.to) {
+        return Err(Rejection::RecipientDenied(tx.to));
+    }
+    if let Some(allowed) = &policy.allowed_recipients {
+        if !allowed.contains(&tx.to) {
+            return Err(Rejection::RecipientNotAllowed(tx.to));
+        }
+    }
+    if let Some(allowed) = &policy.allowed_selectors {
+        if !tx.data.0.is_empty() {
+            let selector = tx.data.0.get(..4).unwrap_or(&tx.data.0);
+            if !allowed.iter().any(|allowed| allowed.0 == selector) {
+                return Err(Rejection::SelectorNotAllowed(format!(
+                    "0x{}",
+                    hex::encode(selector)
+                )));
+            }
+        }
+    }
+    if let Some(max) = policy.max_gas_price {
+        if tx.max_fee_per_gas > max {
+            return Err(Rejection::GasPriceTooHigh {
+                max,
+                given: tx.max_fee_per_gas,
+            });
+        }
+    }
+
+    let cost = max_cost(tx);
+    if let Some(limit) = policy.daily_limit {
+        let today = now / SECS_PER_DAY;
+        let spent = sum(spent
+            .iter()
+            .filter(|spend| spend.at / SECS_PER_DAY == today)
+            .map(|spend| spend.wei));
+        if spent.saturating_add(cost) > limit {
+            return Err(Rejection::DailyLimit { limit, spent, cost });
+        }
+    }
+    if let Some(limit) = &policy.rolling_limit {
+        let since = now.saturating_sub(limit.window_secs);
+        let spent = sum(spent
+            .iter()
+            .filter(|spend| spend.at > since)
+            .map(|spend| spend.wei));
+        if spent.saturating_add(cost) > limit.wei {
+            return Err(Rejection::RollingLimit {
+                limit: limit.wei,
+                spent,
+                cost,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Authorization {
+    Granted,
+    /// Passes every rule but has to be approved first, nothing is booked yet.
+    NeedsApproval(ApprovalRule),
+}
+
+/// Enforces the wallet's policy for the signature of signing session
+/// `session_id` and books its cost against the limits right away, under the
+/// wallet's policy lock so concurrent signatures cannot both pass on the same
+/// allowance. The booking is kept per session: authorizing the same session
+/// again replaces it, and `release` takes it back if the signature is not
+/// produced. `approved` signatures skip the approval rule, the other rules
+/// are checked again.
+pub async fn authorize(
+    state: &AppConfig,
+    user_id: &str,
+    id: &str,
+    session_id: &str,
+    tx: Option<&UnsignedTx>,
+    approved: bool,
+) -> Result<Authorization> {
+    let db: &dyn Storage = state.db.as_ref();
+    let _guard = state.policy_locks.lock(user_id, id).await;
+    let policy: Policy = match db::get(db, user_id, id, &PolicyStruct::Policy).await? {
+        Some(policy) => policy,
+        None => return Ok(Authorization::Granted),
+    };
+
+    let now = gc::now();
+    let spent: Vec<Spend> = db::get::<Vec<Spend>>(db, user_id, id, &PolicyStruct::Spent)
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|spend| !spend.is_for(session_id))
+        .collect();
+    if let Err(rejection) = evaluate(&policy, tx, &spent, now) {
+        return Err(anyhow!(HttpError::new(
+            Status::Forbidden,
+            format!("Refused by policy: {}", rejection)
+        )));
+    }
+
+    if let (Some(tx), Some(rule), false) = (tx, &policy.approval, approved) {
+        if max_cost(tx) > rule.threshold {
+            return Ok(Authorization::NeedsApproval(rule.clone()));
+        }
+    }
+
+    if let (Some(tx), true) = (tx, policy.limits_spend()) {
+        let since = now.saturating_sub(policy.retention_secs());
+        let mut spent: Vec<Spend> = spent.into_iter().filter(|spend| spend.at > since).collect();
+        spent.push(Spend {
+            at: now,
+            wei: max_cost(tx),
+            session_id: Some(session_id.to_string()),
+        });
+        db::insert(db, user_id, id, &PolicyStruct::Spent, &spent).await?;
+    }
+    Ok(Authorization::Granted)
+}
+
+/// Takes back the spend `authorize` booked for signing session `session_id`,
+/// whose signature was not produced.
+pub async fn release(state: &AppConfig, user_id: &str, id: &str, session_id: &str) -> Result<()> {
+    let db: &dyn Storage = state.db.as_ref();
+    let _guard = state.policy_locks.lock(user_id, id).await;
+    let spent: Vec<Spend> = match db::get(db, user_id, id, &PolicyStruct::Spent).await? {
+        Some(spent) => spent,
+        None => return Ok(()),
+    };
+    if !spent.iter().any(|spend| spend.is_for(session_id)) {
+        return Ok(());
+    }
+    let kept: Vec<Spend> = spent
+        .into_iter()
+        .filter(|spend| !spend.is_for(session_id))
+        .collect();
+    db::insert(db, user_id, id, &PolicyStruct::Spent, &kept).await
+}
@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::routes::ecdsa::EcdsaStruct;
+use crate::session::{self, Step};
+use crate::storage::backend::Storage;
+use crate::storage::{db, gc};
+use crate::utils::errors::HttpError;
+
+/// How long the client has to finalize a rotation before it may roll it back.
+pub const CONFIRM_TIMEOUT: Duration = gc::PROTOCOL_STATE_TTL;
+
+/// Version of the key in `Party1MasterKey`. Every rotation issues the next
+/// version and keeps the one it replaced in `Party1MasterKeyPrevious` until
+/// the client finalizes or rolls back.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct KeyVersion {
+    pub version: u64,
+    /// Highest version issued, a rolled back version is never reused.
+    pub latest: u64,
+    pub unconfirmed: Option<Unconfirmed>,
+}
+
+/// A rotation the client has not confirmed yet.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Unconfirmed {
+    /// The version kept in `Party1MasterKeyPrevious`.
+    pub previous: u64,
+    pub rotated_at: u64,
+    pub rollback_after: u64,
+}
+
+/// The previous key, as kept while a rotation is unconfirmed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct VersionedKey<K> {
+    pub version: u64,
+    pub master_key: K,
+}
+
+impl Default for KeyVersion {
+    /// Keygen produces version 1, so do wallets created before keys were
+    /// versioned.
+    fn default() -> KeyVersion {
+        KeyVersion {
+            version: 1,
+            latest: 1,
+            unconfirmed: None,
+        }
+    }
+}
+
+impl KeyVersion {
+    /// The version after swapping in a rotated key at `now`.
+    pub fn rotated(&self, now: u64) -> KeyVersion {
+        let version = self.latest + 1;
+        KeyVersion {
+            version,
+            latest: version,
+            unconfirmed: Some(Unconfirmed {
+                previous: self.version,
+                rotated_at: now,
+                rollback_after: now + CONFIRM_TIMEOUT.as_secs(),
+            }),
+        }
+    }
+
+    fn pending(&self, id: &str) -> Result<&Unconfirmed> {
+        self.unconfirmed.as_ref().ok_or_else(|| {
+            anyhow!(HttpError::conflict(format!(
+                "Version {} of id {} is not awaiting confirmation",
+                self.version, id
+            )))
+        })
+    }
+
+    /// The version once the client confirmed it holds the new share.
+    pub fn finalized(&self, id: &str) -> Result<KeyVersion> {
+        self.pending(id)?;
+        Ok(KeyVersion {
+            unconfirmed: None,
+            ..self.clone()
+        })
+    }
+
+    /// The version after restoring the previous key, only once the rotation
+    /// went unconfirmed for `CONFIRM_TIMEOUT`.
+    pub fn rolled_back(&self, id: &str, now: u64) -> Result<KeyVersion> {
+        let pending = self.pending(id)?;
+        if now < pending.rollback_after {
+            return Err(anyhow!(HttpError::conflict(format!(
+                "Rotation to version {} of id {} can be finalized, or rolled back from {}",
+                self.version, id, pending.rollback_after
+            ))));
+        }
+        Ok(KeyVersion {
+            version: pending.previous,
+            latest: self.latest,
+            unconfirmed: None,
+        })
+    }
+}
+
+pub async fn current(db: &dyn Storage, user_id: &str, id: &str) -> Result<KeyVersion> {
+    let version: Option<KeyVersion> = db::get(db, user_id, id, &EcdsaStruct::KeyVersion).await?;
+    Ok(version.unwrap_or_default())
+}
+
+/// Swaps `rotated` in as the next version, keeping `current` as the previous
+/// one, as part of the batch of `Step::RotateThird`.
+pub async fn swap_in<K: serde::ser::Serialize>(
+    db: &dyn Storage,
+    batch: &mut db::Batch,
+    user_id: &str,
+    id: &str,
+    current: &K,
+    rotated: &K,
+) -> Result<KeyVersion> {
+    let before = self::current(db, user_id, id).await?;
+    let after = before.rotated(gc::now());
+    batch.insert(
+        user_id,
+        id,
+        &EcdsaStruct::Party1MasterKeyPrevious,
+        &VersionedKey {
+            version: before.version,
+            master_key: current,
+        },
+    )?;
+    batch.insert(user_id, id, &EcdsaStruct::Party1MasterKey, rotated)?;
+    batch.insert(user_id, id, &EcdsaStruct::KeyVersion, &after)?;
+    Ok(after)
+}
+
+/// Drops the previous version once the client confirmed the new one.
+pub async fn finalize(db: &dyn Storage, user_id: &str, id: &str) -> Result<KeyVersion> {
+    session::expect(db, user_id, id, Step::RotateFinalize).await?;
+    let version = current(db, user_id, id).await?.finalized(id)?;
+
+    let mut batch = db::Batch::new();
+    batch.delete(user_id, id, &EcdsaStruct::Party1MasterKeyPrevious);
+    batch.insert(user_id, id, &EcdsaStruct::KeyVersion, &version)?;
+    session::advance(&mut batch, user_id, id, Step::RotateFinalize)?;
+    db::insert_many(db, batch).await?;
+    Ok(version)
+}
+
+/// The previous key and the version restoring it, for a rotation that went
+/// unconfirmed past its timeout. Nothing is written yet: the caller hands
+/// the key back to the vault first, then commits with `restore`.
+pub async fn rollback<K: serde::de::DeserializeOwned>(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+) -> Result<(KeyVersion, K)> {
+    session::expect(db, user_id, id, Step::RotateRollback).await?;
+    let version = current(db, user_id, id).await?.rolled_back(id, gc::now())?;
+    let previous: VersionedKey<K> = db::get(db, user_id, id, &EcdsaStruct::Party1MasterKeyPrevious)
+        .await?
+        .ok_or_else(|| {
+            anyhow!(
+                "No Party1MasterKeyPrevious for such userId {} - id {}",
+                user_id,
+                id
+            )
+        })?;
+    if previous.version != version.version {
+        return Err(anyhow!(
+            "Party1MasterKeyPrevious of id {} is version {}, expected {}",
+            id,
+            previous.version,
+            version.version
+        ));
+    }
+    Ok((version, previous.master_key))
+}
+
+pub async fn restore<K: serde::ser::Serialize>(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    version: &KeyVersion,
+    master_key: &K,
+) -> Result<()> {
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, id, &EcdsaStruct::Party1MasterKey, master_key)?;
+    batch.delete(user_id, id, &EcdsaStruct::Party1MasterKeyPrevious);
+    batch.insert(user_id, id, &EcdsaStruct::KeyVersion, version)?;
+    session::advance(&mut batch, user_id, id, Step::RotateRollback)?;
+    db::insert_many(db, batch).await
+}
@@ -0,0 +1,81 @@
+use rocket::serde::json::Json;
+use rocket::State;
+
+use super::super::approval::{self, PendingApproval};
+use super::super::audit::{self, AuditEvent, AuditOp};
+use super::super::auth::guards::{Approver, ValidatedUser};
+use super::super::AppConfig;
+use crate::AnyhowError;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DecisionReqBody {
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+async fn record_decision(state: &AppConfig, approver: &Approver, pending: &PendingApproval) {
+    let event = AuditEvent::new(
+        &pending.user_id,
+        Some(&pending.wallet_id),
+        AuditOp::ApprovalDecided,
+    )
+    .message(&pending.target.message)
+    .detail(format!(
+        "approval {} decided by {}, now {:?}",
+        pending.approval_id, approver.0.user_id, pending.status
+    ));
+    audit::record(state, event).await;
+}
+
+/// The caller's signatures awaiting approval and those the caller may
+/// approve.
+#[get("/ecdsa/approvals")]
+pub async fn list_approvals(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+) -> Result<Json<Vec<PendingApproval>>, AnyhowError> {
+    Ok(Json(approval::list(state, &user).await?))
+}
+
+#[get("/ecdsa/approvals/<approval_id>")]
+pub async fn get_approval(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    approval_id: String,
+) -> Result<Json<PendingApproval>, AnyhowError> {
+    Ok(Json(approval::get(state, &user, &approval_id).await?))
+}
+
+#[post(
+    "/ecdsa/approvals/<approval_id>/approve",
+    format = "json",
+    data = "<decision>"
+)]
+pub async fn approve(
+    state: &State<AppConfig>,
+    approver: Approver,
+    approval_id: String,
+    decision: Json<DecisionReqBody>,
+) -> Result<Json<PendingApproval>, AnyhowError> {
+    let pending =
+        approval::decide(state, &approver.0, &approval_id, true, decision.0.comment).await?;
+    record_decision(state, &approver, &pending).await;
+    Ok(Json(pending))
+}
+
+#[post(
+    "/ecdsa/approvals/<approval_id>/reject",
+    format = "json",
+    data = "<decision>"
+)]
+pub async fn reject(
+    state: &State<AppConfig>,
+    approver: Approver,
+    approval_id: String,
+    decision: Json<DecisionReqBody>,
+) -> Result<Json<PendingApproval>, AnyhowError> {
+    let pending =
+        approval::decide(state, &approver.0, &approval_id, false, decision.0.comment).await?;
+    record_decision(state, &approver, &pending).await;
+    Ok(Json(pending))
+}
@@ -0,0 +1,26 @@
+use rocket::serde::json::Json;
+use rocket::State;
+
+use super::super::audit::{self, AuditPage, DEFAULT_PAGE_SIZE};
+use super::super::auth::guards::ValidatedUser;
+use super::super::AppConfig;
+use crate::AnyhowError;
+
+/// The caller's audit entries, oldest first. Pass the returned `next` as
+/// `after` for the following page, `limit` is capped at 1000.
+#[get("/audit?<after>&<limit>")]
+pub async fn audit_log(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    after: Option<u64>,
+    limit: Option<usize>,
+) -> Result<Json<AuditPage>, AnyhowError> {
+    let page = audit::page(
+        state.db.as_ref(),
+        &user.user_id,
+        after,
+        limit.unwrap_or(DEFAULT_PAGE_SIZE),
+    )
+    .await?;
+    Ok(Json(page))
+}
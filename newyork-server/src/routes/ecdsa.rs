@@ -1,11 +1,13 @@
 // #![allow(non_snake_case)]
 
 use std::fmt::Debug;
+use std::time::Duration;
 
-use crate::utils::requests::{get, post, validate_auth_token, HttpClient};
+use crate::utils::requests::{get, post, HttpClient};
 use crate::AnyhowError;
 
 use anyhow::{anyhow, Result};
+use curv::arithmetic::traits::Converter;
 use curv::cryptographic_primitives::proofs::sigma_dlog::*;
 use curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
 use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
@@ -18,23 +20,47 @@ use kms::chain_code::two_party as chain_code;
 use kms::ecdsa::two_party::*;
 use kms::rotation::two_party::party1::Rotation1;
 use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::*;
+use rocket::http::Status;
+use rocket::response::status;
 use rocket::serde::json::Json;
 use rocket::State;
 use uuid::Uuid;
-
-use super::super::auth::guards::AuthPayload;
-use super::super::storage::db;
+use web3::types::{Address, Bytes};
+
+use super::super::approval::{self, PendingApproval};
+use super::super::audit::{self, AuditEvent, AuditOp, PolicyOutcome};
+use super::super::auth::guards::ValidatedUser;
+use super::super::pdl;
+use super::super::policy;
+use super::super::rotation::{self, KeyVersion};
+use super::super::session::{self, SessionState, Step};
+use super::super::storage::backend::Storage;
+use super::super::storage::keys::KeyClass;
+use super::super::storage::{db, gc};
+use super::super::utils::address;
+use super::super::utils::errors::HttpError;
+use super::super::utils::signature;
+use super::super::utils::transaction::UnsignedTx;
 use super::super::AppConfig;
+use super::eth;
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 struct HDPos {
     pos: u32,
 }
 
+/// Child key issued by `POST /ecdsa/<id>/children`, derived at `x/y`.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
-struct Alpha {
-    value: BigInt,
+pub struct HDChild {
+    pub x: u32,
+    pub y: u32,
+    pub public_key: String,
+    pub address: Address,
+    pub created_at: u64,
 }
 
+/// Children are handed out on the external chain, `0/1`, `0/2`, ...
+const HD_EXTERNAL_CHAIN: u32 = 0;
+
 #[derive(Debug)]
 pub enum EcdsaStruct {
     KeyGenFirstMsg,
@@ -43,32 +69,48 @@ pub enum EcdsaStruct {
     PaillierKeyPair,
     Party1Private,
     Party2Public,
-
+    /// `pdl::PDLProver`, party 1's side of the PDL rounds.
     PDLProver,
+    Party2PDLFirstMsg,
     PDLDecommit,
+    /// `Dec(c')` of the PDL challenge, checked against party 2's opening.
     Alpha,
-    Party2PDLFirstMsg,
 
     CCKeyGenFirstMsg,
     CCCommWitness,
     CCEcKeyPair,
+    /// Party 1's `ChainCode1`, kept once the chain code was agreed on.
     CC,
 
     Party1MasterKey,
+    /// `rotation::KeyVersion` of `Party1MasterKey`.
+    KeyVersion,
+    /// The key an unconfirmed rotation replaced, `rotation::VersionedKey`.
+    Party1MasterKeyPrevious,
 
     EphEcKeyPair,
     EphKeyGenFirstMsg,
+    EphConsumed,
 
     RotateCommitMessage1M,
     RotateCommitMessage1R,
     RotateRandom1,
     RotateFirstMsg,
+    /// The rotated `MasterKey1`, swapped in as the next version by
+    /// `rotate_third` once party 2 proved its new share.
     RotatePrivateNew,
-    RotatePdlDecom,
-    RotateParty2First,
-    RotateParty1Second,
 
     POS,
+    Children,
+    /// When the wallet started tracking `Children`. Wallets from before then
+    /// register the paths they already use with `register_child`.
+    ChildrenTracked,
+    RotatedAt,
+
+    Session,
+
+    /// A signature waiting for approvers, `approval::PendingApproval`.
+    PendingApproval,
 }
 
 impl db::MPCStruct for EcdsaStruct {
@@ -79,8 +121,81 @@ impl db::MPCStruct for EcdsaStruct {
     fn require_customer_id(&self) -> bool {
         self.to_string() == "Party1MasterKey"
     }
+
+    fn ttl(&self) -> Option<Duration> {
+        match self {
+            EcdsaStruct::Party1MasterKey
+            | EcdsaStruct::CC
+            | EcdsaStruct::KeyVersion
+            | EcdsaStruct::Party1MasterKeyPrevious
+            | EcdsaStruct::POS
+            | EcdsaStruct::Children
+            | EcdsaStruct::ChildrenTracked
+            | EcdsaStruct::RotatedAt
+            | EcdsaStruct::Session => None,
+            EcdsaStruct::EphConsumed => Some(gc::SPENT_EPHEMERAL_TTL),
+            _ => Some(gc::PROTOCOL_STATE_TTL),
+        }
+    }
+}
+
+impl EcdsaStruct {
+    const ALL: [EcdsaStruct; 31] = [
+        EcdsaStruct::KeyGenFirstMsg,
+        EcdsaStruct::CommWitness,
+        EcdsaStruct::EcKeyPair,
+        EcdsaStruct::PaillierKeyPair,
+        EcdsaStruct::Party1Private,
+        EcdsaStruct::Party2Public,
+        EcdsaStruct::PDLProver,
+        EcdsaStruct::Party2PDLFirstMsg,
+        EcdsaStruct::PDLDecommit,
+        EcdsaStruct::Alpha,
+        EcdsaStruct::CCKeyGenFirstMsg,
+        EcdsaStruct::CCCommWitness,
+        EcdsaStruct::CCEcKeyPair,
+        EcdsaStruct::CC,
+        EcdsaStruct::Party1MasterKey,
+        EcdsaStruct::KeyVersion,
+        EcdsaStruct::Party1MasterKeyPrevious,
+        EcdsaStruct::EphEcKeyPair,
+        EcdsaStruct::EphKeyGenFirstMsg,
+        EcdsaStruct::EphConsumed,
+        EcdsaStruct::RotateCommitMessage1M,
+        EcdsaStruct::RotateCommitMessage1R,
+        EcdsaStruct::RotateRandom1,
+        EcdsaStruct::RotateFirstMsg,
+        EcdsaStruct::RotatePrivateNew,
+        EcdsaStruct::POS,
+        EcdsaStruct::Children,
+        EcdsaStruct::ChildrenTracked,
+        EcdsaStruct::RotatedAt,
+        EcdsaStruct::Session,
+        EcdsaStruct::PendingApproval,
+    ];
+
+    /// Key class of the record called `name`, for the key schema migration.
+    pub fn class_of(name: &str) -> Option<KeyClass> {
+        EcdsaStruct::ALL
+            .iter()
+            .find(|record| db::MPCStruct::to_string(*record) == name)
+            .map(|record| db::MPCStruct::class(record))
+    }
 }
 
+/// Keygen and chain code state that is folded into `Party1MasterKey`.
+const KEYGEN_INTERMEDIATES: [EcdsaStruct; 9] = [
+    EcdsaStruct::KeyGenFirstMsg,
+    EcdsaStruct::CommWitness,
+    EcdsaStruct::EcKeyPair,
+    EcdsaStruct::PaillierKeyPair,
+    EcdsaStruct::Party1Private,
+    EcdsaStruct::Party2Public,
+    EcdsaStruct::CCKeyGenFirstMsg,
+    EcdsaStruct::CCCommWitness,
+    EcdsaStruct::CCEcKeyPair,
+];
+
 #[derive(Serialize)]
 pub struct HcmcMasterKey<'a> {
     pub master_key: &'a MasterKey1,
@@ -89,130 +204,255 @@ pub struct HcmcMasterKey<'a> {
 #[post("/ecdsa/keygen/first", format = "json")]
 pub async fn first_message(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
 ) -> Result<Json<(String, party_one::KeyGenFirstMsg)>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
+    let user_id = &user.user_id;
     let id = Uuid::new_v4().to_string();
     let (key_gen_first_msg, comm_witness, ec_key_pair) = MasterKey1::key_gen_first_message();
-    let user_id = &auth_payload.user_id;
 
+    let mut batch = db::Batch::new();
     //save pos 0
-    db::insert(
-        &state.db, // current DB connection state
-        user_id,   // user id in supabase
-        &id,       // uuid to unify DB column key
+    batch.insert(
+        user_id, // user id in supabase
+        &id,     // uuid to unify DB column key
         &EcdsaStruct::POS,
         &HDPos { pos: 0u32 }, // Initial HD position
     )?;
-
-    db::insert(
-        &state.db,
+    batch.insert(
         user_id,
         &id,
         &EcdsaStruct::KeyGenFirstMsg,
         &key_gen_first_msg,
     )?;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CommWitness,
-        &comm_witness,
-    )?;
-
-    db::insert(
-        &state.db,
+    batch.insert(user_id, &id, &EcdsaStruct::CommWitness, &comm_witness)?;
+    batch.insert(user_id, &id, &EcdsaStruct::EcKeyPair, &ec_key_pair)?;
+    // Only kept for good once keygen completes.
+    batch.expire(
         user_id,
         &id,
-        &EcdsaStruct::EcKeyPair,
-        &ec_key_pair,
-    )?;
+        &EcdsaStruct::POS,
+        Some(gc::PROTOCOL_STATE_TTL),
+    );
+    batch.index_wallet(user_id, &id, gc::now(), Some(gc::PROTOCOL_STATE_TTL));
+    session::start(&mut batch, user_id, &id, SessionState::KeygenStarted)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::KeyGenFirst),
+    )
+    .await;
 
     Ok(Json((id, key_gen_first_msg)))
 }
 
+/// Replies with the decommitment to `P1`, party 1's Paillier key,
+/// `c_key = Enc(x1)`, a correct-key proof of the Paillier key and a
+/// PDL-with-slack proof for `c_key`, which party 2 checks in
+/// `MasterKey2::key_gen_second_message`. Keygen continues with the
+/// interactive PDL rounds, `/ecdsa/keygen/<id>/third` and `fourth`.
 #[post("/ecdsa/keygen/<id>/second", format = "json", data = "<dlog_proof>")]
-pub fn second_message(
+pub async fn second_message(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
     dlog_proof: Json<DLogProof<GE>>,
 ) -> Result<Json<party1::KeyGenParty1Message2>, AnyhowError> {
     let party2_public: GE = dlog_proof.0.pk;
-    let user_id = &auth_payload.user_id;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::Party2Public,
-        &party2_public,
-    )?;
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::KeygenSecond).await?;
 
     let comm_witness: party_one::CommWitness =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CommWitness)?
+        db::get(&state.db, user_id, &id, &EcdsaStruct::CommWitness)
+            .await?
             .ok_or_else(|| anyhow!("No CommWitness for such userId {} - id {}", user_id, id))?;
 
     let ec_key_pair: party_one::EcKeyPair =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::EcKeyPair)?
+        db::get(&state.db, user_id, &id, &EcdsaStruct::EcKeyPair)
+            .await?
             .ok_or_else(|| anyhow!("No EcKeyPair for such userId {} - id {}", user_id, id))?;
 
     let (kg_party_one_second_message, paillier_key_pair, party_one_private) =
         MasterKey1::key_gen_second_message(comm_witness, &ec_key_pair, &dlog_proof.0);
 
-    db::insert(
-        &state.db,
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, &id, &EcdsaStruct::Party2Public, &party2_public)?;
+    batch.insert(
         user_id,
         &id,
         &EcdsaStruct::PaillierKeyPair,
         &paillier_key_pair,
     )?;
-
-    db::insert(
-        &state.db,
+    batch.insert(
         user_id,
         &id,
         &EcdsaStruct::Party1Private,
         &party_one_private,
     )?;
+    batch.insert(
+        user_id,
+        &id,
+        &EcdsaStruct::PDLProver,
+        &pdl::PDLProver::new(&ec_key_pair, &paillier_key_pair)?,
+    )?;
+    session::advance(&mut batch, user_id, &id, Step::KeygenSecond)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::KeyGenSecond),
+    )
+    .await;
 
     Ok(Json(kg_party_one_second_message))
 }
 
+/// First PDL round: party 2 challenges `c_key` with `c' = a * c_key + Enc(b)`
+/// and a commitment to `a` and `b`. Party 1 decrypts `c'` to `alpha` and only
+/// commits to `alpha * G` until party 2 opened its commitment.
+#[post(
+    "/ecdsa/keygen/<id>/third",
+    format = "json",
+    data = "<party2_pdl_first_message>"
+)]
+pub async fn third_message(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+    party2_pdl_first_message: Json<pdl::Party2PDLFirstMsg>,
+) -> Result<Json<pdl::Party1PDLFirstMsg>, AnyhowError> {
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::KeygenThird).await?;
+
+    let prover: pdl::PDLProver = db::get(&state.db, user_id, &id, &EcdsaStruct::PDLProver)
+        .await?
+        .ok_or_else(|| anyhow!("No PDLProver for such userId {} - id {}", user_id, id))?;
+
+    let (party1_pdl_first_message, pdl_decommit, alpha) =
+        prover.first_message(&party2_pdl_first_message.0);
+
+    let mut batch = db::Batch::new();
+    batch.insert(
+        user_id,
+        &id,
+        &EcdsaStruct::Party2PDLFirstMsg,
+        &party2_pdl_first_message.0,
+    )?;
+    batch.insert(user_id, &id, &EcdsaStruct::PDLDecommit, &pdl_decommit)?;
+    batch.insert(user_id, &id, &EcdsaStruct::Alpha, &alpha)?;
+    session::advance(&mut batch, user_id, &id, Step::KeygenThird)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::KeyGenThird),
+    )
+    .await;
+
+    Ok(Json(party1_pdl_first_message))
+}
+
+/// Second PDL round: party 2 opens `a` and `b`, party 1 checks that `alpha`
+/// is `a * x1 + b` and opens `alpha * G`, which party 2 compares with
+/// `a * Q1 + b * G`. A failed check aborts keygen, the PDL state is dropped
+/// and the wallet has to be generated again.
+#[post(
+    "/ecdsa/keygen/<id>/fourth",
+    format = "json",
+    data = "<party2_pdl_second_message>"
+)]
+pub async fn fourth_message(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+    party2_pdl_second_message: Json<pdl::Party2PDLSecondMsg>,
+) -> Result<Json<pdl::PDLDecommit>, AnyhowError> {
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::KeygenFourth).await?;
+
+    let aborted = || {
+        anyhow!(HttpError::conflict(format!(
+            "Keygen {} was aborted, start over with /ecdsa/keygen/first",
+            id
+        )))
+    };
+    let prover: pdl::PDLProver = db::get(&state.db, user_id, &id, &EcdsaStruct::PDLProver)
+        .await?
+        .ok_or_else(aborted)?;
+    let party2_pdl_first_message: pdl::Party2PDLFirstMsg =
+        db::get(&state.db, user_id, &id, &EcdsaStruct::Party2PDLFirstMsg)
+            .await?
+            .ok_or_else(aborted)?;
+    let pdl_decommit: pdl::PDLDecommit =
+        db::get(&state.db, user_id, &id, &EcdsaStruct::PDLDecommit)
+            .await?
+            .ok_or_else(aborted)?;
+    let alpha: BigInt = db::get(&state.db, user_id, &id, &EcdsaStruct::Alpha)
+        .await?
+        .ok_or_else(aborted)?;
+
+    let checked = prover.second_message(
+        &party2_pdl_first_message,
+        &party2_pdl_second_message.0,
+        &alpha,
+        pdl_decommit,
+    );
+
+    let mut batch = db::Batch::new();
+    for record in [
+        EcdsaStruct::PDLProver,
+        EcdsaStruct::Party2PDLFirstMsg,
+        EcdsaStruct::PDLDecommit,
+        EcdsaStruct::Alpha,
+    ]
+    .iter()
+    {
+        batch.delete(user_id, &id, record);
+    }
+    if checked.is_ok() {
+        session::advance(&mut batch, user_id, &id, Step::KeygenFourth)?;
+    }
+    db::insert_many(&state.db, batch).await?;
+
+    let mut event = AuditEvent::new(user_id, Some(&id), AuditOp::KeyGenFourth);
+    if let Err(e) = &checked {
+        event = event.detail(format!("aborted: {}", e));
+    }
+    audit::record(state, event).await;
+
+    let pdl_decommit = checked.map_err(|e| {
+        anyhow!(HttpError::new(
+            Status::BadRequest,
+            format!("PDL check failed for id {}: {}", id, e)
+        ))
+    })?;
+    Ok(Json(pdl_decommit))
+}
+
 #[post("/ecdsa/keygen/<id>/chaincode/first", format = "json")]
-pub fn chain_code_first_message(
+pub async fn chain_code_first_message(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
 ) -> Result<Json<Party1FirstMessage>, AnyhowError> {
     let (cc_party_one_first_message, cc_comm_witness, cc_ec_key_pair1) =
         chain_code::party1::ChainCode1::chain_code_first_message();
-    let user_id = &auth_payload.user_id;
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::ChainCodeFirst).await?;
 
-    db::insert(
-        &state.db,
+    let mut batch = db::Batch::new();
+    batch.insert(
         user_id,
         &id,
         &EcdsaStruct::CCKeyGenFirstMsg,
         &cc_party_one_first_message,
     )?;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CCCommWitness,
-        &cc_comm_witness,
-    )?;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CCEcKeyPair,
-        &cc_ec_key_pair1,
-    )?;
+    batch.insert(user_id, &id, &EcdsaStruct::CCCommWitness, &cc_comm_witness)?;
+    batch.insert(user_id, &id, &EcdsaStruct::CCEcKeyPair, &cc_ec_key_pair1)?;
+    session::advance(&mut batch, user_id, &id, Step::ChainCodeFirst)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::ChainCodeFirst),
+    )
+    .await;
 
     Ok(Json(cc_party_one_first_message))
 }
@@ -224,14 +464,16 @@ pub fn chain_code_first_message(
 )]
 pub async fn chain_code_second_message(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
     cc_party_two_first_message_d_log_proof: Json<DLogProof<GE>>,
 ) -> Result<Json<Party1SecondMessage<GE>>, AnyhowError> {
-    let user_id = &auth_payload.user_id;
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::ChainCodeSecond).await?;
 
     let cc_comm_witness: CommWitness<GE> =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CCCommWitness)?
+        db::get(&state.db, user_id, &id, &EcdsaStruct::CCCommWitness)
+            .await?
             .ok_or_else(|| anyhow!("No CCCommWitness for such userId {} - id {}", user_id, id))?;
 
     let party1_cc = chain_code::party1::ChainCode1::chain_code_second_message(
@@ -241,76 +483,88 @@ pub async fn chain_code_second_message(
 
     let party2_pub = &cc_party_two_first_message_d_log_proof.pk;
 
-    let master_key = chain_code_compute_message(state, &auth_payload, id, party2_pub)?;
+    let master_key = chain_code_compute_message(state, &user, id.clone(), party2_pub).await?;
 
     // Send mk#2 to HCMC
-    send_mk_to_vault(state, &auth_payload, &master_key).await?;
+    send_mk_to_vault(state, &user, &master_key).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::ChainCodeSecond).detail(format!(
+            "public key {}",
+            address::compressed_hex(&master_key.public.q)
+        )),
+    )
+    .await;
 
     Ok(Json(party1_cc))
 }
 
-pub fn chain_code_compute_message(
+pub async fn chain_code_compute_message(
     state: &State<AppConfig>,
-    auth_payload: &AuthPayload,
+    user: &ValidatedUser,
     id: String,
     cc_party2_public: &GE,
 ) -> Result<MasterKey1> {
-    let user_id = &auth_payload.user_id;
+    let user_id = &user.user_id;
     let cc_ec_key_pair_party1: EcKeyPair<GE> =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CCEcKeyPair)?
+        db::get(&state.db, user_id, &id, &EcdsaStruct::CCEcKeyPair)
+            .await?
             .ok_or_else(|| anyhow!("No CCEcKeyPair for such userId {} - id {}", user_id, id))?;
     let party1_cc = chain_code::party1::ChainCode1::compute_chain_code(
         &cc_ec_key_pair_party1,
         cc_party2_public,
     );
 
-    db::insert(&state.db, user_id, &id, &EcdsaStruct::CC, &party1_cc)?;
+    let master_key = master_key(state, user, &id, &party1_cc).await?;
 
-    master_key(state, auth_payload, id)
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, &id, &EcdsaStruct::Party1MasterKey, &master_key)?;
+    batch.insert(user_id, &id, &EcdsaStruct::CC, &party1_cc)?;
+    batch.expire(user_id, &id, &EcdsaStruct::POS, None);
+    batch.insert(user_id, &id, &EcdsaStruct::ChildrenTracked, gc::now())?;
+    batch.expire_wallet_index(user_id, &id, None);
+    for intermediate in KEYGEN_INTERMEDIATES.iter() {
+        batch.delete(user_id, &id, intermediate);
+    }
+    session::advance(&mut batch, user_id, &id, Step::ChainCodeSecond)?;
+    db::insert_many(&state.db, batch).await?;
+
+    Ok(master_key)
 }
 
-fn master_key(
+async fn master_key(
     state: &State<AppConfig>,
-    auth_payload: &AuthPayload,
-    id: String,
+    user: &ValidatedUser,
+    id: &str,
+    party1_cc: &chain_code::party1::ChainCode1,
 ) -> Result<MasterKey1> {
-    let user_id = &auth_payload.user_id;
-    let party2_public: GE = db::get(&state.db, user_id, &id, &EcdsaStruct::Party2Public)?
+    let user_id = &user.user_id;
+    let party2_public: GE = db::get(&state.db, user_id, id, &EcdsaStruct::Party2Public)
+        .await?
         .ok_or_else(|| anyhow!("No Party2Public for such userId {} - id {}", user_id, id))?;
 
     let paillier_key_pair: party_one::PaillierKeyPair =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::PaillierKeyPair)?
+        db::get(&state.db, user_id, id, &EcdsaStruct::PaillierKeyPair)
+            .await?
             .ok_or_else(|| anyhow!("No PaillierKeyPair for such userId {} - id {}", user_id, id))?;
 
-    let party1_cc: chain_code::party1::ChainCode1 =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CC)?
-            .ok_or_else(|| anyhow!("No CC for such userId {} - id {}", user_id, id))?;
-
     let party_one_private: party_one::Party1Private =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::Party1Private)?
+        db::get(&state.db, user_id, id, &EcdsaStruct::Party1Private)
+            .await?
             .ok_or_else(|| anyhow!("No Party1Private for such userId {} - id {}", user_id, id))?;
 
     let comm_witness: party_one::CommWitness =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CommWitness)?
+        db::get(&state.db, user_id, id, &EcdsaStruct::CommWitness)
+            .await?
             .ok_or_else(|| anyhow!("No CommWitness for such userId {} - id {}", user_id, id))?;
 
-    let master_key = MasterKey1::set_master_key(
+    Ok(MasterKey1::set_master_key(
         &party1_cc.chain_code,
         party_one_private,
         &comm_witness.public_share,
         &party2_public,
         paillier_key_pair,
-    );
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::Party1MasterKey,
-        &master_key,
-    )?;
-
-    Ok(master_key)
+    ))
 }
 
 #[post(
@@ -320,31 +574,52 @@ fn master_key(
 )]
 pub async fn sign_first(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
     eph_key_gen_first_message_party_two: Json<party_two::EphKeyGenFirstMsg>,
-) -> Result<Json<party_one::EphKeyGenFirstMsg>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
+) -> Result<Json<(String, party_one::EphKeyGenFirstMsg)>, AnyhowError> {
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::SignFirst).await?;
     let (sign_party_one_first_message, eph_ec_key_pair_party1) = MasterKey1::sign_first_message();
-    let user_id = &auth_payload.user_id;
+    let session_id = Uuid::new_v4().to_string();
+    let sign_id = sign_session_id(&id, &session_id)?;
 
-    db::insert(
-        &state.db,
+    let mut batch = db::Batch::new();
+    batch.insert(
         user_id,
-        &id,
+        &sign_id,
         &EcdsaStruct::EphKeyGenFirstMsg,
         &eph_key_gen_first_message_party_two.0,
     )?;
-
-    db::insert(
-        &state.db,
+    batch.insert(
         user_id,
-        &id,
+        &sign_id,
         &EcdsaStruct::EphEcKeyPair,
         &eph_ec_key_pair_party1,
     )?;
+    session::advance(&mut batch, user_id, &sign_id, Step::SignFirst)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record_or_fail(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::SignFirst)
+            .detail(format!("session {}", session_id)),
+    )
+    .await?;
+
+    Ok(Json((session_id, sign_party_one_first_message)))
+}
 
-    Ok(Json(sign_party_one_first_message))
+/// Ephemeral signing state lives under its own id per signing session, so
+/// parallel signatures for one wallet never overwrite each other and a nonce
+/// can never be picked up by a different `sign_second` call.
+fn sign_session_id(id: &str, session_id: &str) -> Result<String> {
+    Uuid::parse_str(session_id).map_err(|_| {
+        anyhow!(HttpError::new(
+            Status::NotFound,
+            format!("Invalid signing session {}", session_id)
+        ))
+    })?;
+    Ok(format!("{}_{}", id, session_id))
 }
 
 // Added here because the attribute data takes only a single struct
@@ -354,93 +629,346 @@ pub struct SignSecondMsgRequest {
     pub party_two_sign_message: party2::SignMessage,
     pub x_pos_child_key: BigInt,
     pub y_pos_child_key: BigInt,
+    /// Transaction mode: `message` must be the sighash of this transaction,
+    /// which the server recomputes.
+    #[serde(default)]
+    pub tx: Option<UnsignedTx>,
+    /// Like `tx`, for a transaction built by `/eth/tx/build`.
+    #[serde(default)]
+    pub tx_id: Option<String>,
+}
+
+/// The signature fields stay at the top level, so clients decoding a plain
+/// `SignatureRecid` keep working. `rsv` is only set for `?rsv=true`.
+#[derive(Serialize, Deserialize)]
+pub struct SignSecondResp {
+    #[serde(flatten)]
+    pub signature: party_one::SignatureRecid,
+    /// Hex of the 65-byte `r || s || v`, `v` being the raw recovery id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rsv: Option<String>,
+    /// The signed transaction in transaction mode, ready for `/eth/tx/send`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_tx: Option<Bytes>,
+}
+
+/// `200` with the signature, or `202` with the parked request when the
+/// wallet's policy asks for approval first.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SignSecondReply {
+    Signed(SignSecondResp),
+    PendingApproval(PendingApproval),
 }
-#[post("/ecdsa/sign/<id>/second", format = "json", data = "<request>")]
+
+#[post(
+    "/ecdsa/sign/<id>/<session_id>/second?<rsv>",
+    format = "json",
+    data = "<request>"
+)]
 pub async fn sign_second(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
+    session_id: String,
+    rsv: Option<bool>,
     request: Json<SignSecondMsgRequest>,
-) -> Result<Json<party_one::SignatureRecid>, AnyhowError> {
-    let user_id = &auth_payload.user_id;
-    let master_key: MasterKey1 = match get_mk(state, auth_payload.clone(), &id) {
-        Ok(mk) => mk,
-        Err(_) => {
-            info!("MasterKey1 not found in memory, trying to get from vault");
-            let mk = match get_mk_from_vault(state, &auth_payload).await {
-                Ok(mk) => {
-                    db::insert(&state.db, user_id, &id, &EcdsaStruct::Party1MasterKey, &mk)?;
-                    mk
-                }
-                Err(e) => return Err(AnyhowError::from(anyhow!("{:#?}", e))),
-            };
-            mk
+) -> Result<status::Custom<Json<SignSecondReply>>, AnyhowError> {
+    let user_id = &user.user_id;
+    let sign_id = sign_session_id(&id, &session_id)?;
+    if let Err(e) = expect_issued_child(
+        &state.db,
+        user_id,
+        &id,
+        &request.x_pos_child_key,
+        &request.y_pos_child_key,
+    )
+    .await
+    {
+        if e.is::<HttpError>() {
+            audit::record(
+                state,
+                sign_event(user_id, &id, &session_id, &request.message).detail(format!(
+                    "session {}, refused: unissued path {}/{}",
+                    session_id, request.x_pos_child_key, request.y_pos_child_key
+                )),
+            )
+            .await;
         }
-    };
+        return Err(AnyhowError::from(e));
+    }
+    // Checked before the ephemeral key is spent, a mismatch can be retried.
+    let tx = eth::tx_to_sign(
+        &state.db,
+        user_id,
+        &id,
+        (&request.x_pos_child_key, &request.y_pos_child_key),
+        &request.tx,
+        &request.tx_id,
+    )
+    .await?;
+    if let Some(tx) = &tx {
+        expect_sighash(tx, &request.message)?;
+    }
 
-    let x: BigInt = request.x_pos_child_key.clone();
-    let y: BigInt = request.y_pos_child_key.clone();
+    // The policy, and the approval it may ask for, is settled while the
+    // ephemeral key is still unused: a parked signature is completed by
+    // repeating this call once approved.
+    let _session = session::begin(state, user_id, &sign_id, Step::SignSecond).await?;
+    let eph_unused: Option<party_one::EphEcKeyPair> =
+        db::get(&state.db, user_id, &sign_id, &EcdsaStruct::EphEcKeyPair).await?;
+    let mut policy_outcome = PolicyOutcome::Granted;
+    if eph_unused.is_some() {
+        let target = approval::SignTarget {
+            message: request.message.clone(),
+            x_pos_child_key: request.x_pos_child_key.clone(),
+            y_pos_child_key: request.y_pos_child_key.clone(),
+        };
+        let approved = approval::approved_for(&state.db, user_id, &sign_id, &target).await?;
+        let authorization = match policy::authorize(
+            state,
+            user_id,
+            &id,
+            &session_id,
+            tx.as_ref(),
+            approved,
+        )
+        .await
+        {
+            Ok(authorization) => authorization,
+            Err(e) => {
+                if let Some(refusal) = e.downcast_ref::<HttpError>() {
+                    audit::record(
+                        state,
+                        sign_event(user_id, &id, &session_id, &request.message).policy(
+                            PolicyOutcome::Refused {
+                                reason: refusal.message.clone(),
+                            },
+                        ),
+                    )
+                    .await;
+                }
+                return Err(AnyhowError::from(e));
+            }
+        };
+        policy_outcome = if approved {
+            PolicyOutcome::Approved
+        } else {
+            PolicyOutcome::Granted
+        };
+        if let (policy::Authorization::NeedsApproval(rule), Some(tx)) = (authorization, &tx) {
+            let pending =
+                approval::park(&state.db, user_id, &id, &session_id, &target, tx, rule).await?;
+            audit::record_or_fail(
+                state,
+                sign_event(user_id, &id, &session_id, &request.message)
+                    .policy(PolicyOutcome::PendingApproval),
+            )
+            .await?;
+            return Ok(status::Custom(
+                Status::Accepted,
+                Json(SignSecondReply::PendingApproval(pending)),
+            ));
+        }
+    }
 
-    let child_master_key = master_key.get_child(vec![x, y]);
+    // A spend booked by this call is taken back unless the signature is
+    // produced and recorded.
+    let authorized = eph_unused.is_some();
+    let signed = async {
+        let master_key: MasterKey1 = match get_mk(state, &user, &id).await {
+            Ok(mk) => mk,
+            Err(_) => {
+                info!("MasterKey1 not found in memory, trying to get from vault");
+                let mk = match get_mk_from_vault(state, &user).await {
+                    Ok(mk) => {
+                        db::insert(&state.db, user_id, &id, &EcdsaStruct::Party1MasterKey, &mk)
+                            .await?;
+                        mk
+                    }
+                    Err(e) => return Err(AnyhowError::from(anyhow!("{:#?}", e))),
+                };
+                mk
+            }
+        };
+
+        let x: BigInt = request.x_pos_child_key.clone();
+        let y: BigInt = request.y_pos_child_key.clone();
+
+        let child_master_key = master_key.get_child(vec![x, y]);
+
+        // Everything the signature needs is read before the ephemeral key is
+        // consumed, a lookup that fails leaves it unspent.
+        let eph_key_gen_first_message_party_two: Option<party_two::EphKeyGenFirstMsg> = db::get(
+            &state.db,
+            user_id,
+            &sign_id,
+            &EcdsaStruct::EphKeyGenFirstMsg,
+        )
+        .await?;
+        // The ephemeral key is taken together with its spent marker, before
+        // anything else can fail, so k1 is never used for more than one
+        // signature.
+        let eph_keys = match eph_key_gen_first_message_party_two {
+            Some(eph_key_gen_first_message) => {
+                let mut batch = db::Batch::new();
+                batch.insert(user_id, &sign_id, &EcdsaStruct::EphConsumed, &gc::now())?;
+                batch.delete(user_id, &sign_id, &EcdsaStruct::EphKeyGenFirstMsg);
+                let eph_ec_key_pair: Option<party_one::EphEcKeyPair> = db::take_with(
+                    &state.db,
+                    user_id,
+                    &sign_id,
+                    &EcdsaStruct::EphEcKeyPair,
+                    batch,
+                )
+                .await?;
+                eph_ec_key_pair.map(|eph_ec_key_pair| (eph_ec_key_pair, eph_key_gen_first_message))
+            }
+            None => None,
+        };
+        let (eph_ec_key_pair_party1, eph_key_gen_first_message_party_two) = match eph_keys {
+            Some(eph_keys) => eph_keys,
+            None => {
+                let consumed_at: Option<u64> =
+                    db::get(&state.db, user_id, &sign_id, &EcdsaStruct::EphConsumed).await?;
+                let reason = match consumed_at {
+                    Some(_) => "already used",
+                    None => "unknown or expired",
+                };
+                audit::record(
+                    state,
+                    sign_event(user_id, &id, &session_id, &request.message).detail(format!(
+                        "session {}, refused: {} ephemeral key",
+                        session_id, reason
+                    )),
+                )
+                .await;
+                return Err(AnyhowError::from(anyhow!(HttpError::conflict(format!(
+                    "Ephemeral key of signing session {} is {}, start a new signing session",
+                    session_id, reason
+                )))));
+            }
+        };
+
+        let signature_with_recid = child_master_key.sign_second_message(
+            &request.party_two_sign_message,
+            &eph_key_gen_first_message_party_two,
+            &eph_ec_key_pair_party1,
+            &request.message,
+        );
+
+        if signature_with_recid.is_err() {
+            error!("Signature validation failed");
+            return Err(AnyhowError::from(anyhow!("Signature validation failed")));
+        };
+
+        // Nothing the server cannot vouch for leaves it, the ephemeral key is
+        // spent either way.
+        let signature = signature::finalize(
+            &child_master_key.public.q,
+            &request.message,
+            signature_with_recid.unwrap(),
+        )
+        .map_err(|e| {
+            error!(
+                "Produced signature rejected - userId {} - id {} - session {}: {:#}",
+                user_id, id, session_id, e
+            );
+            e
+        })?;
 
-    let eph_ec_key_pair_party1: party_one::EphEcKeyPair =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::EphEcKeyPair)?
-            .ok_or_else(|| anyhow!("No EphEcKeyPair for such userId {} - id {}", user_id, id))?;
+        let raw_tx = match &tx {
+            Some(tx) => Some(tx.encode_signed(&signature)?),
+            None => None,
+        };
 
-    let eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::EphKeyGenFirstMsg)?.ok_or_else(|| {
-            anyhow!(
-                "No EphKeyGenFirstMsg for such userId {} - id {}",
-                user_id,
-                id
-            )
-        })?;
+        let mut batch = db::Batch::new();
+        session::finish(&mut batch, user_id, &sign_id);
+        if let Some(tx_id) = &request.tx_id {
+            batch.delete(user_id, tx_id, &eth::EthStruct::UnsignedTx);
+        }
+        approval::mark_signed(&state.db, &mut batch, user_id, &sign_id).await?;
+        db::insert_many(&state.db, batch).await?;
+        audit::record_or_fail(
+            state,
+            sign_event(user_id, &id, &session_id, &request.message).policy(policy_outcome),
+        )
+        .await?;
 
-    let signature_with_recid = child_master_key.sign_second_message(
-        &request.party_two_sign_message,
-        &eph_key_gen_first_message_party_two,
-        &eph_ec_key_pair_party1,
-        &request.message,
-    );
+        let rsv = match rsv {
+            Some(true) => Some(hex::encode(signature::to_rsv(&signature)?)),
+            _ => None,
+        };
+        Ok::<_, AnyhowError>(status::Custom(
+            Status::Ok,
+            Json(SignSecondReply::Signed(SignSecondResp {
+                signature,
+                rsv,
+                raw_tx,
+            })),
+        ))
+    }
+    .await;
+    if signed.is_err() && authorized {
+        if let Err(e) = policy::release(state, user_id, &id, &session_id).await {
+            error!(
+                "Releasing the spend of session {} failed - userId {} - id {}: {:#}",
+                session_id, user_id, id, e
+            );
+        }
+    }
+    signed
+}
 
-    if signature_with_recid.is_err() {
-        error!("Signature validation failed");
-        return Err(AnyhowError::from(anyhow!("Signature validation failed")));
-    };
+fn sign_event(user_id: &str, id: &str, session_id: &str, message: &BigInt) -> AuditEvent {
+    AuditEvent::new(user_id, Some(id), AuditOp::SignSecond)
+        .message(message)
+        .detail(format!("session {}", session_id))
+}
 
-    Ok(Json(signature_with_recid.unwrap()))
+/// In transaction mode the server only co-signs the hash it computed itself,
+/// so whatever the transaction does is what gets signed.
+fn expect_sighash(tx: &UnsignedTx, message: &BigInt) -> Result<()> {
+    let sighash = tx.sighash();
+    if &BigInt::from_bytes(&sighash.0) == message {
+        return Ok(());
+    }
+    Err(anyhow!(HttpError::new(
+        Status::BadRequest,
+        format!("Message is not the transaction's sighash {:?}", sighash)
+    )))
 }
 
-pub fn get_mk(state: &State<AppConfig>, auth_payload: AuthPayload, id: &str) -> Result<MasterKey1> {
-    let user_id = &auth_payload.user_id;
-    db::get(&state.db, user_id, id, &EcdsaStruct::Party1MasterKey)?
+pub async fn get_mk(
+    state: &State<AppConfig>,
+    user: &ValidatedUser,
+    id: &str,
+) -> Result<MasterKey1> {
+    let user_id = &user.user_id;
+    db::get(&state.db, user_id, id, &EcdsaStruct::Party1MasterKey)
+        .await?
         .ok_or_else(|| anyhow!("No Party1MasterKey for such userId {} - id {}", user_id, id))
 }
 
 #[post("/ecdsa/rotate/<id>/first", format = "json")]
 pub async fn rotate_first(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
 ) -> Result<Json<coin_flip_optimal_rounds::Party1FirstMessage<GE>>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
+    let user_id = &user.user_id;
+    let _session = session::begin(state, user_id, &id, Step::RotateFirst).await?;
     let (party1_coin_flip_first_message, m1, r1) = Rotation1::key_rotate_first_message();
-    let user_id = &auth_payload.user_id;
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::RotateCommitMessage1M,
-        &m1,
-    )?;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::RotateCommitMessage1R,
-        &r1,
-    )?;
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, &id, &EcdsaStruct::RotateCommitMessage1M, &m1)?;
+    batch.insert(user_id, &id, &EcdsaStruct::RotateCommitMessage1R, &r1)?;
+    session::advance(&mut batch, user_id, &id, Step::RotateFirst)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record_or_fail(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::RotateFirst),
+    )
+    .await?;
 
     Ok(Json(party1_coin_flip_first_message))
 }
@@ -453,7 +981,7 @@ pub async fn rotate_first(
 pub async fn rotate_second(
     state: &State<AppConfig>,
     id: String,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     party2_first_message: Json<coin_flip_optimal_rounds::Party2FirstMessage<GE>>,
 ) -> Result<
     Json<(
@@ -462,13 +990,21 @@ pub async fn rotate_second(
     )>,
     AnyhowError,
 > {
-    let party_one_master_key: MasterKey1 = match get_mk(state, auth_payload.clone(), &id) {
+    let _session = session::begin(state, &user.user_id, &id, Step::RotateSecond).await?;
+    let party_one_master_key: MasterKey1 = match get_mk(state, &user, &id).await {
         Ok(mk) => mk,
         Err(_) => {
             info!("MasterKey1 not found in memory, trying to get from vault");
-            let mk = match get_mk_from_vault(state, &auth_payload).await {
+            let mk = match get_mk_from_vault(state, &user).await {
                 Ok(mk) => {
-                    db::insert(&state.db, &auth_payload.user_id, &id, &EcdsaStruct::Party1MasterKey, &mk)?;
+                    db::insert(
+                        &state.db,
+                        &user.user_id,
+                        &id,
+                        &EcdsaStruct::Party1MasterKey,
+                        &mk,
+                    )
+                    .await?;
                     mk
                 }
                 Err(e) => return Err(AnyhowError::from(anyhow!("{:#?}", e))),
@@ -476,53 +1012,53 @@ pub async fn rotate_second(
             mk
         }
     };
-    let user_id = &auth_payload.user_id;
+    let user_id = &user.user_id;
 
-    let m1: Secp256k1Scalar =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1M)?.ok_or_else(
-            || {
-                anyhow!(
-                    "No RotateCommitMessage1M for such userId {} - id {}",
-                    user_id,
-                    id
-                )
-            },
-        )?;
-
-    let r1: Secp256k1Scalar =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1R)?.ok_or_else(
-            || {
-                anyhow!(
-                    "No RotateCommitMessage1R for such userId {} - id {}",
-                    user_id,
-                    id
-                )
-            },
-        )?;
+    let m1: Secp256k1Scalar = db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1M)
+        .await?
+        .ok_or_else(|| {
+            anyhow!(
+                "No RotateCommitMessage1M for such userId {} - id {}",
+                user_id,
+                id
+            )
+        })?;
+
+    let r1: Secp256k1Scalar = db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1R)
+        .await?
+        .ok_or_else(|| {
+            anyhow!(
+                "No RotateCommitMessage1R for such userId {} - id {}",
+                user_id,
+                id
+            )
+        })?;
 
     let (party1_second_message, random1) =
         Rotation1::key_rotate_second_message(&party2_first_message.0, &m1, &r1);
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::RotateRandom1,
-        &random1,
-    )?;
 
     let (rotation_party_one_first_message, party_one_master_key_rotated) =
         party_one_master_key.rotation_first_message(&random1);
 
-    db::insert(
-        &state.db,
+    // The current key stays in use until party 2 confirms in
+    // `rotate_third`, an abandoned rotation just expires.
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, &id, &EcdsaStruct::RotateRandom1, &random1)?;
+    batch.delete(user_id, &id, &EcdsaStruct::RotateCommitMessage1M);
+    batch.delete(user_id, &id, &EcdsaStruct::RotateCommitMessage1R);
+    batch.insert(
         user_id,
         &id,
-        &EcdsaStruct::Party1MasterKey,
+        &EcdsaStruct::RotatePrivateNew,
         &party_one_master_key_rotated,
     )?;
-
-    // Send mk#2 to HCMC
-    send_mk_to_vault(state, &auth_payload, &party_one_master_key_rotated).await?;
+    session::advance(&mut batch, user_id, &id, Step::RotateSecond)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record_or_fail(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::RotateSecond),
+    )
+    .await?;
 
     Ok(Json((
         party1_second_message,
@@ -530,28 +1066,414 @@ pub async fn rotate_second(
     )))
 }
 
+/// Last rotation round. kms carries the PDL, range and correct-key proofs of
+/// the new Paillier key in `RotationParty1Message1`, party 2 checks them while
+/// rotating its share and answers with a proof of knowledge of the new share.
+/// Only a proof matching the pending key's `p2` swaps the key in, as the next
+/// version; the previous one is kept until `rotate_finalize`.
+#[post(
+    "/ecdsa/rotate/<id>/third",
+    format = "json",
+    data = "<party2_dlog_proof>"
+)]
+pub async fn rotate_third(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+    party2_dlog_proof: Json<DLogProof<GE>>,
+) -> Result<Json<KeyVersion>, AnyhowError> {
+    let user_id = &user.user_id;
+    let _guard = state.rotation_locks.lock(user_id, &id).await;
+    let _session = session::begin(state, user_id, &id, Step::RotateThird).await?;
+    let rotated: MasterKey1 = db::get(&state.db, user_id, &id, &EcdsaStruct::RotatePrivateNew)
+        .await?
+        .ok_or_else(|| {
+            anyhow!(HttpError::conflict(format!(
+                "No pending rotation for id {}, start over with /ecdsa/rotate/{}/first",
+                id, id
+            )))
+        })?;
+    if let Err(e) = expect_party2_share(&rotated.public.p2, &party2_dlog_proof.0) {
+        audit::record(
+            state,
+            AuditEvent::new(user_id, Some(&id), AuditOp::RotateThird)
+                .detail(format!("refused: {:#}", e)),
+        )
+        .await;
+        return Err(AnyhowError::from(e));
+    }
+    let current = get_mk(state, &user, &id).await?;
+
+    // Sent first: a failed upload leaves the rotation pending and retryable.
+    send_mk_to_vault(state, &user, &rotated).await?;
+
+    let mut batch = db::Batch::new();
+    let version =
+        rotation::swap_in(&state.db, &mut batch, user_id, &id, &current, &rotated).await?;
+    batch.delete(user_id, &id, &EcdsaStruct::RotatePrivateNew);
+    batch.delete(user_id, &id, &EcdsaStruct::RotateRandom1);
+    batch.insert(user_id, &id, &EcdsaStruct::RotatedAt, &gc::now())?;
+    session::advance(&mut batch, user_id, &id, Step::RotateThird)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record_or_fail(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::RotateThird)
+            .detail(format!("version {} awaiting confirmation", version.version)),
+    )
+    .await?;
+
+    Ok(Json(version))
+}
+
+/// The client confirms it stored its rotated share, the previous key version
+/// is dropped.
+#[post("/ecdsa/rotate/<id>/finalize", format = "json")]
+pub async fn rotate_finalize(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+) -> Result<Json<KeyVersion>, AnyhowError> {
+    let user_id = &user.user_id;
+    let _guard = state.rotation_locks.lock(user_id, &id).await;
+    let _session = state.session_locks.lock(user_id, &id).await;
+    let version = rotation::finalize(&state.db, user_id, &id).await?;
+    audit::record_or_fail(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::RotateFinalize)
+            .detail(format!("version {}", version.version)),
+    )
+    .await?;
+
+    Ok(Json(version))
+}
+
+/// Restores the previous key version of a rotation that was not finalized
+/// within `rotation::CONFIRM_TIMEOUT`, for a client that lost its rotated
+/// share. The vault gets the previous key back before the DB does.
+#[post("/ecdsa/rotate/<id>/rollback", format = "json")]
+pub async fn rotate_rollback(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+) -> Result<Json<KeyVersion>, AnyhowError> {
+    let user_id = &user.user_id;
+    let _guard = state.rotation_locks.lock(user_id, &id).await;
+    let _session = state.session_locks.lock(user_id, &id).await;
+    let (version, previous): (KeyVersion, MasterKey1) =
+        rotation::rollback(&state.db, user_id, &id).await?;
+    send_mk_to_vault(state, &user, &previous).await?;
+    rotation::restore(&state.db, user_id, &id, &version, &previous).await?;
+    audit::record_or_fail(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::RotateRollback)
+            .detail(format!("version {}", version.version)),
+    )
+    .await?;
+
+    Ok(Json(version))
+}
+
+/// Checks party 2's proof of knowledge of its rotated share `x2'`, whose
+/// public share the rotated `MasterKey1` expects to be `expected_p2`.
+pub fn expect_party2_share(expected_p2: &GE, proof: &DLogProof<GE>) -> Result<()> {
+    let invalid = |reason: &str| {
+        anyhow!(HttpError::new(
+            Status::BadRequest,
+            format!("Invalid rotation proof: {}", reason)
+        ))
+    };
+    if &proof.pk != expected_p2 {
+        return Err(invalid("not for the rotated share of party 2"));
+    }
+    DLogProof::verify(proof).map_err(|_| invalid("proof of knowledge does not verify"))
+}
+
 #[post("/ecdsa/<id>/recover", format = "json")]
 pub async fn recover(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     id: String,
 ) -> Result<Json<u32>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
-    let pos_old: u32 = db::get(&state.db, &auth_payload.user_id, &id, &EcdsaStruct::POS)?
+    let pos_old: HDPos = db::get(&state.db, &user.user_id, &id, &EcdsaStruct::POS)
+        .await?
         .ok_or_else(|| anyhow!("No POS for such identifier {}", id))?;
-    Ok(Json(pos_old))
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, Some(&id), AuditOp::Recover),
+    )
+    .await;
+    Ok(Json(pos_old.pos))
+}
+
+/// Issues the next child key of a wallet. Only issued children can sign.
+#[post("/ecdsa/<id>/children", format = "json")]
+pub async fn allocate_child(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+) -> Result<Json<HDChild>, AnyhowError> {
+    let user_id = &user.user_id;
+    let master_key = get_mk(state, &user, &id)
+        .await
+        .map_err(|e| anyhow!(HttpError::new(Status::NotFound, e.to_string())))?;
+
+    // Serialises allocations so two requests never get the same index.
+    let _guard = state.hd_locks.lock(user_id, &id).await;
+    let pos: HDPos = db::get(&state.db, user_id, &id, &EcdsaStruct::POS)
+        .await?
+        .ok_or_else(|| anyhow!("No POS for such userId {} - id {}", user_id, id))?;
+    let mut children: Vec<HDChild> = db::get(&state.db, user_id, &id, &EcdsaStruct::Children)
+        .await?
+        .unwrap_or_default();
+
+    let y = pos
+        .pos
+        .checked_add(1)
+        .ok_or_else(|| anyhow!("HD positions exhausted for id {}", id))?;
+    let child = hd_child(&master_key, HD_EXTERNAL_CHAIN, y);
+    children.push(child.clone());
+
+    let mut batch = db::Batch::new();
+    batch.insert(user_id, &id, &EcdsaStruct::POS, &HDPos { pos: y })?;
+    batch.insert(user_id, &id, &EcdsaStruct::Children, &children)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::AllocateChild).detail(format!(
+            "path {}/{} address {:?}",
+            child.x, child.y, child.address
+        )),
+    )
+    .await;
+
+    Ok(Json(child))
+}
+
+/// Registers a path a wallet from before child tracking already signs
+/// under, so `sign_second` accepts it again. Wallets created since then only
+/// get children from `allocate_child`.
+#[post("/ecdsa/<id>/children/<x>/<y>", format = "json", rank = 2)]
+pub async fn register_child(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+    x: u32,
+    y: u32,
+) -> Result<Json<HDChild>, AnyhowError> {
+    let user_id = &user.user_id;
+    let master_key = get_mk(state, &user, &id)
+        .await
+        .map_err(|e| anyhow!(HttpError::new(Status::NotFound, e.to_string())))?;
+
+    let _guard = state.hd_locks.lock(user_id, &id).await;
+    let tracked_since: Option<u64> =
+        db::get(&state.db, user_id, &id, &EcdsaStruct::ChildrenTracked).await?;
+    if let Some(tracked_since) = tracked_since {
+        return Err(AnyhowError::from(anyhow!(HttpError::new(
+            Status::Forbidden,
+            format!(
+                "Wallet {} tracks its children since {}, allocate them with POST /ecdsa/{}/children",
+                id, tracked_since, id
+            )
+        ))));
+    }
+    let mut children: Vec<HDChild> = db::get(&state.db, user_id, &id, &EcdsaStruct::Children)
+        .await?
+        .unwrap_or_default();
+    if children.iter().any(|child| child.x == x && child.y == y) {
+        return Err(AnyhowError::from(anyhow!(HttpError::conflict(format!(
+            "Child path {}/{} of id {} is already registered",
+            x, y, id
+        )))));
+    }
+    let child = hd_child(&master_key, x, y);
+    children.push(child.clone());
+
+    let mut batch = db::Batch::new();
+    // Later allocations continue after the highest path in use.
+    if x == HD_EXTERNAL_CHAIN {
+        let pos: HDPos = db::get(&state.db, user_id, &id, &EcdsaStruct::POS)
+            .await?
+            .ok_or_else(|| anyhow!("No POS for such userId {} - id {}", user_id, id))?;
+        if y > pos.pos {
+            batch.insert(user_id, &id, &EcdsaStruct::POS, &HDPos { pos: y })?;
+        }
+    }
+    batch.insert(user_id, &id, &EcdsaStruct::Children, &children)?;
+    db::insert_many(&state.db, batch).await?;
+    audit::record(
+        state,
+        AuditEvent::new(user_id, Some(&id), AuditOp::RegisterChild).detail(format!(
+            "path {}/{} address {:?}",
+            child.x, child.y, child.address
+        )),
+    )
+    .await;
+
+    Ok(Json(child))
+}
+
+fn hd_child(master_key: &MasterKey1, x: u32, y: u32) -> HDChild {
+    let child_key = master_key.get_child(vec![BigInt::from(x), BigInt::from(y)]);
+    HDChild {
+        x,
+        y,
+        public_key: address::compressed_hex(&child_key.public.q),
+        address: address::eth_address(&child_key.public.q),
+        created_at: gc::now(),
+    }
+}
+
+#[get("/ecdsa/<id>/children", rank = 2)]
+pub async fn children(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+) -> Result<Json<Vec<HDChild>>, AnyhowError> {
+    let children: Vec<HDChild> = db::get(&state.db, &user.user_id, &id, &EcdsaStruct::Children)
+        .await?
+        .unwrap_or_default();
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, Some(&id), AuditOp::ListChildren),
+    )
+    .await;
+    Ok(Json(children))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PublicKeyResp {
+    /// `x/y`, or `None` for the aggregate key `Q` itself.
+    pub path: Option<String>,
+    pub compressed: String,
+    pub uncompressed: String,
+    pub eth_address: Address,
+    pub btc_p2wpkh: String,
+}
+
+/// `x/y` as used by `MasterKey1::get_child`.
+fn parse_hd_path(path: &str) -> Result<(u32, u32)> {
+    let invalid = || {
+        anyhow!(HttpError::new(
+            Status::BadRequest,
+            format!("Invalid path {}, expected x/y", path)
+        ))
+    };
+    let (x, y) = path.split_once('/').ok_or_else(invalid)?;
+    Ok((
+        x.parse().map_err(|_| invalid())?,
+        y.parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// Public key and addresses of a wallet or one of its children, so services
+/// can check addresses reported by the client. `network=testnet` switches
+/// the Bitcoin address to `tb1...`.
+#[get("/ecdsa/<id>/pubkey?<path>&<network>", rank = 2)]
+pub async fn public_key(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+    path: Option<String>,
+    network: Option<String>,
+) -> Result<Json<PublicKeyResp>, AnyhowError> {
+    let hrp = match network.as_deref() {
+        None | Some("mainnet") => "bc",
+        Some("testnet") => "tb",
+        Some(other) => {
+            return Err(AnyhowError::from(anyhow!(HttpError::new(
+                Status::BadRequest,
+                format!("Unknown network {}", other)
+            ))))
+        }
+    };
+    let master_key = get_mk(state, &user, &id)
+        .await
+        .map_err(|e| anyhow!(HttpError::new(Status::NotFound, e.to_string())))?;
+    let q = match &path {
+        Some(path) => {
+            let (x, y) = parse_hd_path(path)?;
+            master_key
+                .get_child(vec![BigInt::from(x), BigInt::from(y)])
+                .public
+                .q
+        }
+        None => master_key.public.q,
+    };
+    let mut event = AuditEvent::new(&user.user_id, Some(&id), AuditOp::PublicKey);
+    if let Some(path) = &path {
+        event = event.detail(format!("path {}", path));
+    }
+    audit::record(state, event).await;
+
+    Ok(Json(PublicKeyResp {
+        path,
+        compressed: address::compressed_hex(&q),
+        uncompressed: address::uncompressed_hex(&q),
+        eth_address: address::eth_address(&q),
+        btc_p2wpkh: address::p2wpkh_address(&q, hrp)?,
+    }))
+}
+
+/// Rejects signing under a path the server never issued for this wallet.
+async fn expect_issued_child(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    x: &BigInt,
+    y: &BigInt,
+) -> Result<()> {
+    let children: Vec<HDChild> = db::get(db, user_id, id, &EcdsaStruct::Children)
+        .await?
+        .unwrap_or_default();
+    if children
+        .iter()
+        .any(|child| &BigInt::from(child.x) == x && &BigInt::from(child.y) == y)
+    {
+        return Ok(());
+    }
+
+    Err(anyhow!(HttpError::new(
+        Status::Forbidden,
+        format!(
+            "Child path {}/{} was never issued, allocate it with POST /ecdsa/{}/children \
+             or, for wallets from before child tracking, register it with POST /ecdsa/{}/children/{}/{}",
+            x, y, id, id, x, y
+        )
+    )))
+}
+
+/// The issued child of wallet `id` sending from `address`.
+pub async fn child_with_address(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    address: &Address,
+) -> Result<HDChild> {
+    let children: Vec<HDChild> = db::get(db, user_id, id, &EcdsaStruct::Children)
+        .await?
+        .unwrap_or_default();
+    children
+        .into_iter()
+        .find(|child| &child.address == address)
+        .ok_or_else(|| {
+            anyhow!(HttpError::new(
+                Status::BadRequest,
+                format!("{:?} is not an issued child of wallet {}", address, id)
+            ))
+        })
 }
 
 async fn send_mk_to_vault(
     state: &State<AppConfig>,
-    auth_payload: &AuthPayload,
+    user: &ValidatedUser,
     master_key: &MasterKey1,
 ) -> Result<()> {
     let http_client = HttpClient::new(state.hcmc_api.clone());
 
     let update_mk_resp = post(&http_client, "/api/v1/storage/secret")
         .await
-        .bearer_auth(&auth_payload.token)
+        .bearer_auth(&user.token)
         .json(&HcmcMasterKey { master_key })
         .send()
         .await?;
@@ -566,14 +1488,11 @@ async fn send_mk_to_vault(
     Ok(())
 }
 
-async fn get_mk_from_vault(
-    state: &State<AppConfig>,
-    auth_payload: &AuthPayload,
-) -> Result<MasterKey1> {
+async fn get_mk_from_vault(state: &State<AppConfig>, user: &ValidatedUser) -> Result<MasterKey1> {
     let http_client = HttpClient::new(state.hcmc_api.clone());
     let mk_resp = get(&http_client, "/api/v1/storage/secret")
         .await
-        .bearer_auth(&auth_payload.token)
+        .bearer_auth(&user.token)
         .send()
         .await?;
 
@@ -584,3 +1503,83 @@ async fn get_mk_from_vault(
     let mk = serde_json::from_str::<MasterKey1>(&mk_str)?;
     Ok(mk)
 }
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WalletSummary {
+    pub id: String,
+    pub created_at: u64,
+    pub status: SessionState,
+    /// Compressed aggregate public key `Q`, once keygen completed.
+    pub public_key: Option<String>,
+    /// Whether the chain code was agreed on, the chain code itself is not
+    /// served.
+    pub has_chain_code: bool,
+    pub children: Vec<HDChild>,
+    pub last_rotated_at: Option<u64>,
+    pub key_version: KeyVersion,
+}
+
+async fn wallet_summary(
+    db: &dyn Storage,
+    user_id: &str,
+    id: String,
+    created_at: u64,
+) -> Result<WalletSummary> {
+    let master_key: Option<MasterKey1> =
+        db::get(db, user_id, &id, &EcdsaStruct::Party1MasterKey).await?;
+    Ok(WalletSummary {
+        status: session::current(db, user_id, &id).await?,
+        public_key: master_key
+            .as_ref()
+            .map(|mk| address::compressed_hex(&mk.public.q)),
+        has_chain_code: db::exists(db, user_id, &id, &EcdsaStruct::CC).await?,
+        children: db::get(db, user_id, &id, &EcdsaStruct::Children)
+            .await?
+            .unwrap_or_default(),
+        last_rotated_at: db::get(db, user_id, &id, &EcdsaStruct::RotatedAt).await?,
+        key_version: rotation::current(db, user_id, &id).await?,
+        id,
+        created_at,
+    })
+}
+
+#[get("/ecdsa/wallets")]
+pub async fn wallets(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+) -> Result<Json<Vec<WalletSummary>>, AnyhowError> {
+    let mut wallets = vec![];
+    for (id, created_at) in db::wallets(&state.db, &user.user_id).await? {
+        wallets.push(wallet_summary(&state.db, &user.user_id, id, created_at).await?);
+    }
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, None, AuditOp::ListWallets),
+    )
+    .await;
+    Ok(Json(wallets))
+}
+
+#[get("/ecdsa/wallets/<id>")]
+pub async fn wallet(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+) -> Result<Json<WalletSummary>, AnyhowError> {
+    let created_at = db::wallet_created_at(&state.db, &user.user_id, &id)
+        .await?
+        .ok_or_else(|| {
+            anyhow!(HttpError::new(
+                Status::NotFound,
+                format!("No wallet {}", id)
+            ))
+        })?;
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, Some(&id), AuditOp::GetWallet),
+    )
+    .await;
+    Ok(Json(
+        wallet_summary(&state.db, &user.user_id, id, created_at).await?,
+    ))
+}
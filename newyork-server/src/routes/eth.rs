@@ -1,34 +1,64 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use curv::BigInt;
+use futures::TryFutureExt;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
-use web3::types::{AccessList, Address, Bytes, TransactionParameters, H256, U256, U64};
+use uuid::Uuid;
+use web3::types::{
+    AccessList, Address, BlockId, BlockNumber, Bytes, CallRequest, TransactionParameters, H256,
+    U256, U64,
+};
 use web3::{transports, Web3};
 
-use crate::utils::requests::validate_auth_token;
 use crate::AnyhowError;
 
-use super::super::auth::guards::AuthPayload;
+use super::super::audit::{self, AuditEvent, AuditOp};
+use super::super::auth::guards::ValidatedUser;
+use super::super::storage::backend::Storage;
+use super::super::storage::keys::KeyClass;
+use super::super::storage::{db, gc};
+use super::super::utils::amount::EthAmount;
+use super::super::utils::errors::HttpError;
+use super::super::utils::fees::{self, FeeSpeed, FeeStrategy, FeeTiers, FEE_HISTORY_BLOCKS};
+use super::super::utils::signature;
+use super::super::utils::transaction::{self, UnsignedTx};
 use super::super::AppConfig;
+use super::ecdsa;
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct EthTxParamsResp {
     pub to: Option<Address>,
     pub nonce: U256,
+    /// `eth_estimateGas` of the transfer.
     pub gas: U256,
+    /// `max_fee_per_gas` for EIP-1559 transactions.
     pub gas_price: U256,
     pub value: U256,
     pub data: Vec<u8>,
     pub transaction_type: Option<U64>,
     pub access_list: AccessList,
+    pub max_fee_per_gas: U256,
     pub max_priority_fee_per_gas: U256,
     pub chain_id: u64,
+    pub fee_strategy: FeeStrategy,
+    /// Tier the fields above were taken from.
+    pub speed: FeeSpeed,
+    pub fee_tiers: FeeTiers,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct EthTxParamsReqBody {
     pub from_address: Address,
     pub to_address: Address,
-    pub eth_value: f64,
+    /// `"value"` and `"unit"` fields, e.g. `"value": "0.015", "unit": "ether"`.
+    #[serde(flatten)]
+    pub amount: EthAmount,
+    #[serde(default)]
+    pub speed: FeeSpeed,
 }
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
@@ -43,59 +73,300 @@ pub struct EthSendTxReqBody {
 
 const EIP1559_TX_ID: u64 = 2;
 
+#[derive(Debug)]
+pub enum EthStruct {
+    /// Transaction built by `/eth/tx/build`, kept until it is signed.
+    UnsignedTx,
+}
+
+impl db::MPCStruct for EthStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        Some(gc::PROTOCOL_STATE_TTL)
+    }
+}
+
+impl EthStruct {
+    const ALL: [EthStruct; 1] = [EthStruct::UnsignedTx];
+
+    /// Key class of the record called `name`, for the key schema migration.
+    pub fn class_of(name: &str) -> Option<KeyClass> {
+        EthStruct::ALL
+            .iter()
+            .find(|record| db::MPCStruct::to_string(*record) == name)
+            .map(|record| db::MPCStruct::class(record))
+    }
+}
+
 #[post("/eth/tx/params", format = "json", data = "<tx_info>")]
 pub async fn tx_parameters(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     tx_info: Json<EthTxParamsReqBody>,
 ) -> Result<Json<EthTxParamsResp>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
-    let tx_params = create_eth_transaction(tx_info.to_address, tx_info.eth_value)?;
+    let tx_params = create_eth_transaction(tx_info.to_address, amount_to_wei(&tx_info)?)?;
     let web3 = establish_web3_connection(&state.alchemy_api).await?;
 
-    let (nonce, gas_price, chain_id) =
-        get_chain_required_params(tx_info.from_address, tx_params.clone(), web3).await?;
-
-    let max_priority_fee_per_gas = match tx_params.transaction_type {
-        Some(tx_type) if tx_type == U64::from(EIP1559_TX_ID) => {
-            tx_params.max_priority_fee_per_gas.unwrap_or(gas_price)
-        }
-        _ => gas_price,
+    let chain_params = get_chain_required_params(tx_info.from_address, &tx_params, &web3).await?;
+    let tier = chain_params.fee_tiers.get(tx_info.speed).clone();
+    let transaction_type = match chain_params.fee_strategy {
+        FeeStrategy::Eip1559 => Some(U64::from(EIP1559_TX_ID)),
+        FeeStrategy::Legacy => None,
     };
 
     let resp = EthTxParamsResp {
         to: tx_params.to,
-        nonce,
-        gas: tx_params.gas,
-        gas_price,
+        nonce: chain_params.nonce,
+        gas: chain_params.gas,
+        gas_price: tier.max_fee_per_gas,
         value: tx_params.value,
         data: tx_params.data.0,
-        transaction_type: tx_params.transaction_type,
+        transaction_type,
         access_list: tx_params.access_list.unwrap_or_default(),
-        max_priority_fee_per_gas,
-        chain_id,
+        max_fee_per_gas: tier.max_fee_per_gas,
+        max_priority_fee_per_gas: tier.max_priority_fee_per_gas,
+        chain_id: chain_params.chain_id,
+        fee_strategy: chain_params.fee_strategy,
+        speed: tx_info.speed,
+        fee_tiers: chain_params.fee_tiers,
     };
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, None, AuditOp::TxParams).detail(format!(
+            "{:?} -> {:?}, {} wei",
+            tx_info.from_address, tx_info.to_address, resp.value
+        )),
+    )
+    .await;
 
     Ok(Json(resp))
 }
 
+/// The transaction plus its sighash, the `message` to sign with the sender's
+/// child key. Passing `tx_id` to `sign_second` signs exactly this `tx`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EthTxBuildResp {
+    pub tx_id: String,
+    pub tx: UnsignedTx,
+    pub sighash: H256,
+    pub fee_tiers: FeeTiers,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct EthTxBuildReqBody {
+    /// Wallet whose issued child owns `from_address`.
+    pub wallet_id: String,
+    #[serde(flatten)]
+    pub params: EthTxParamsReqBody,
+}
+
+/// A built transaction, bound to the wallet and child path it sends from.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct BuiltTx {
+    pub wallet_id: String,
+    pub x: u32,
+    pub y: u32,
+    pub tx: UnsignedTx,
+}
+
+#[post("/eth/tx/build", format = "json", data = "<build>")]
+pub async fn tx_build(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    build: Json<EthTxBuildReqBody>,
+) -> Result<Json<EthTxBuildResp>, AnyhowError> {
+    let tx_info = &build.params;
+    let child = ecdsa::child_with_address(
+        &state.db,
+        &user.user_id,
+        &build.wallet_id,
+        &tx_info.from_address,
+    )
+    .await?;
+    let tx_params = create_eth_transaction(tx_info.to_address, amount_to_wei(tx_info)?)?;
+    let web3 = establish_web3_connection(&state.alchemy_api).await?;
+
+    let chain_params = get_chain_required_params(tx_info.from_address, &tx_params, &web3).await?;
+    let tier = chain_params.fee_tiers.get(tx_info.speed).clone();
+    let tx = UnsignedTx {
+        fee_strategy: chain_params.fee_strategy,
+        chain_id: chain_params.chain_id,
+        nonce: chain_params.nonce,
+        to: tx_info.to_address,
+        value: tx_params.value,
+        data: tx_params.data,
+        gas: chain_params.gas,
+        max_fee_per_gas: tier.max_fee_per_gas,
+        max_priority_fee_per_gas: tier.max_priority_fee_per_gas,
+    };
+
+    let tx_id = Uuid::new_v4().to_string();
+    let built = BuiltTx {
+        wallet_id: build.wallet_id.clone(),
+        x: child.x,
+        y: child.y,
+        tx,
+    };
+    db::insert(
+        &state.db,
+        &user.user_id,
+        &tx_id,
+        &EthStruct::UnsignedTx,
+        &built,
+    )
+    .await?;
+    let tx = built.tx;
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, Some(&build.wallet_id), AuditOp::TxBuild)
+            .hash(tx.sighash())
+            .detail(format!(
+                "tx {} {:?} ({}/{}) -> {:?}, {} wei",
+                tx_id, tx_info.from_address, child.x, child.y, tx.to, tx.value
+            )),
+    )
+    .await;
+
+    Ok(Json(EthTxBuildResp {
+        tx_id,
+        sighash: tx.sighash(),
+        tx,
+        fee_tiers: chain_params.fee_tiers,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EthTxAssembleReqBody {
+    pub tx: UnsignedTx,
+    /// `sign_second` response over the tx's sighash.
+    pub signature: party_one::SignatureRecid,
+    /// Rejects the signature unless it was made by this address.
+    #[serde(default)]
+    pub from_address: Option<Address>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EthTxAssembleResp {
+    /// Ready for `/eth/tx/send`.
+    pub raw_tx: Bytes,
+    pub tx_hash: H256,
+    /// Sender recovered from the signature.
+    pub from: Address,
+}
+
+#[post("/eth/tx/assemble", format = "json", data = "<signed>")]
+pub async fn tx_assemble(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    signed: Json<EthTxAssembleReqBody>,
+) -> Result<Json<EthTxAssembleResp>, AnyhowError> {
+    let bad_request = |message: String| anyhow!(HttpError::new(Status::BadRequest, message));
+
+    // Recovering against the rebuilt sighash proves the signature covers
+    // exactly this transaction.
+    let signature = signature::normalize_low_s(signed.signature.clone());
+    let from = signature::recover_address(&signed.tx.sighash().0, &signature)
+        .map_err(|e| bad_request(format!("{:#}", e)))?;
+    if let Some(from_address) = signed.from_address {
+        if from != from_address {
+            return Err(AnyhowError::from(bad_request(format!(
+                "Signature is from {:?}, not {:?}",
+                from, from_address
+            ))));
+        }
+    }
+
+    let raw_tx = signed
+        .tx
+        .encode_signed(&signature)
+        .map_err(|e| bad_request(format!("{:#}", e)))?;
+    let tx_hash = transaction::tx_hash(&raw_tx);
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, None, AuditOp::TxAssemble)
+            .hash(tx_hash)
+            .detail(format!("{:?} -> {:?}", from, signed.tx.to)),
+    )
+    .await;
+    Ok(Json(EthTxAssembleResp {
+        tx_hash,
+        raw_tx,
+        from,
+    }))
+}
+
 #[post("/eth/tx/send", format = "json", data = "<signed>")]
 pub async fn tx_send(
     state: &State<AppConfig>,
-    auth_payload: AuthPayload,
+    user: ValidatedUser,
     signed: Json<EthSendTxReqBody>,
 ) -> Result<Json<EthSendTxResp>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
     let web3 = establish_web3_connection(&state.alchemy_api).await?;
     let tx_hash = send_tx(web3, signed.raw_tx.clone()).await?;
+    audit::record(
+        state,
+        AuditEvent::new(&user.user_id, None, AuditOp::TxSend).hash(tx_hash),
+    )
+    .await;
 
     Ok(Json(EthSendTxResp { tx_hash }))
 }
 
-fn create_eth_transaction(to: Address, eth_value: f64) -> Result<TransactionParameters> {
+/// The transaction a signing request is for, given inline or as the id of a
+/// built one. Only one of the two may be set, a built one is only signed by
+/// the wallet `id` and child path `x/y` it was built for.
+pub async fn tx_to_sign(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    (x, y): (&BigInt, &BigInt),
+    tx: &Option<UnsignedTx>,
+    tx_id: &Option<String>,
+) -> Result<Option<UnsignedTx>> {
+    match (tx, tx_id) {
+        (None, None) => Ok(None),
+        (Some(tx), None) => Ok(Some(tx.clone())),
+        (None, Some(tx_id)) => {
+            let built: BuiltTx = db::get(db, user_id, tx_id, &EthStruct::UnsignedTx)
+                .await?
+                .ok_or_else(|| {
+                    anyhow!(HttpError::new(
+                        Status::NotFound,
+                        format!("Unknown or expired transaction {}", tx_id)
+                    ))
+                })?;
+            if built.wallet_id != id || &BigInt::from(built.x) != x || &BigInt::from(built.y) != y {
+                return Err(anyhow!(HttpError::new(
+                    Status::Forbidden,
+                    format!(
+                        "Transaction {} was built for {}/{}/{}, not {}/{}/{}",
+                        tx_id, built.wallet_id, built.x, built.y, id, x, y
+                    )
+                )));
+            }
+            Ok(Some(built.tx))
+        }
+        (Some(_), Some(_)) => Err(anyhow!(HttpError::new(
+            Status::BadRequest,
+            "Pass either tx or tx_id, not both".to_string()
+        ))),
+    }
+}
+
+fn amount_to_wei(tx_info: &EthTxParamsReqBody) -> Result<U256> {
+    tx_info
+        .amount
+        .to_wei()
+        .map_err(|e| anyhow!(HttpError::new(Status::BadRequest, e.to_string())))
+}
+
+fn create_eth_transaction(to: Address, value: U256) -> Result<TransactionParameters> {
     Ok(TransactionParameters {
         to: Some(to),
-        value: eth_to_wei(eth_value),
+        value,
         ..Default::default()
     })
 }
@@ -105,52 +376,93 @@ pub async fn establish_web3_connection(url: &str) -> Result<Web3<transports::Web
     Ok(Web3::new(transport))
 }
 
+pub struct ChainParams {
+    pub nonce: U256,
+    pub gas: U256,
+    pub chain_id: u64,
+    pub fee_strategy: FeeStrategy,
+    pub fee_tiers: FeeTiers,
+}
+
 pub async fn get_chain_required_params(
     from_address: Address,
-    tx_params: TransactionParameters,
-    web3: Web3<transports::WebSocket>,
-) -> Result<(U256, U256, u64)> {
-    macro_rules! maybe {
-        ($o: expr, $f: expr) => {
-            async {
-                match $o {
-                    Some(value) => Ok(value),
-                    None => $f.await,
-                }
-            }
-        };
-    }
-
-    let gas_price = match tx_params.transaction_type {
-        Some(tx_type)
-            if tx_type == U64::from(EIP1559_TX_ID) && tx_params.max_fee_per_gas.is_some() =>
-        {
-            tx_params.max_fee_per_gas
-        }
-        _ => tx_params.gas_price,
+    tx_params: &TransactionParameters,
+    web3: &Web3<transports::WebSocket>,
+) -> Result<ChainParams> {
+    let call = CallRequest {
+        from: Some(from_address),
+        to: tx_params.to,
+        value: Some(tx_params.value),
+        data: Some(tx_params.data.clone()),
+        ..Default::default()
     };
 
-    let (nonce, gas_price, chain_id) = futures::future::try_join3(
-        maybe!(
-            tx_params.nonce,
-            web3.eth().transaction_count(from_address, None)
-        ),
-        maybe!(gas_price, web3.eth().gas_price()),
-        maybe!(tx_params.chain_id.map(U256::from), web3.eth().chain_id()),
+    let (nonce, gas, chain_id, (fee_strategy, fee_tiers)) = futures::future::try_join4(
+        web3.eth()
+            .transaction_count(from_address, None)
+            .map_err(anyhow::Error::from),
+        web3.eth()
+            .estimate_gas(call, None)
+            .map_err(anyhow::Error::from),
+        web3.eth().chain_id().map_err(anyhow::Error::from),
+        estimate_fees(web3),
     )
     .await?;
 
-    Ok((nonce, gas_price, chain_id.as_u64()))
+    Ok(ChainParams {
+        nonce,
+        gas,
+        chain_id: chain_id.as_u64(),
+        fee_strategy,
+        fee_tiers,
+    })
+}
+
+/// EIP-1559 tiers from the recent blocks' base fees and tips once the chain
+/// has a base fee, `eth_gasPrice` otherwise or if the node has no
+/// `eth_feeHistory`.
+pub async fn estimate_fees(web3: &Web3<transports::WebSocket>) -> Result<(FeeStrategy, FeeTiers)> {
+    let latest = web3
+        .eth()
+        .block(BlockId::Number(BlockNumber::Latest))
+        .await?;
+    if latest.and_then(|block| block.base_fee_per_gas).is_some() {
+        let percentiles = FeeSpeed::ALL
+            .iter()
+            .map(|speed| speed.reward_percentile())
+            .collect();
+        match web3
+            .eth()
+            .fee_history(
+                U256::from(FEE_HISTORY_BLOCKS),
+                BlockNumber::Latest,
+                Some(percentiles),
+            )
+            .await
+        {
+            Ok(history) => {
+                let tiers = fees::eip1559_tiers(
+                    &history.base_fee_per_gas,
+                    &history.gas_used_ratio,
+                    &history.reward.unwrap_or_default(),
+                );
+                if let Some(tiers) = tiers {
+                    return Ok((FeeStrategy::Eip1559, tiers));
+                }
+                warn!("Empty eth_feeHistory, falling back to the legacy gas price");
+            }
+            Err(e) => warn!(
+                "eth_feeHistory failed, falling back to the legacy gas price: {}",
+                e
+            ),
+        }
+    }
+
+    let gas_price = web3.eth().gas_price().await?;
+    Ok((FeeStrategy::Legacy, fees::legacy_tiers(gas_price)))
 }
 
 pub async fn send_tx(web3: Web3<transports::WebSocket>, raw_tx: Bytes) -> Result<H256> {
     let tx_hash = web3.eth().send_raw_transaction(raw_tx).await?;
     Ok(tx_hash)
 }
-
-pub fn eth_to_wei(eth_value: f64) -> U256 {
-    let result = eth_value * 1_000_000_000_000_000_000.0;
-    let result = result as u128;
-
-    U256::from(result)
-}
@@ -1,5 +1,9 @@
+pub mod approvals;
+pub mod audit;
 pub mod ecdsa;
 pub mod eddsa;
 pub mod eth;
+pub mod monitoring;
 pub mod ping;
+pub mod policy;
 pub mod schnorr;
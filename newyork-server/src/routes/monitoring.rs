@@ -0,0 +1,11 @@
+use rocket::serde::json::Json;
+use rocket::State;
+
+use super::super::storage::gc::GcReport;
+use super::super::AppConfig;
+
+/// Served from the counters the sweeper keeps, no request touches the DB.
+#[get("/monitoring/gc")]
+pub fn gc(state: &State<AppConfig>) -> Json<GcReport> {
+    Json(state.gc_stats.report())
+}
@@ -0,0 +1,108 @@
+use anyhow::anyhow;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+use super::super::audit::{self, AuditEvent, AuditOp};
+use super::super::auth::guards::{PolicyAdmin, ValidatedUser};
+use super::super::policy::{Policy, PolicyStruct};
+use super::super::storage::db;
+use super::super::utils::errors::HttpError;
+use super::super::AppConfig;
+use crate::AnyhowError;
+
+async fn expect_wallet(state: &AppConfig, user_id: &str, id: &str) -> Result<(), AnyhowError> {
+    match db::wallet_created_at(state.db.as_ref(), user_id, id).await? {
+        Some(_) => Ok(()),
+        None => Err(AnyhowError::from(anyhow!(HttpError::new(
+            Status::NotFound,
+            format!("No wallet {}", id)
+        )))),
+    }
+}
+
+fn no_policy(id: &str) -> AnyhowError {
+    AnyhowError::from(anyhow!(HttpError::new(
+        Status::NotFound,
+        format!("Wallet {} has no policy", id)
+    )))
+}
+
+#[get("/ecdsa/<id>/policy", rank = 2)]
+pub async fn get_policy(
+    state: &State<AppConfig>,
+    user: ValidatedUser,
+    id: String,
+) -> Result<Json<Policy>, AnyhowError> {
+    expect_wallet(state, &user.user_id, &id).await?;
+    match db::get(&state.db, &user.user_id, &id, &PolicyStruct::Policy).await? {
+        Some(policy) => Ok(Json(policy)),
+        None => Err(no_policy(&id)),
+    }
+}
+
+/// Turns away policy admins changing the policy of their own wallets, or
+/// owners could still lift their own limits.
+fn expect_other_owner(admin: &PolicyAdmin, owner: &str) -> Result<(), AnyhowError> {
+    if admin.0.user_id == owner {
+        return Err(AnyhowError::from(anyhow!(HttpError::new(
+            Status::Forbidden,
+            "Policies of your own wallets are set by another policy admin".to_string()
+        ))));
+    }
+    Ok(())
+}
+
+/// Creates or replaces the policy of wallet `id` of `owner`. Spends booked
+/// under the previous policy keep counting against the new limits.
+#[put("/ecdsa/<id>/policy?<owner>", format = "json", data = "<policy>")]
+pub async fn put_policy(
+    state: &State<AppConfig>,
+    admin: PolicyAdmin,
+    id: String,
+    owner: String,
+    policy: Json<Policy>,
+) -> Result<Json<Policy>, AnyhowError> {
+    expect_other_owner(&admin, &owner)?;
+    expect_wallet(state, &owner, &id).await?;
+    policy
+        .validate()
+        .map_err(|e| anyhow!(HttpError::new(Status::BadRequest, e)))?;
+
+    let _guard = state.policy_locks.lock(&owner, &id).await;
+    db::insert(&state.db, &owner, &id, &PolicyStruct::Policy, &policy.0).await?;
+    audit::record(
+        state,
+        AuditEvent::new(&owner, Some(&id), AuditOp::PolicySet).detail(format!(
+            "set by {}: {}",
+            admin.0.user_id,
+            serde_json::to_string(&policy.0)?
+        )),
+    )
+    .await;
+    Ok(policy)
+}
+
+#[delete("/ecdsa/<id>/policy?<owner>")]
+pub async fn delete_policy(
+    state: &State<AppConfig>,
+    admin: PolicyAdmin,
+    id: String,
+    owner: String,
+) -> Result<Status, AnyhowError> {
+    expect_other_owner(&admin, &owner)?;
+    expect_wallet(state, &owner, &id).await?;
+
+    let _guard = state.policy_locks.lock(&owner, &id).await;
+    let policy: Option<Policy> = db::take(&state.db, &owner, &id, &PolicyStruct::Policy).await?;
+    if policy.is_none() {
+        return Err(no_policy(&id));
+    }
+    audit::record(
+        state,
+        AuditEvent::new(&owner, Some(&id), AuditOp::PolicyRemoved)
+            .detail(format!("removed by {}", admin.0.user_id)),
+    )
+    .await;
+    Ok(Status::NoContent)
+}
@@ -1,11 +1,23 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use rocket;
-use rocket::Request;
-use rocksdb;
+use rocket::fairing::AdHoc;
+use rocket::{Request, Route};
 
 use crate::utils::settings::{get_app_env, AppEnv};
 
+use super::auth::jwt::{self, AuthMode, JwksSource, JwtVerifier};
+use super::policy::PolicyStruct;
 use super::routes::*;
-use super::storage::db;
+use super::storage::backend::{Storage, Unavailable};
+use super::storage::encrypted::{self, EncryptedStorage, MasterKeyring};
+use super::storage::gc::{self, GcStats};
+use super::storage::keys::KeyClass;
+use super::storage::memory::MemoryStorage;
+use super::storage::rocks::RocksDbStorage;
+use super::storage::schema;
+use super::utils::locks::KeyedLocks;
 use super::AppConfig;
 
 #[catch(500)]
@@ -23,48 +35,233 @@ fn not_found(req: &Request) -> String {
     format!("Unknown route '{}'.", req.uri())
 }
 
+/// Every route the server mounts at `/`.
+pub fn routes() -> Vec<Route> {
+    routes![
+        ping::ping,
+        ecdsa::first_message,
+        ecdsa::second_message,
+        ecdsa::third_message,
+        ecdsa::fourth_message,
+        ecdsa::chain_code_first_message,
+        ecdsa::chain_code_second_message,
+        ecdsa::sign_first,
+        ecdsa::sign_second,
+        ecdsa::rotate_first,
+        ecdsa::rotate_second,
+        ecdsa::rotate_third,
+        ecdsa::rotate_finalize,
+        ecdsa::rotate_rollback,
+        ecdsa::recover,
+        ecdsa::allocate_child,
+        ecdsa::register_child,
+        ecdsa::children,
+        ecdsa::public_key,
+        ecdsa::wallets,
+        ecdsa::wallet,
+        policy::get_policy,
+        policy::put_policy,
+        policy::delete_policy,
+        approvals::list_approvals,
+        approvals::get_approval,
+        approvals::approve,
+        approvals::reject,
+        audit::audit_log,
+        eth::tx_parameters,
+        eth::tx_build,
+        eth::tx_assemble,
+        eth::tx_send,
+        monitoring::gc,
+    ]
+}
+
 #[launch]
 pub fn get_server() -> _ {
     let env_configs = get_app_env::<AppEnv>(".env.staging");
+    let (db, encrypted_db) = match get_keyring(&env_configs) {
+        Some(keyring) => {
+            let mut encrypted_db = EncryptedStorage::new(get_db(&env_configs), keyring);
+            if env_configs.encryption_migrate_plaintext.unwrap_or(false) {
+                warn!("Accepting unencrypted records until the first rewrap pass sealed them");
+                encrypted_db = encrypted_db.migrating_plaintext();
+            }
+            let encrypted_db = Arc::new(encrypted_db);
+            (encrypted_db.clone() as Arc<dyn Storage>, Some(encrypted_db))
+        }
+        None => {
+            warn!("No master key configured, MPC secrets are stored unencrypted");
+            (get_db(&env_configs), None)
+        }
+    };
+    let rewrap_interval = Duration::from_secs(env_configs.key_rewrap_interval_secs.unwrap_or(3600));
+    let gc_interval = Duration::from_secs(env_configs.gc_interval_secs.unwrap_or(300));
+    let jwks_refresh_interval =
+        Duration::from_secs(env_configs.jwks_refresh_interval_secs.unwrap_or(3600));
+    let auth = get_auth_mode(&env_configs);
+    let verifier = auth.verifier();
+
     let app_config = AppConfig {
-        db: get_db(),
+        db,
         hcmc_api: env_configs.hcmc_host,
+        auth,
+        user_claim: env_configs
+            .jwt_user_claim
+            .unwrap_or_else(|| "email".to_string()),
+        role_claim: env_configs
+            .jwt_role_claim
+            .unwrap_or_else(|| "cognito:groups".to_string()),
+        approver_role: env_configs
+            .approver_role
+            .unwrap_or_else(|| "approver".to_string()),
+        policy_admin_role: env_configs
+            .policy_admin_role
+            .unwrap_or_else(|| "policy_admin".to_string()),
         alchemy_api: env_configs.alchemy_api,
+        gc_stats: Arc::new(GcStats::default()),
+        session_locks: KeyedLocks::new(),
+        hd_locks: KeyedLocks::new(),
+        policy_locks: KeyedLocks::new(),
+        rotation_locks: KeyedLocks::new(),
     };
 
     rocket::build()
         .register("/", catchers![internal_error, not_found, bad_request])
-        .mount(
-            "/",
-            routes![
-                ping::ping,
-                ecdsa::first_message,
-                ecdsa::second_message,
-                ecdsa::chain_code_first_message,
-                ecdsa::chain_code_second_message,
-                ecdsa::sign_first,
-                ecdsa::sign_second,
-                ecdsa::rotate_first,
-                ecdsa::rotate_second,
-                ecdsa::recover,
-                eth::tx_parameters,
-                eth::tx_send,
-            ],
-        )
+        .mount("/", routes())
         .manage(app_config)
+        .attach(AdHoc::on_liftoff("Master key rewrap", move |_| {
+            Box::pin(async move {
+                if let Some(encrypted_db) = encrypted_db {
+                    tokio::spawn(encrypted::rewrap_task(encrypted_db, rewrap_interval));
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("JWKS refresh", move |_| {
+            Box::pin(async move {
+                if let Some(verifier) = verifier {
+                    tokio::spawn(jwt::refresh_task(verifier, jwks_refresh_interval));
+                }
+            })
+        }))
+        .attach(AdHoc::try_on_ignite("Key schema migration", |rocket| {
+            Box::pin(async move {
+                let db = match rocket.state::<AppConfig>() {
+                    Some(config) => config.db.clone(),
+                    None => return Ok(rocket),
+                };
+                // Unreadable storage is reported on every access instead.
+                if let Err(e) = schema::version(db.as_ref()).await {
+                    error!("Skipping key schema migration: {:#?}", e);
+                    return Ok(rocket);
+                }
+                match schema::migrate(
+                    db.as_ref(),
+                    &record_class,
+                    &ecdsa::EcdsaStruct::Party1MasterKey,
+                )
+                .await
+                {
+                    Ok(0) => Ok(rocket),
+                    Ok(count) => {
+                        info!("Migrated {} records to the current key schema", count);
+                        Ok(rocket)
+                    }
+                    Err(e) => {
+                        error!("Key schema migration failed: {:#?}", e);
+                        Err(rocket)
+                    }
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Expired state sweeper", move |rocket| {
+            Box::pin(async move {
+                if let Some(config) = rocket.state::<AppConfig>() {
+                    tokio::spawn(gc::sweeper_task(
+                        config.db.clone(),
+                        config.gc_stats.clone(),
+                        gc_interval,
+                    ));
+                }
+            })
+        }))
+}
+
+/// Key class of a record name written by any of the routes.
+pub fn record_class(name: &str) -> Option<KeyClass> {
+    ecdsa::EcdsaStruct::class_of(name)
+        .or_else(|| eth::EthStruct::class_of(name))
+        .or_else(|| PolicyStruct::class_of(name))
+}
+
+fn get_auth_mode(env_configs: &AppEnv) -> AuthMode {
+    let source = match (&env_configs.jwks_file, &env_configs.jwks_url) {
+        (Some(path), _) => Some(JwksSource::File(path.clone())),
+        (None, Some(url)) => Some(JwksSource::Url(url.clone())),
+        (None, None) => None,
+    };
+    let default_mode = if source.is_some() { "local" } else { "remote" };
+    let mode = env_configs.auth_mode.as_deref().unwrap_or(default_mode);
+    if mode == "remote" {
+        return AuthMode::Remote;
+    }
+
+    let verifier = match (source, &env_configs.jwt_issuer, &env_configs.jwt_audience) {
+        (Some(source), Some(issuer), Some(audience)) => {
+            Arc::new(JwtVerifier::new(source, issuer.clone(), audience.clone()))
+        }
+        _ => panic!(
+            "AUTH_MODE={} needs JWKS_FILE or JWKS_URL, JWT_ISSUER and JWT_AUDIENCE",
+            mode
+        ),
+    };
+    match mode {
+        "local" => AuthMode::Local(verifier),
+        "local_with_remote_fallback" => AuthMode::LocalWithRemoteFallback(verifier),
+        other => panic!("Unknown AUTH_MODE {}", other),
+    }
 }
 
-fn get_db() -> db::DB {
-    match rocksdb::DB::open_default("./db") {
-        Ok(db) => {
-            info!("Init RocksDB connection successfully");
-            db::DB::Local(db)
+fn get_keyring(env_configs: &AppEnv) -> Option<MasterKeyring> {
+    let keyring = match (&env_configs.master_key_file, &env_configs.master_key) {
+        (Some(path), _) => MasterKeyring::from_file(path),
+        (None, Some(hex_key)) => MasterKeyring::from_hex(
+            env_configs.master_key_id.as_deref().unwrap_or("default"),
+            hex_key,
+        ),
+        (None, None) => return None,
+    };
+    match keyring {
+        Ok(keyring) => Some(keyring),
+        Err(e) => panic!("Couldn't load master keyring ({})", e),
+    }
+}
+
+fn get_db(env_configs: &AppEnv) -> Arc<dyn Storage> {
+    match env_configs.db_backend.as_deref().unwrap_or("rocksdb") {
+        "memory" => {
+            warn!("Using in-memory storage, all MPC state is lost on shutdown");
+            Arc::new(MemoryStorage::new())
+        }
+        "rocksdb" => {
+            let path = env_configs.db_path.as_deref().unwrap_or("./db");
+            match RocksDbStorage::open(path) {
+                Ok(db) => {
+                    info!("Init RocksDB connection successfully");
+                    Arc::new(db)
+                }
+                Err(e) => {
+                    error!("{:#?}", e);
+                    Arc::new(Unavailable(
+                        "Failed to connect RocksDB, please check your configuration".to_string(),
+                    ))
+                }
+            }
         }
-        Err(e) => {
-            error!("{:#?}", e);
-            db::DB::ConnError(
-                "Failed to connect RocksDB, please check your configuration".to_string(),
-            )
+        other => {
+            error!("Unknown DB_BACKEND {}", other);
+            Arc::new(Unavailable(format!(
+                "Unsupported storage backend {}",
+                other
+            )))
         }
     }
 }
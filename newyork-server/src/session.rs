@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+
+use rocket::http::Status;
+
+use crate::routes::ecdsa::EcdsaStruct;
+use crate::storage::backend::Storage;
+use crate::storage::db;
+use crate::storage::gc::{self, now};
+use crate::utils::errors::HttpError;
+use crate::utils::locks::KeyedGuard;
+use crate::AppConfig;
+
+/// Where a wallet `id` is in the keygen / rotate flows. `Signing` is tracked
+/// per signing session id instead, so a wallet can have several signatures
+/// in flight.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum SessionState {
+    KeygenStarted,
+    KeygenDlogDone,
+    /// Party 1 committed to `Dec(c')`, waiting for party 2's opening.
+    KeygenPdlStarted,
+    KeygenPdlDone,
+    ChainCodeStarted,
+    Complete,
+    Signing,
+    Rotating,
+    /// The rotated key waits for party 2 to prove its new share.
+    RotationPending,
+    /// The rotated key is in use, the previous version is kept until the
+    /// client finalizes or rolls back the rotation.
+    RotationUnconfirmed,
+}
+
+impl SessionState {
+    fn next_step(&self) -> &'static str {
+        match self {
+            SessionState::KeygenStarted => "/ecdsa/keygen/<id>/second",
+            SessionState::KeygenDlogDone => "/ecdsa/keygen/<id>/third",
+            SessionState::KeygenPdlStarted => "/ecdsa/keygen/<id>/fourth",
+            SessionState::KeygenPdlDone => "/ecdsa/keygen/<id>/chaincode/first",
+            SessionState::ChainCodeStarted => "/ecdsa/keygen/<id>/chaincode/second",
+            SessionState::Complete => "/ecdsa/sign/<id>/first or /ecdsa/rotate/<id>/first",
+            SessionState::Signing => "/ecdsa/sign/<id>/<session_id>/second",
+            SessionState::Rotating => "/ecdsa/rotate/<id>/second",
+            SessionState::RotationPending => "/ecdsa/rotate/<id>/third",
+            SessionState::RotationUnconfirmed => {
+                "/ecdsa/rotate/<id>/finalize or, once timed out, /ecdsa/rotate/<id>/rollback"
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Session {
+    pub state: SessionState,
+    pub updated_at: u64,
+}
+
+/// Protocol steps after `/ecdsa/keygen/first`, each only valid from some states.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Step {
+    KeygenSecond,
+    KeygenThird,
+    KeygenFourth,
+    ChainCodeFirst,
+    ChainCodeSecond,
+    SignFirst,
+    SignSecond,
+    RotateFirst,
+    RotateSecond,
+    RotateThird,
+    RotateFinalize,
+    RotateRollback,
+}
+
+impl Step {
+    fn allowed_from(&self) -> &'static [SessionState] {
+        match self {
+            Step::KeygenSecond => &[SessionState::KeygenStarted],
+            Step::KeygenThird => &[SessionState::KeygenDlogDone],
+            Step::KeygenFourth => &[SessionState::KeygenPdlStarted],
+            Step::ChainCodeFirst => &[SessionState::KeygenPdlDone],
+            Step::ChainCodeSecond => &[SessionState::ChainCodeStarted],
+            // Checked against the wallet, the step leads to `Signing` on the
+            // new signing session id.
+            // A client holding the rotated share can sign before finalizing.
+            Step::SignFirst => &[SessionState::Complete, SessionState::RotationUnconfirmed],
+            Step::SignSecond => &[SessionState::Signing],
+            // Starting over an abandoned rotation is fine, the coin flip and
+            // pending key of the previous attempt are simply replaced.
+            Step::RotateFirst => &[
+                SessionState::Complete,
+                SessionState::Rotating,
+                SessionState::RotationPending,
+            ],
+            Step::RotateSecond => &[SessionState::Rotating],
+            Step::RotateThird => &[SessionState::RotationPending],
+            Step::RotateFinalize | Step::RotateRollback => &[SessionState::RotationUnconfirmed],
+        }
+    }
+
+    fn leads_to(&self) -> SessionState {
+        match self {
+            Step::KeygenSecond => SessionState::KeygenDlogDone,
+            Step::KeygenThird => SessionState::KeygenPdlStarted,
+            Step::KeygenFourth => SessionState::KeygenPdlDone,
+            Step::ChainCodeFirst => SessionState::ChainCodeStarted,
+            Step::ChainCodeSecond => SessionState::Complete,
+            Step::SignFirst => SessionState::Signing,
+            Step::SignSecond => SessionState::Complete,
+            Step::RotateFirst => SessionState::Rotating,
+            Step::RotateSecond => SessionState::RotationPending,
+            Step::RotateThird => SessionState::RotationUnconfirmed,
+            Step::RotateFinalize | Step::RotateRollback => SessionState::Complete,
+        }
+    }
+
+    fn is_keygen(&self) -> bool {
+        matches!(
+            self,
+            Step::KeygenSecond
+                | Step::KeygenThird
+                | Step::KeygenFourth
+                | Step::ChainCodeFirst
+                | Step::ChainCodeSecond
+        )
+    }
+}
+
+/// Where wallet `id` is, wallets created before sessions were tracked are
+/// `Complete`.
+pub async fn current(db: &dyn Storage, user_id: &str, id: &str) -> Result<SessionState> {
+    let session: Option<Session> = db::get(db, user_id, id, &EcdsaStruct::Session).await?;
+    Ok(session.map_or(SessionState::Complete, |session| session.state))
+}
+
+/// Fails with a 409 naming the expected next step unless `step` may run now,
+/// or with a 404 for a wallet that does not exist.
+pub async fn expect(db: &dyn Storage, user_id: &str, id: &str, step: Step) -> Result<()> {
+    let session: Option<Session> = db::get(db, user_id, id, &EcdsaStruct::Session).await?;
+    let state = match session {
+        Some(session) => session.state,
+        None if step.is_keygen() => {
+            return Err(anyhow!(HttpError::conflict(format!(
+                "No keygen session for id {}, expected next step /ecdsa/keygen/first",
+                id
+            ))))
+        }
+        None if step == Step::SignSecond => {
+            return Err(anyhow!(HttpError::conflict(format!(
+                "No signing session {}, expected next step /ecdsa/sign/<id>/first",
+                id
+            ))))
+        }
+        // Wallets created before sessions were tracked are complete.
+        None if db::exists(db, user_id, id, &EcdsaStruct::Party1MasterKey).await? => {
+            SessionState::Complete
+        }
+        None => {
+            return Err(anyhow!(HttpError::new(
+                Status::NotFound,
+                format!("No wallet {}", id)
+            )))
+        }
+    };
+
+    if !step.allowed_from().contains(&state) {
+        return Err(anyhow!(HttpError::conflict(format!(
+            "{:?} is out of order for id {} in state {:?}, expected next step {}",
+            step,
+            id,
+            state,
+            state.next_step()
+        ))));
+    }
+    Ok(())
+}
+
+/// `expect`, holding the session of `id` until the guard is dropped: two
+/// requests for the same step cannot both pass the check before one of them
+/// wrote the state it advances to.
+pub async fn begin<'a>(
+    state: &'a AppConfig,
+    user_id: &str,
+    id: &str,
+    step: Step,
+) -> Result<KeyedGuard<'a>> {
+    let guard = state.session_locks.lock(user_id, id).await;
+    expect(state.db.as_ref(), user_id, id, step).await?;
+    Ok(guard)
+}
+
+/// Records the state `step` leads to as part of the step's write batch.
+pub fn advance(batch: &mut db::Batch, user_id: &str, id: &str, step: Step) -> Result<()> {
+    start(batch, user_id, id, step.leads_to())
+}
+
+/// Sessions stuck in an intermediate state expire with the protocol state
+/// they guard; only `Complete` is kept, and `RotationUnconfirmed`, which
+/// guards the previous key version.
+pub fn start(batch: &mut db::Batch, user_id: &str, id: &str, state: SessionState) -> Result<()> {
+    batch.insert(
+        user_id,
+        id,
+        &EcdsaStruct::Session,
+        &Session {
+            state,
+            updated_at: now(),
+        },
+    )?;
+    if !matches!(
+        state,
+        SessionState::Complete | SessionState::RotationUnconfirmed
+    ) {
+        batch.expire(
+            user_id,
+            id,
+            &EcdsaStruct::Session,
+            Some(gc::PROTOCOL_STATE_TTL),
+        );
+    }
+    Ok(())
+}
+
+/// Drops a finished signing session, replays are still caught through the
+/// spent ephemeral marker.
+pub fn finish(batch: &mut db::Batch, user_id: &str, id: &str) {
+    batch.delete(user_id, id, &EcdsaStruct::Session);
+}
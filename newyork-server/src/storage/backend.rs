@@ -0,0 +1,73 @@
+use anyhow::{anyhow, Result};
+
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// A key and the value `Storage::batch_if` expects it to hold.
+pub type ExpectedValue = (Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// Key/value backend the MPC state is persisted into. `db::insert`/`db::get`
+/// take care of key layout and (de)serialization on top of it.
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    async fn delete(&self, key: &[u8]) -> Result<()>;
+
+    /// Removes `key` and returns what it held. Of several concurrent callers
+    /// at most one gets the value.
+    async fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Every entry whose key starts with `prefix`, in key order.
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>>;
+
+    /// Applies all `ops` or none of them.
+    async fn batch(&self, ops: Vec<BatchOp>) -> Result<()>;
+
+    /// Applies `ops` like `batch`, but only if every key in `expected` still
+    /// holds the given value (`None`: is absent) when they are written.
+    /// Returns whether they were.
+    async fn batch_if(&self, expected: Vec<ExpectedValue>, ops: Vec<BatchOp>) -> Result<bool>;
+}
+
+/// Placeholder backend used when the configured storage could not be opened,
+/// so the server still boots and reports the failure on every DB access.
+pub struct Unavailable(pub String);
+
+#[rocket::async_trait]
+impl Storage for Unavailable {
+    async fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Err(anyhow!("{}", self.0))
+    }
+
+    async fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
+        Err(anyhow!("{}", self.0))
+    }
+
+    async fn delete(&self, _key: &[u8]) -> Result<()> {
+        Err(anyhow!("{}", self.0))
+    }
+
+    async fn take(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Err(anyhow!("{}", self.0))
+    }
+
+    async fn scan(&self, _prefix: &[u8]) -> Result<Vec<KeyValue>> {
+        Err(anyhow!("{}", self.0))
+    }
+
+    async fn batch(&self, _ops: Vec<BatchOp>) -> Result<()> {
+        Err(anyhow!("{}", self.0))
+    }
+
+    async fn batch_if(&self, _expected: Vec<ExpectedValue>, _ops: Vec<BatchOp>) -> Result<bool> {
+        Err(anyhow!("{}", self.0))
+    }
+}
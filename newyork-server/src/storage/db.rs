@@ -1,77 +1,404 @@
+use std::time::Duration;
+
 use anyhow::{anyhow, Result};
-use rocksdb;
 use serde;
 
-pub enum DB {
-    Local(rocksdb::DB),
-    ConnError(String),
-}
+use super::backend::{BatchOp, Storage};
+use super::gc;
+use super::keys::{self, KeyClass};
 
-pub trait MPCStruct {
+pub trait MPCStruct: Sync {
     fn to_string(&self) -> String;
 
     fn require_customer_id(&self) -> bool {
         true
     }
+
+    /// Transient records are purged by the sweeper once this elapses after
+    /// their last write. `None` keeps the record until explicitly deleted.
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Column family of the record, transient records are protocol state.
+    fn class(&self) -> KeyClass {
+        match self.ttl() {
+            Some(_) => KeyClass::Protocol,
+            None => KeyClass::MasterKey,
+        }
+    }
 }
 
+/// Human readable name of a record for the logs.
 fn idify(user_id: &str, id: &str, name: &dyn MPCStruct) -> String {
-    format!("{}_{}_{}", user_id, id, name.to_string())
+    format!("{}/{}/{}", user_id, id, name.to_string())
+}
+
+fn record_key(user_id: &str, id: &str, name: &dyn MPCStruct) -> Vec<u8> {
+    keys::record(name.class(), user_id, id, &name.to_string())
+}
+
+/// Secondary index `user_id -> id` holding the wallet creation time, so a
+/// user's wallets are found with a prefix scan instead of a full DB scan.
+const WALLET_INDEX: &[u8] = b"wallets";
+
+pub fn wallet_index_key(user_id: &str, id: &str) -> Vec<u8> {
+    keys::encode(
+        KeyClass::Index,
+        &[WALLET_INDEX, user_id.as_bytes(), id.as_bytes()],
+    )
+}
+
+/// Index `session_id -> (user_id, id)` of parked signatures, so approvers
+/// find requests parked in other users' namespaces.
+const APPROVAL_INDEX: &[u8] = b"approvals";
+/// The same for requests still awaiting decisions only, so listing them does
+/// not scan every decision ever made.
+const PENDING_APPROVAL_INDEX: &[u8] = b"pending_approvals";
+
+fn approval_index_key(session_id: &str) -> Vec<u8> {
+    keys::encode(KeyClass::Index, &[APPROVAL_INDEX, session_id.as_bytes()])
+}
+
+fn pending_approval_index_key(session_id: &str) -> Vec<u8> {
+    keys::encode(
+        KeyClass::Index,
+        &[PENDING_APPROVAL_INDEX, session_id.as_bytes()],
+    )
 }
 
-pub fn insert<T>(db: &DB, user_id: &str, id: &str, name: &dyn MPCStruct, v: T) -> Result<()>
+pub async fn insert<T>(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    name: &dyn MPCStruct,
+    v: T,
+) -> Result<()>
 where
     T: serde::ser::Serialize,
 {
-    match db {
-        DB::Local(rocksdb_client) => {
-            let identifier = idify(user_id, id, name);
-            let v_string = serde_json::to_string(&v)?;
-            rocksdb_client.put(identifier.as_bytes(), v_string.as_bytes())?;
+    let identifier = idify(user_id, id, name);
+    let mut batch = Batch::new();
+    batch.insert(user_id, id, name, v)?;
+    db.batch(batch.ops).await?;
+    info!(
+        "Insert {} of ({}) into db SUCCESS",
+        name.to_string(),
+        identifier
+    );
+    Ok(())
+}
+
+pub async fn get<T>(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    name: &dyn MPCStruct,
+) -> Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let identifier = idify(user_id, id, name);
+
+    match db.get(&record_key(user_id, id, name)).await? {
+        Some(vec) => {
             info!(
-                "Insert {} of ({}) into db SUCCESS",
+                "Get {} of ({}) from db SUCCESS",
                 name.to_string(),
                 identifier
             );
-            Ok(())
+            Ok(serde_json::from_slice(&vec)?)
         }
-        DB::ConnError(msg) => {
-            return Err(anyhow!("{}", msg));
+        None => {
+            error!(
+                "Get {} of ({}) from db FAILED",
+                name.to_string(),
+                identifier
+            );
+            Ok(None)
         }
     }
 }
 
-pub fn get<T>(db: &DB, user_id: &str, id: &str, name: &dyn MPCStruct) -> Result<Option<T>>
+pub async fn exists(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    name: &dyn MPCStruct,
+) -> Result<bool> {
+    Ok(db.get(&record_key(user_id, id, name)).await?.is_some())
+}
+
+/// Reads and deletes a record in one go, for single-use secrets. Concurrent
+/// callers never both get the value.
+pub async fn take<T>(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    name: &dyn MPCStruct,
+) -> Result<Option<T>>
 where
     T: serde::de::DeserializeOwned,
 {
-    match db {
-        DB::Local(rocksdb_client) => {
-            let identifier = idify(user_id, id, name);
-
-            let db_option = rocksdb_client.get(identifier.as_bytes())?;
-            let vec_option: Option<Vec<u8>> = db_option.map(|v| v.to_vec());
-            match vec_option {
-                Some(vec) => {
-                    info!(
-                        "Get {} of ({}) from db SUCCESS",
-                        name.to_string(),
-                        identifier
-                    );
-                    Ok(serde_json::from_slice(&vec)?)
-                }
-                None => {
-                    error!(
-                        "Get {} of ({}) from db FAILED",
-                        name.to_string(),
-                        identifier
-                    );
-                    Ok(None)
-                }
-            }
+    let identifier = idify(user_id, id, name);
+
+    match db.take(&record_key(user_id, id, name)).await? {
+        Some(vec) => {
+            info!(
+                "Take {} of ({}) from db SUCCESS",
+                name.to_string(),
+                identifier
+            );
+            Ok(serde_json::from_slice(&vec)?)
         }
-        DB::ConnError(msg) => {
-            return Err(anyhow!("{}", msg));
+        None => {
+            error!(
+                "Take {} of ({}) from db FAILED",
+                name.to_string(),
+                identifier
+            );
+            Ok(None)
         }
     }
 }
+
+/// Like `take`, and writes `batch` in the same atomic step, so the record is
+/// never gone without what `batch` records about its use.
+pub async fn take_with<T>(
+    db: &dyn Storage,
+    user_id: &str,
+    id: &str,
+    name: &dyn MPCStruct,
+    batch: Batch,
+) -> Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let identifier = idify(user_id, id, name);
+    let key = record_key(user_id, id, name);
+    let mut consume = Batch::new();
+    consume.delete(user_id, id, name);
+    consume.ops.extend(batch.ops);
+    consume.names.extend(batch.names);
+
+    // Retried until the value read is the one removed, or it is gone.
+    loop {
+        let value = match db.get(&key).await? {
+            Some(value) => value,
+            None => {
+                error!(
+                    "Take {} of ({}) from db FAILED",
+                    name.to_string(),
+                    identifier
+                );
+                return Ok(None);
+            }
+        };
+        if db
+            .batch_if(
+                vec![(key.clone(), Some(value.clone()))],
+                consume.ops.clone(),
+            )
+            .await?
+        {
+            info!(
+                "Take {} with batch [{}] from db SUCCESS",
+                identifier,
+                consume.names.join(", ")
+            );
+            return Ok(Some(serde_json::from_slice(&value)?));
+        }
+    }
+}
+
+/// Records produced by a single protocol step, persisted together by
+/// `insert_many` so a failure never leaves half of a step in the DB.
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<BatchOp>,
+    names: Vec<String>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    pub fn insert<T>(&mut self, user_id: &str, id: &str, name: &dyn MPCStruct, v: T) -> Result<()>
+    where
+        T: serde::ser::Serialize,
+    {
+        let key = record_key(user_id, id, name);
+        let v_string = serde_json::to_string(&v)?;
+        self.ops.push(BatchOp::Put {
+            key: key.clone(),
+            value: v_string.into_bytes(),
+        });
+        self.set_ttl(&key, name.ttl());
+        self.names.push(idify(user_id, id, name));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, user_id: &str, id: &str, name: &dyn MPCStruct) {
+        let key = record_key(user_id, id, name);
+        self.ops.push(BatchOp::Delete { key: key.clone() });
+        self.set_ttl(&key, None);
+        self.names.push(format!("-{}", idify(user_id, id, name)));
+    }
+
+    /// Adds wallet `id` to the index of `user_id`. Wallets whose keygen may
+    /// still be abandoned get the TTL of their protocol state.
+    pub fn index_wallet(
+        &mut self,
+        user_id: &str,
+        id: &str,
+        created_at: u64,
+        ttl: Option<Duration>,
+    ) {
+        let key = wallet_index_key(user_id, id);
+        self.ops.push(BatchOp::Put {
+            key: key.clone(),
+            value: created_at.to_string().into_bytes(),
+        });
+        self.set_ttl(&key, ttl);
+        self.names.push(format!("wallets/{}/{}", user_id, id));
+    }
+
+    /// Indexes the approval `session_id`, until `ttl` elapses if given.
+    pub fn index_approval(
+        &mut self,
+        session_id: &str,
+        user_id: &str,
+        id: &str,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let key = approval_index_key(session_id);
+        self.ops.push(BatchOp::Put {
+            key: key.clone(),
+            value: serde_json::to_vec(&(user_id, id))?,
+        });
+        self.set_ttl(&key, ttl);
+        self.names.push(format!("approvals/{}", session_id));
+        Ok(())
+    }
+
+    /// Lists the approval `session_id` as pending until `ttl` elapses or
+    /// `unindex_pending_approval` drops it.
+    pub fn index_pending_approval(
+        &mut self,
+        session_id: &str,
+        user_id: &str,
+        id: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        let key = pending_approval_index_key(session_id);
+        self.ops.push(BatchOp::Put {
+            key: key.clone(),
+            value: serde_json::to_vec(&(user_id, id))?,
+        });
+        self.set_ttl(&key, Some(ttl));
+        self.names.push(format!("pending_approvals/{}", session_id));
+        Ok(())
+    }
+
+    pub fn unindex_pending_approval(&mut self, session_id: &str) {
+        let key = pending_approval_index_key(session_id);
+        self.ops.push(BatchOp::Delete { key: key.clone() });
+        self.set_ttl(&key, None);
+        self.names
+            .push(format!("-pending_approvals/{}", session_id));
+    }
+
+    pub fn expire_wallet_index(&mut self, user_id: &str, id: &str, ttl: Option<Duration>) {
+        self.set_ttl(&wallet_index_key(user_id, id), ttl);
+    }
+
+    /// Overrides the TTL of a record inserted earlier in this batch, e.g. for
+    /// records that only become permanent once a protocol completes.
+    pub fn expire(&mut self, user_id: &str, id: &str, name: &dyn MPCStruct, ttl: Option<Duration>) {
+        self.set_ttl(&record_key(user_id, id, name), ttl);
+    }
+
+    /// Keeps a record until unix time `at` instead of its TTL, for protocol
+    /// state that has to outlive a deadline set after it was written.
+    pub fn expire_at(&mut self, user_id: &str, id: &str, name: &dyn MPCStruct, at: u64) {
+        self.ops.push(BatchOp::Put {
+            key: gc::expiry_key(&record_key(user_id, id, name)),
+            value: at.to_string().into_bytes(),
+        });
+    }
+
+    fn set_ttl(&mut self, key: &[u8], ttl: Option<Duration>) {
+        let key = gc::expiry_key(key);
+        self.ops.push(match ttl {
+            Some(ttl) => BatchOp::Put {
+                key,
+                value: gc::expires_at(ttl),
+            },
+            None => BatchOp::Delete { key },
+        });
+    }
+}
+
+pub async fn insert_many(db: &dyn Storage, batch: Batch) -> Result<()> {
+    db.batch(batch.ops).await?;
+    info!("Write batch [{}] into db SUCCESS", batch.names.join(", "));
+    Ok(())
+}
+
+/// Wallet ids of `user_id` with their creation time.
+pub async fn wallets(db: &dyn Storage, user_id: &str) -> Result<Vec<(String, u64)>> {
+    let prefix = keys::encode(KeyClass::Index, &[WALLET_INDEX, user_id.as_bytes()]);
+    db.scan(&prefix)
+        .await?
+        .into_iter()
+        .map(|(key, value)| -> Result<(String, u64)> {
+            let id = match keys::decode(&key)
+                .as_ref()
+                .map(|(_, parts)| parts.as_slice())
+            {
+                Some([_, _, id]) => String::from_utf8(id.to_vec())?,
+                _ => return Err(anyhow!("Malformed wallet index key")),
+            };
+            Ok((id, String::from_utf8(value)?.parse()?))
+        })
+        .collect()
+}
+
+pub async fn wallet_created_at(db: &dyn Storage, user_id: &str, id: &str) -> Result<Option<u64>> {
+    match db.get(&wallet_index_key(user_id, id)).await? {
+        Some(value) => Ok(Some(String::from_utf8(value)?.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Owner and wallet of the signature parked under `session_id`.
+pub async fn approval_owner(
+    db: &dyn Storage,
+    session_id: &str,
+) -> Result<Option<(String, String)>> {
+    match db.get(&approval_index_key(session_id)).await? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Every approval still awaiting decisions as `(session_id, user_id, id)`.
+pub async fn pending_approvals(db: &dyn Storage) -> Result<Vec<(String, String, String)>> {
+    let prefix = keys::encode(KeyClass::Index, &[PENDING_APPROVAL_INDEX]);
+    db.scan(&prefix)
+        .await?
+        .into_iter()
+        .map(|(key, value)| -> Result<(String, String, String)> {
+            let session_id = match keys::decode(&key)
+                .as_ref()
+                .map(|(_, parts)| parts.as_slice())
+            {
+                Some([_, session_id]) => String::from_utf8(session_id.to_vec())?,
+                _ => return Err(anyhow!("Malformed approval index key")),
+            };
+            let (user_id, id): (String, String) = serde_json::from_slice(&value)?;
+            Ok((session_id, user_id, id))
+        })
+        .collect()
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Result};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::sync::Mutex;
+
+use super::backend::{BatchOp, ExpectedValue, KeyValue, Storage};
+
+const MAGIC: &[u8; 4] = b"NYE1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + TAG_LEN;
+
+#[derive(Deserialize)]
+struct KeyringFile {
+    active: String,
+    keys: HashMap<String, String>,
+}
+
+/// Master keys used to wrap per-record data keys. Only `active` is used for
+/// new writes; the others are kept so older records can still be unwrapped
+/// until the rewrap job has moved them over.
+pub struct MasterKeyring {
+    active: String,
+    keys: HashMap<String, [u8; KEY_LEN]>,
+}
+
+impl MasterKeyring {
+    /// Keyring file format: `{ "active": "<kid>", "keys": { "<kid>": "<hex>" } }`.
+    pub fn from_file(path: &str) -> Result<MasterKeyring> {
+        let file: KeyringFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let mut keys = HashMap::new();
+        for (kid, hex_key) in file.keys.iter() {
+            keys.insert(kid.clone(), parse_key(kid, hex_key)?);
+        }
+        MasterKeyring::new(file.active, keys)
+    }
+
+    pub fn from_hex(kid: &str, hex_key: &str) -> Result<MasterKeyring> {
+        let mut keys = HashMap::new();
+        keys.insert(kid.to_string(), parse_key(kid, hex_key)?);
+        MasterKeyring::new(kid.to_string(), keys)
+    }
+
+    fn new(active: String, keys: HashMap<String, [u8; KEY_LEN]>) -> Result<MasterKeyring> {
+        if !keys.contains_key(&active) {
+            return Err(anyhow!(
+                "Active master key {} is not in the keyring",
+                active
+            ));
+        }
+        if active.is_empty() || active.len() > u8::MAX as usize {
+            return Err(anyhow!("Master key id must be 1-255 bytes long"));
+        }
+        Ok(MasterKeyring { active, keys })
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.active
+    }
+
+    fn key(&self, kid: &str) -> Result<&[u8; KEY_LEN]> {
+        self.keys
+            .get(kid)
+            .ok_or_else(|| anyhow!("Unknown master key id {}", kid))
+    }
+}
+
+fn parse_key(kid: &str, hex_key: &str) -> Result<[u8; KEY_LEN]> {
+    let bytes = hex::decode(hex_key.trim())?;
+    if bytes.len() != KEY_LEN {
+        return Err(anyhow!("Master key {} must be {} bytes", kid, KEY_LEN));
+    }
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn seal(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    Aes256Gcm::new(GenericArray::from_slice(key))
+        .encrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| anyhow!("Failed to encrypt record"))
+}
+
+fn open(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    Aes256Gcm::new(GenericArray::from_slice(key))
+        .decrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| anyhow!("Failed to decrypt record, wrong master key or tampered value"))
+}
+
+/// Parsed view of a stored value:
+/// `MAGIC | kid_len | kid | dk_nonce | wrapped_dk | nonce | ciphertext`.
+struct Envelope<'a> {
+    kid: &'a str,
+    dk_nonce: &'a [u8],
+    wrapped_dk: &'a [u8],
+    body: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(value: &'a [u8]) -> Result<Option<Envelope<'a>>> {
+        if !value.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        let rest = &value[MAGIC.len()..];
+        let kid_len = *rest.first().ok_or_else(|| anyhow!("Truncated envelope"))? as usize;
+        let rest = &rest[1..];
+        if rest.len() < kid_len + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN {
+            return Err(anyhow!("Truncated envelope"));
+        }
+        let (kid, rest) = rest.split_at(kid_len);
+        let (dk_nonce, rest) = rest.split_at(NONCE_LEN);
+        let (wrapped_dk, body) = rest.split_at(WRAPPED_KEY_LEN);
+        Ok(Some(Envelope {
+            kid: std::str::from_utf8(kid)?,
+            dk_nonce,
+            wrapped_dk,
+            body,
+        }))
+    }
+
+    fn encode(kid: &str, dk_nonce: &[u8], wrapped_dk: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            MAGIC.len() + 1 + kid.len() + dk_nonce.len() + wrapped_dk.len() + body.len(),
+        );
+        out.extend_from_slice(MAGIC);
+        out.push(kid.len() as u8);
+        out.extend_from_slice(kid.as_bytes());
+        out.extend_from_slice(dk_nonce);
+        out.extend_from_slice(wrapped_dk);
+        out.extend_from_slice(body);
+        out
+    }
+}
+
+/// Envelope encryption on top of any backend: every value is sealed with a
+/// fresh AES-256-GCM data key, which is itself wrapped by the active master
+/// key. The storage key is bound as AAD so records cannot be swapped around.
+/// Values written before encryption was enabled are read back as plaintext
+/// and picked up by `rewrap_all`.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keyring: MasterKeyring,
+    write_lock: Mutex<()>,
+    /// Set while a DB written before encryption was enabled is being sealed.
+    accept_plaintext: AtomicBool,
+    /// Records left out of a scan because they could not be decrypted.
+    refused: AtomicU64,
+}
+
+impl EncryptedStorage {
+    /// Only sealed values are accepted, anything else is refused as tampered.
+    pub fn new(inner: Arc<dyn Storage>, keyring: MasterKeyring) -> EncryptedStorage {
+        EncryptedStorage {
+            inner,
+            keyring,
+            write_lock: Mutex::new(()),
+            accept_plaintext: AtomicBool::new(false),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    /// Also reads values stored before encryption was enabled, until
+    /// `rewrap_all` has sealed all of them.
+    pub fn migrating_plaintext(self) -> EncryptedStorage {
+        self.accept_plaintext.store(true, Ordering::SeqCst);
+        self
+    }
+
+    pub fn accepts_plaintext(&self) -> bool {
+        self.accept_plaintext.load(Ordering::SeqCst)
+    }
+
+    /// How many records scans have left out so far, as unencrypted or tampered.
+    pub fn refused_records(&self) -> u64 {
+        self.refused.load(Ordering::SeqCst)
+    }
+
+    fn wrap_data_key(&self, dk: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let kid = self.keyring.active_id();
+        let dk_nonce = random_bytes::<NONCE_LEN>();
+        let wrapped = seal(self.keyring.key(kid)?, &dk_nonce, dk, kid.as_bytes())?;
+        Ok((dk_nonce.to_vec(), wrapped))
+    }
+
+    fn unwrap_data_key(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        open(
+            self.keyring.key(envelope.kid)?,
+            envelope.dk_nonce,
+            envelope.wrapped_dk,
+            envelope.kid.as_bytes(),
+        )
+    }
+
+    fn encrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let dk = random_bytes::<KEY_LEN>();
+        let nonce = random_bytes::<NONCE_LEN>();
+        let mut body = nonce.to_vec();
+        body.extend(seal(&dk, &nonce, value, key)?);
+        let (dk_nonce, wrapped_dk) = self.wrap_data_key(&dk)?;
+        Ok(Envelope::encode(
+            self.keyring.active_id(),
+            &dk_nonce,
+            &wrapped_dk,
+            &body,
+        ))
+    }
+
+    fn decrypt(&self, key: &[u8], value: Vec<u8>) -> Result<Vec<u8>> {
+        if !value.starts_with(MAGIC) {
+            if self.accepts_plaintext() {
+                return Ok(value);
+            }
+            return Err(anyhow!(
+                "Refusing unencrypted record {}, set ENCRYPTION_MIGRATE_PLAINTEXT=true to seal a DB written before encryption was enabled",
+                hex::encode(key)
+            ));
+        }
+        let envelope = Envelope::parse(&value)?.ok_or_else(|| anyhow!("Malformed envelope"))?;
+        let dk = self.unwrap_data_key(&envelope)?;
+        let (nonce, ciphertext) = envelope.body.split_at(NONCE_LEN);
+        open(&dk, nonce, ciphertext, key)
+    }
+
+    fn seal_ops(&self, ops: Vec<BatchOp>) -> Result<Vec<BatchOp>> {
+        ops.into_iter()
+            .map(|op| -> Result<BatchOp> {
+                match op {
+                    BatchOp::Put { key, value } => {
+                        let value = self.encrypt(&key, &value)?;
+                        Ok(BatchOp::Put { key, value })
+                    }
+                    delete => Ok(delete),
+                }
+            })
+            .collect()
+    }
+
+    /// Re-wraps one stored value under the active master key. Only the data
+    /// key is re-encrypted, legacy plaintext values get sealed from scratch
+    /// while migrating.
+    fn rewrap(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        match Envelope::parse(value)? {
+            Some(envelope) if envelope.kid == self.keyring.active_id() => Ok(None),
+            Some(envelope) => {
+                let dk = self.unwrap_data_key(&envelope)?;
+                let (dk_nonce, wrapped_dk) = self.wrap_data_key(&dk)?;
+                Ok(Some(Envelope::encode(
+                    self.keyring.active_id(),
+                    &dk_nonce,
+                    &wrapped_dk,
+                    envelope.body,
+                )))
+            }
+            None if self.accepts_plaintext() => Ok(Some(self.encrypt(key, value)?)),
+            // Sealing it would vouch for a value nobody encrypted.
+            None => {
+                error!("Leaving unencrypted record {} unsealed", hex::encode(key));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Moves every record that is not yet under the active master key over to
+    /// it. Returns how many records were rewritten. Once a migrating pass got
+    /// through, unencrypted values are refused from then on.
+    pub async fn rewrap_all(&self) -> Result<usize> {
+        let mut rewrapped = 0;
+        for (key, value) in self.inner.scan(&[]).await? {
+            let new_value = match self.rewrap(&key, &value)? {
+                Some(new_value) => new_value,
+                None => continue,
+            };
+            let _guard = self.write_lock.lock().await;
+            // Skip records that changed since the scan, the writer already
+            // sealed them under the active key.
+            if self.inner.get(&key).await?.as_deref() != Some(value.as_slice()) {
+                continue;
+            }
+            self.inner.put(&key, &new_value).await?;
+            rewrapped += 1;
+        }
+        if self.accept_plaintext.swap(false, Ordering::SeqCst) {
+            info!("Every record is sealed, unencrypted values are refused from now on");
+        }
+        Ok(rewrapped)
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for EncryptedStorage {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(key).await? {
+            Some(value) => Ok(Some(self.decrypt(key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let sealed = self.encrypt(key, value)?;
+        let _guard = self.write_lock.lock().await;
+        self.inner.put(key, &sealed).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        self.inner.delete(key).await
+    }
+
+    async fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _guard = self.write_lock.lock().await;
+        match self.inner.take(key).await? {
+            Some(value) => Ok(Some(self.decrypt(key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// A record that does not decrypt is left out and counted, one planted
+    /// or corrupted value must not hide every other record under `prefix`.
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>> {
+        let mut entries = Vec::new();
+        for (key, value) in self.inner.scan(prefix).await? {
+            match self.decrypt(&key, value) {
+                Ok(value) => entries.push((key, value)),
+                Err(e) => {
+                    self.refused.fetch_add(1, Ordering::SeqCst);
+                    error!("Skipping record {} in scan: {}", hex::encode(&key), e);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let ops = self.seal_ops(ops)?;
+        let _guard = self.write_lock.lock().await;
+        self.inner.batch(ops).await
+    }
+
+    /// Sealed values never compare equal, the expected ones are checked in
+    /// plaintext while no other write can get in.
+    async fn batch_if(&self, expected: Vec<ExpectedValue>, ops: Vec<BatchOp>) -> Result<bool> {
+        let ops = self.seal_ops(ops)?;
+        let _guard = self.write_lock.lock().await;
+        for (key, value) in expected {
+            let current = match self.inner.get(&key).await? {
+                Some(sealed) => Some(self.decrypt(&key, sealed)?),
+                None => None,
+            };
+            if current != value {
+                return Ok(false);
+            }
+        }
+        self.inner.batch(ops).await?;
+        Ok(true)
+    }
+}
+
+/// Runs `rewrap_all` right away and then every `interval`
+/// (`KEY_REWRAP_INTERVAL_SECS`).
+pub async fn rewrap_task(storage: Arc<EncryptedStorage>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match storage.rewrap_all().await {
+            Ok(0) => {}
+            Ok(count) => info!(
+                "Re-wrapped {} records under master key {}",
+                count,
+                storage.keyring.active_id()
+            ),
+            Err(e) => error!("Master key rewrap failed: {:#?}", e),
+        }
+    }
+}
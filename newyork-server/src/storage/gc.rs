@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+use super::backend::{BatchOp, Storage};
+use super::keys::{self, KeyClass};
+
+/// Keygen, chain code, rotation and signing intermediates.
+pub const PROTOCOL_STATE_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a spent ephemeral key is remembered to flag replays explicitly.
+pub const SPENT_EPHEMERAL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Expiry entries live in the index column family as `expiry/<key>`,
+/// holding the unix time after which both may be purged.
+const EXPIRY: &[u8] = b"expiry";
+
+fn expiry_prefix() -> Vec<u8> {
+    keys::encode(KeyClass::Index, &[EXPIRY])
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn expiry_key(key: &[u8]) -> Vec<u8> {
+    keys::encode(KeyClass::Index, &[EXPIRY, key])
+}
+
+pub fn expires_at(ttl: Duration) -> Vec<u8> {
+    (now() + ttl.as_secs()).to_string().into_bytes()
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Sweep {
+    pub purged: usize,
+    /// Records still carrying a TTL after the sweep.
+    pub tracked: usize,
+}
+
+/// Deletes every record whose TTL ran out before `now`, together with its
+/// expiry entry. Each record is only deleted while its expiry entry still
+/// holds what the scan read: a record written again since then got a new
+/// one and is kept.
+pub async fn sweep(db: &dyn Storage, now: u64) -> Result<Sweep> {
+    let mut sweep = Sweep {
+        purged: 0,
+        tracked: 0,
+    };
+    for (key, value) in db.scan(&expiry_prefix()).await? {
+        let expires_at: u64 = String::from_utf8(value.clone())?.parse()?;
+        if expires_at > now {
+            sweep.tracked += 1;
+            continue;
+        }
+        let record = match keys::decode(&key)
+            .as_ref()
+            .map(|(_, parts)| parts.as_slice())
+        {
+            Some([_, record]) => record.to_vec(),
+            _ => return Err(anyhow!("Malformed expiry key")),
+        };
+        let ops = vec![
+            BatchOp::Delete { key: record },
+            BatchOp::Delete { key: key.clone() },
+        ];
+        if db.batch_if(vec![(key, Some(value))], ops).await? {
+            sweep.purged += 1;
+        } else {
+            sweep.tracked += 1;
+        }
+    }
+    Ok(sweep)
+}
+
+#[derive(Default)]
+pub struct GcStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    purged_total: AtomicU64,
+    last_purged: AtomicU64,
+    last_run_at: AtomicU64,
+    tracked: AtomicU64,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct GcReport {
+    pub runs: u64,
+    pub failures: u64,
+    pub purged_total: u64,
+    pub last_purged: u64,
+    pub last_run_at: u64,
+    /// Records carrying a TTL as of the last successful run.
+    pub tracked: u64,
+}
+
+impl GcStats {
+    pub fn report(&self) -> GcReport {
+        GcReport {
+            runs: self.runs.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            purged_total: self.purged_total.load(Ordering::Relaxed),
+            last_purged: self.last_purged.load(Ordering::Relaxed),
+            last_run_at: self.last_run_at.load(Ordering::Relaxed),
+            tracked: self.tracked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sweeps expired records every `interval` (`GC_INTERVAL_SECS`) and records
+/// each run in `stats` for `/monitoring/gc`.
+pub async fn sweeper_task(db: Arc<dyn Storage>, stats: Arc<GcStats>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let run_at = now();
+        stats.runs.fetch_add(1, Ordering::Relaxed);
+        stats.last_run_at.store(run_at, Ordering::Relaxed);
+        match sweep(db.as_ref(), run_at).await {
+            Ok(Sweep { purged, tracked }) => {
+                if purged > 0 {
+                    info!("Purged {} expired protocol records", purged);
+                }
+                stats.last_purged.store(purged as u64, Ordering::Relaxed);
+                stats
+                    .purged_total
+                    .fetch_add(purged as u64, Ordering::Relaxed);
+                stats.tracked.store(tracked as u64, Ordering::Relaxed);
+            }
+            Err(e) => {
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                error!("Expired state sweep failed: {:#?}", e);
+            }
+        }
+    }
+}
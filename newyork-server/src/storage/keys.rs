@@ -0,0 +1,91 @@
+/// Version of the key layout below, stored under `schema_version_key()`.
+/// Version 1 is the legacy `"{user_id}_{id}_{name}"` string layout.
+pub const SCHEMA_VERSION: u32 = 2;
+
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Leading byte of every key, selecting the RocksDB column family it lives in.
+/// Legacy string keys never start with one of these bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyClass {
+    Meta = 1,
+    /// Long-lived wallet records: master keys, HD positions, children.
+    MasterKey = 2,
+    /// Intermediate keygen, signing and rotation state.
+    Protocol = 3,
+    /// Secondary indexes and expiry entries.
+    Index = 4,
+    /// The hash-chained audit log, never expired.
+    Audit = 5,
+    /// Co-signing policies and the spend ledgers of their limits.
+    Policy = 6,
+}
+
+impl KeyClass {
+    pub const ALL: [KeyClass; 6] = [
+        KeyClass::Meta,
+        KeyClass::MasterKey,
+        KeyClass::Protocol,
+        KeyClass::Index,
+        KeyClass::Audit,
+        KeyClass::Policy,
+    ];
+
+    pub fn of(key: &[u8]) -> Option<KeyClass> {
+        KeyClass::ALL
+            .iter()
+            .copied()
+            .find(|class| key.first() == Some(&(*class as u8)))
+    }
+
+    pub fn column_family(&self) -> &'static str {
+        match self {
+            KeyClass::Meta => DEFAULT_COLUMN_FAMILY,
+            KeyClass::MasterKey => "master_keys",
+            KeyClass::Protocol => "protocol_state",
+            KeyClass::Index => "indexes",
+            KeyClass::Audit => "audit_log",
+            KeyClass::Policy => "policies",
+        }
+    }
+}
+
+/// Column family of `key`, legacy keys all live in the default one.
+pub fn column_family(key: &[u8]) -> &'static str {
+    match KeyClass::of(key) {
+        Some(class) => class.column_family(),
+        None => DEFAULT_COLUMN_FAMILY,
+    }
+}
+
+/// `class | (len u32 BE | part)*`. Every part is length-prefixed, so no
+/// user or wallet id can run into the next part, and the encoding of some
+/// leading parts is a prefix of the full key for scans.
+pub fn encode(class: KeyClass, parts: &[&[u8]]) -> Vec<u8> {
+    let mut key = vec![class as u8];
+    for part in parts {
+        key.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        key.extend_from_slice(part);
+    }
+    key
+}
+
+pub fn decode(key: &[u8]) -> Option<(KeyClass, Vec<&[u8]>)> {
+    let class = KeyClass::of(key)?;
+    let mut rest = &key[1..];
+    let mut parts = vec![];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        parts.push(rest.get(4..4 + len)?);
+        rest = &rest[4 + len..];
+    }
+    Some((class, parts))
+}
+
+pub fn record(class: KeyClass, user_id: &str, id: &str, name: &str) -> Vec<u8> {
+    encode(class, &[user_id.as_bytes(), id.as_bytes(), name.as_bytes()])
+}
+
+pub fn schema_version_key() -> Vec<u8> {
+    encode(KeyClass::Meta, &[b"schema_version"])
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+
+use super::backend::{BatchOp, ExpectedValue, KeyValue, Storage};
+
+/// Process-local storage, used by tests and throwaway dev servers.
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn entries(&self) -> Result<std::sync::MutexGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>>> {
+        self.entries
+            .lock()
+            .map_err(|_| anyhow!("In-memory storage lock poisoned"))
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for MemoryStorage {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries()?.get(key).cloned())
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.entries()?.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.entries()?.remove(key);
+        Ok(())
+    }
+
+    async fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries()?.remove(key))
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>> {
+        Ok(self
+            .entries()?
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    async fn batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        apply(&mut self.entries()?, ops);
+        Ok(())
+    }
+
+    async fn batch_if(&self, expected: Vec<ExpectedValue>, ops: Vec<BatchOp>) -> Result<bool> {
+        let mut entries = self.entries()?;
+        if expected
+            .iter()
+            .any(|(key, value)| entries.get(key) != value.as_ref())
+        {
+            return Ok(false);
+        }
+        apply(&mut entries, ops);
+        Ok(true)
+    }
+}
+
+fn apply(entries: &mut BTreeMap<Vec<u8>, Vec<u8>>, ops: Vec<BatchOp>) {
+    for op in ops {
+        match op {
+            BatchOp::Put { key, value } => {
+                entries.insert(key, value);
+            }
+            BatchOp::Delete { key } => {
+                entries.remove(&key);
+            }
+        }
+    }
+}
@@ -1 +1,8 @@
+pub mod backend;
 pub mod db;
+pub mod encrypted;
+pub mod gc;
+pub mod keys;
+pub mod memory;
+pub mod rocks;
+pub mod schema;
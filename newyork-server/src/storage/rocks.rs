@@ -0,0 +1,140 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch};
+
+use super::backend::{BatchOp, ExpectedValue, KeyValue, Storage};
+use super::keys::{self, KeyClass};
+
+/// Keys are spread over one column family per `KeyClass`, routed by their
+/// leading byte.
+pub struct RocksDbStorage {
+    db: rocksdb::DB,
+    /// Held by every write, so `take` and `batch_if` read and write without
+    /// another write in between.
+    write_lock: Mutex<()>,
+}
+
+impl RocksDbStorage {
+    pub fn open(path: &str) -> Result<RocksDbStorage> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let column_families: Vec<&str> = KeyClass::ALL
+            .iter()
+            .map(|class| class.column_family())
+            .collect();
+        let db = rocksdb::DB::open_cf(&opts, path, column_families)?;
+        Ok(RocksDbStorage {
+            db,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Opens an existing database without taking its lock, e.g. for the
+    /// offline audit verifier next to a running server. Writes fail.
+    pub fn open_read_only(path: &str) -> Result<RocksDbStorage> {
+        let column_families: Vec<&str> = KeyClass::ALL
+            .iter()
+            .map(|class| class.column_family())
+            .collect();
+        let db =
+            rocksdb::DB::open_cf_for_read_only(&Options::default(), path, column_families, false)?;
+        Ok(RocksDbStorage {
+            db,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    fn write_guard(&self) -> Result<std::sync::MutexGuard<'_, ()>> {
+        self.write_lock
+            .lock()
+            .map_err(|_| anyhow!("RocksDB write lock poisoned"))
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                BatchOp::Put { key, value } => batch.put_cf(self.cf(&key)?, key, value),
+                BatchOp::Delete { key } => batch.delete_cf(self.cf(&key)?, key),
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn cf(&self, key: &[u8]) -> Result<&ColumnFamily> {
+        let name = keys::column_family(key);
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow!("Missing RocksDB column family {}", name))
+    }
+
+    fn scan_cf(&self, cf: &ColumnFamily, prefix: &[u8]) -> Vec<KeyValue> {
+        self.db
+            .iterator_cf(cf, IteratorMode::From(prefix, Direction::Forward))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect()
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for RocksDbStorage {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get_cf(self.cf(key)?, key)?)
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let _guard = self.write_guard()?;
+        self.db.put_cf(self.cf(key)?, key, value)?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        let _guard = self.write_guard()?;
+        self.db.delete_cf(self.cf(key)?, key)?;
+        Ok(())
+    }
+
+    async fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _guard = self.write_guard()?;
+        let cf = self.cf(key)?;
+        let value = self.db.get_cf(cf, key)?;
+        if value.is_some() {
+            self.db.delete_cf(cf, key)?;
+        }
+        Ok(value)
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<KeyValue>> {
+        if !prefix.is_empty() {
+            return Ok(self.scan_cf(self.cf(prefix)?, prefix));
+        }
+        let mut entries = self.scan_cf(self.cf(&[])?, prefix);
+        for class in KeyClass::ALL.iter() {
+            if class.column_family() != keys::DEFAULT_COLUMN_FAMILY {
+                entries.extend(self.scan_cf(self.cf(&[*class as u8])?, prefix));
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    async fn batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let _guard = self.write_guard()?;
+        self.write_batch(ops)
+    }
+
+    async fn batch_if(&self, expected: Vec<ExpectedValue>, ops: Vec<BatchOp>) -> Result<bool> {
+        let _guard = self.write_guard()?;
+        for (key, value) in expected.iter() {
+            if self.db.get_cf(self.cf(key)?, key)? != *value {
+                return Ok(false);
+            }
+        }
+        self.write_batch(ops)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
+
+use super::backend::{BatchOp, Storage};
+use super::db::{self, MPCStruct};
+use super::keys::{self, KeyClass, SCHEMA_VERSION};
+
+const UUID_LEN: usize = 36;
+
+pub async fn version(db: &dyn Storage) -> Result<u32> {
+    match db.get(&keys::schema_version_key()).await? {
+        Some(value) => Ok(String::from_utf8(value)?.parse()?),
+        None => Ok(1),
+    }
+}
+
+/// Splits a legacy key into user id, wallet id and record name. Wallet ids
+/// are uuids, or `{id}_{session_id}` for signing sessions, so user ids with
+/// underscores are still recovered. Old builds wrote some names with a
+/// trailing space.
+fn parse_legacy_key(key: &str) -> Option<(&str, &str, &str)> {
+    let (rest, name) = key.rsplit_once('_')?;
+    for id_len in [2 * UUID_LEN + 1, UUID_LEN] {
+        let split = match rest.len().checked_sub(id_len + 1) {
+            Some(split) => split,
+            None => continue,
+        };
+        let (user_id, id) = match (rest.get(..split), rest.get(split + 1..)) {
+            (Some(user_id), Some(id)) if rest.as_bytes()[split] == b'_' => (user_id, id),
+            _ => continue,
+        };
+        if id.split('_').all(|part| Uuid::parse_str(part).is_ok()) {
+            return Some((user_id, id, name.trim_end()));
+        }
+    }
+    None
+}
+
+/// Rewrites a version 1 DB into the length-prefixed, column family keyed
+/// layout in a single batch and stamps `SCHEMA_VERSION`. `class_of` maps
+/// record names to their key class; records it does not know are left in
+/// place. Wallets holding a `master_key` record get indexed. Returns how many
+/// records were moved.
+pub async fn migrate(
+    db: &dyn Storage,
+    class_of: &dyn Fn(&str) -> Option<KeyClass>,
+    master_key: &dyn MPCStruct,
+) -> Result<usize> {
+    let current = version(db).await?;
+    if current == SCHEMA_VERSION {
+        return Ok(0);
+    }
+    if current > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "DB key schema v{} is newer than this server (v{})",
+            current,
+            SCHEMA_VERSION
+        ));
+    }
+
+    let mut ops = vec![];
+    let mut moved = 0;
+    let mut wallets = vec![];
+    for (key, value) in db.scan(&[]).await? {
+        if KeyClass::of(&key).is_some() {
+            continue;
+        }
+
+        let parsed = std::str::from_utf8(&key)
+            .ok()
+            .and_then(parse_legacy_key)
+            .and_then(|(user_id, id, name)| Some((user_id, id, name, class_of(name)?)));
+        let (user_id, id, name, class) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                warn!(
+                    "Leaving unknown legacy key {} in place",
+                    String::from_utf8_lossy(&key)
+                );
+                continue;
+            }
+        };
+        let new_key = keys::record(class, user_id, id, name);
+        if name == master_key.to_string() {
+            wallets.push((user_id.to_string(), id.to_string()));
+        }
+        ops.push(BatchOp::Put {
+            key: new_key,
+            value,
+        });
+        ops.push(BatchOp::Delete { key });
+        moved += 1;
+    }
+
+    // Wallets from before the index existed, their creation time is unknown.
+    for (user_id, id) in wallets {
+        ops.push(BatchOp::Put {
+            key: db::wallet_index_key(&user_id, &id),
+            value: b"0".to_vec(),
+        });
+    }
+
+    ops.push(BatchOp::Put {
+        key: keys::schema_version_key(),
+        value: SCHEMA_VERSION.to_string().into_bytes(),
+    });
+    db.batch(ops).await?;
+    Ok(moved)
+}
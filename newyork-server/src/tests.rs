@@ -4,26 +4,60 @@ mod test_suites {
     use crate::utils::settings::get_app_env;
     use crate::utils::settings::TestEnv;
 
+    use super::super::approval::{self, ApprovalStatus, SignTarget};
+    use super::super::audit::{self, AuditEntry, AuditEvent, AuditOp, ChainError, PolicyOutcome};
+    use super::super::auth::guards::ValidatedUser;
+    use super::super::auth::jwt::{AuthMode, Claims, JwksSource, JwksUnavailable, JwtVerifier};
+    use super::super::pdl;
+    use super::super::policy::{
+        self, ApprovalRule, Authorization, Policy, PolicyStruct, Rejection, RollingLimit, Spend,
+        TimeWindow,
+    };
+    use super::super::rotation;
     use super::super::routes::ecdsa;
+    use super::super::routes::eth;
     use super::super::server;
+    use super::super::session::{self, SessionState, Step};
+    use super::super::storage::backend::{BatchOp, ExpectedValue, KeyValue, Storage};
+    use super::super::storage::db;
+    use super::super::storage::encrypted::{EncryptedStorage, MasterKeyring};
+    use super::super::storage::gc;
+    use super::super::storage::keys;
+    use super::super::storage::memory::MemoryStorage;
+    use super::super::storage::schema;
+    use super::super::utils::address;
+    use super::super::utils::amount::{self, AmountError, EthAmount, EthUnit};
+    use super::super::utils::errors::HttpError;
+    use super::super::utils::fees::{self, FeeSpeed, FeeStrategy};
+    use super::super::utils::locks::KeyedLocks;
+    use super::super::utils::signature;
+    use super::super::utils::transaction::{self, UnsignedTx};
+    use super::super::AppConfig;
     use rocket;
     use rocket::http::ContentType;
     use rocket::http::Header;
     use rocket::http::Status;
+    use rocket::local::asynchronous::Client as AsyncClient;
     use rocket::local::blocking::Client;
     use serde_json;
     use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
     use std::time::Instant;
     use zk_paillier::zkproofs::SALT_STRING;
 
-    use curv::arithmetic::traits::Converter;
+    use curv::arithmetic::traits::{Converter, Modulo};
     use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::*;
-    use curv::elliptic::curves::secp256_k1::GE;
+    use curv::elliptic::curves::secp256_k1::{Secp256k1Scalar, FE, GE};
+    use curv::elliptic::curves::traits::{ECPoint, ECScalar};
     use curv::BigInt;
     use floating_duration::TimeFormat;
+    use jsonwebtoken::{get_current_timestamp, Algorithm, EncodingKey, Header as JwtHeader};
     use kms::chain_code::two_party as chain_code;
     use kms::ecdsa::two_party::*;
     use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::*;
+    use proptest::prelude::*;
+    use web3::types::{Address, Bytes, H256, U256};
 
     #[derive(Debug, Deserialize)]
     #[allow(dead_code, non_snake_case)]
@@ -32,11 +66,23 @@ mod test_suites {
         Msg: String,
     }
 
-    fn key_gen(
-        client: &Client,
-        auth_header: Header<'static>,
-        user_id_header: Header<'static>,
-    ) -> (String, MasterKey2) {
+    /// Bearer token and `user_id` header the client under test sends.
+    #[derive(Clone)]
+    struct Caller {
+        auth: Header<'static>,
+        user_id: Header<'static>,
+    }
+
+    impl Caller {
+        fn new(token: &str, user_id: &str) -> Caller {
+            Caller {
+                auth: Header::new("Authorization", format!("Bearer {}", token)),
+                user_id: Header::new("user_id", user_id.to_string()),
+            }
+        }
+    }
+
+    fn key_gen(client: &Client, caller: &Caller) -> (String, MasterKey2) {
         time_test!();
 
         /*************** START: FIRST MESSAGE ***************/
@@ -45,8 +91,8 @@ mod test_suites {
         let response = client
             .post("/ecdsa/keygen/first")
             .header(ContentType::JSON)
-            .header(auth_header.clone())
-            .header(user_id_header.clone())
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
@@ -79,8 +125,8 @@ mod test_suites {
             .post(format!("/ecdsa/keygen/{}/second", id))
             .body(body)
             .header(ContentType::JSON)
-            .header(auth_header.clone())
-            .header(user_id_header.clone())
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
@@ -111,14 +157,60 @@ mod test_suites {
 
         /*************** END: SECOND MESSAGE ***************/
 
+        /*************** START: PDL MESSAGES ***************/
+        let start = Instant::now();
+
+        let pdl_verifier = pdl::PDLVerifier::new(
+            &kg_party_one_second_message.ek.n,
+            &kg_party_one_second_message.c_key,
+            &kg_party_one_second_message
+                .ecdh_second_message
+                .comm_witness
+                .public_share,
+        );
+        let body = serde_json::to_string(&pdl_verifier.first_message()).unwrap();
+
+        let response = client
+            .post(format!("/ecdsa/keygen/{}/third", id))
+            .body(body)
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let party1_pdl_first_message: pdl::Party1PDLFirstMsg =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        let body = serde_json::to_string(&pdl_verifier.second_message()).unwrap();
+        let response = client
+            .post(format!("/ecdsa/keygen/{}/fourth", id))
+            .body(body)
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let pdl_decommit: pdl::PDLDecommit =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(
+            pdl_verifier.verify(&party1_pdl_first_message, &pdl_decommit),
+            Ok(())
+        );
+
+        println!(
+            "{} Network/Server: PDL messages",
+            TimeFormat(start.elapsed())
+        );
+        /*************** END: PDL MESSAGES ***************/
+
         /*************** START: CHAINCODE FIRST MESSAGE ***************/
         let start = Instant::now();
 
         let response = client
             .post(format!("/ecdsa/keygen/{}/chaincode/first", id))
             .header(ContentType::JSON)
-            .header(auth_header.clone())
-            .header(user_id_header.clone())
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
@@ -150,8 +242,8 @@ mod test_suites {
             .post(format!("/ecdsa/keygen/{}/chaincode/second", id))
             .body(body)
             .header(ContentType::JSON)
-            .header(auth_header.clone())
-            .header(user_id_header.clone())
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
@@ -207,15 +299,14 @@ mod test_suites {
         (id, party_two_master_key)
     }
 
-    fn sign(
-        client: &Client,
-        id: String,
-        master_key_2: MasterKey2,
-        message: BigInt,
-        auth_header: Header<'static>,
-        user_id_header: Header<'static>,
-    ) -> party_one::SignatureRecid {
-        time_test!();
+    struct PendingSignature {
+        session_id: String,
+        eph_comm_witness: party_two::EphCommWitness,
+        eph_ec_key_pair_party2: party_two::EphEcKeyPair,
+        sign_party_one_first_message: party_one::EphKeyGenFirstMsg,
+    }
+
+    fn sign_first(client: &Client, id: &str, caller: &Caller) -> PendingSignature {
         let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
             MasterKey2::sign_first_message();
 
@@ -229,8 +320,8 @@ mod test_suites {
             .post(format!("/ecdsa/sign/{}/first", id))
             .body(body)
             .header(ContentType::JSON)
-            .header(auth_header.clone())
-            .header(user_id_header.clone())
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
@@ -240,20 +331,59 @@ mod test_suites {
         );
 
         let res_body = response.into_string().unwrap();
-        let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
+        let (session_id, sign_party_one_first_message): (String, party_one::EphKeyGenFirstMsg) =
             serde_json::from_str(&res_body).unwrap();
 
-        let x_pos = BigInt::from(0);
-        let y_pos = BigInt::from(21);
+        PendingSignature {
+            session_id,
+            eph_comm_witness,
+            eph_ec_key_pair_party2,
+            sign_party_one_first_message,
+        }
+    }
+
+    fn allocate_child(
+        client: &Client,
+        id: &str,
+        master_key_2: &MasterKey2,
+        caller: &Caller,
+    ) -> ecdsa::HDChild {
+        let response = client
+            .post(format!("/ecdsa/{}/children", id))
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let child: ecdsa::HDChild = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        // The server derives the same child public key as the client.
+        let child_party_two_master_key =
+            master_key_2.get_child(vec![BigInt::from(child.x), BigInt::from(child.y)]);
+        assert_eq!(
+            child.address,
+            address::eth_address(&child_party_two_master_key.public.q)
+        );
+        child
+    }
+
+    fn sign_second_request(
+        master_key_2: &MasterKey2,
+        pending: PendingSignature,
+        message: BigInt,
+        child: &ecdsa::HDChild,
+    ) -> ecdsa::SignSecondMsgRequest {
+        let x_pos = BigInt::from(child.x);
+        let y_pos = BigInt::from(child.y);
 
         let child_party_two_master_key = master_key_2.get_child(vec![x_pos.clone(), y_pos.clone()]);
 
         let start = Instant::now();
 
         let party_two_sign_message = child_party_two_master_key.sign_second_message(
-            &eph_ec_key_pair_party2,
-            eph_comm_witness,
-            &sign_party_one_first_message,
+            &pending.eph_ec_key_pair_party2,
+            pending.eph_comm_witness,
+            &pending.sign_party_one_first_message,
             &message,
         );
 
@@ -262,23 +392,45 @@ mod test_suites {
             TimeFormat(start.elapsed())
         );
 
-        let request: ecdsa::SignSecondMsgRequest = ecdsa::SignSecondMsgRequest {
+        ecdsa::SignSecondMsgRequest {
             message,
             party_two_sign_message,
             x_pos_child_key: x_pos,
             y_pos_child_key: y_pos,
-        };
+            tx: None,
+            tx_id: None,
+        }
+    }
 
-        let body = serde_json::to_string(&request).unwrap();
+    fn sign_second_body(
+        master_key_2: &MasterKey2,
+        pending: PendingSignature,
+        message: BigInt,
+        child: &ecdsa::HDChild,
+    ) -> String {
+        serde_json::to_string(&sign_second_request(master_key_2, pending, message, child)).unwrap()
+    }
+
+    fn sign_second(
+        client: &Client,
+        id: &str,
+        master_key_2: &MasterKey2,
+        pending: PendingSignature,
+        message: BigInt,
+        child: &ecdsa::HDChild,
+        caller: &Caller,
+    ) -> party_one::SignatureRecid {
+        let path = format!("/ecdsa/sign/{}/{}/second", id, pending.session_id);
+        let body = sign_second_body(master_key_2, pending, message, child);
 
         let start = Instant::now();
 
         let response = client
-            .post(format!("/ecdsa/sign/{}/second", id))
-            .body(body)
+            .post(format!("{}?rsv=true", path))
+            .body(body.clone())
             .header(ContentType::JSON)
-            .header(auth_header.clone())
-            .header(user_id_header.clone())
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
 
@@ -288,11 +440,40 @@ mod test_suites {
         );
 
         let res_body = response.into_string().unwrap();
-        let signature_recid: party_one::SignatureRecid = serde_json::from_str(&res_body).unwrap();
+        let resp: ecdsa::SignSecondResp = serde_json::from_str(&res_body).unwrap();
+        let signature_recid = resp.signature;
+        let rsv = hex::decode(resp.rsv.unwrap()).unwrap();
+        assert_eq!(rsv.len(), signature::RSV_LEN);
+        assert_eq!(rsv[64], signature_recid.recid);
+        let q = Secp256k1Scalar::q();
+        assert!(signature_recid.s <= BigInt::mod_sub(&q, &signature_recid.s, &q));
+
+        // The ephemeral key of a signing session is single-use.
+        let response = client
+            .post(path)
+            .body(body)
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
 
         signature_recid
     }
 
+    fn sign(
+        client: &Client,
+        id: String,
+        master_key_2: MasterKey2,
+        message: BigInt,
+        child: &ecdsa::HDChild,
+        caller: &Caller,
+    ) -> party_one::SignatureRecid {
+        time_test!();
+        let pending = sign_first(client, &id, caller);
+        sign_second(client, &id, &master_key_2, pending, message, child, caller)
+    }
+
     #[test]
     fn key_gen_and_sign() {
         let env_configs = get_app_env::<TestEnv>(".env.test");
@@ -316,24 +497,18 @@ mod test_suites {
             .unwrap();
 
         println!("{:#?}", http_resp);
-        let auth_header = Header::new("Authorization", format!("Bearer {}", http_resp.Msg));
-        let user_id_header = Header::new("user_id", test_email);
+        let caller = Caller::new(&http_resp.Msg, &test_email);
 
         let client = Client::tracked(server::get_server()).expect("valid rocket instance");
 
-        let (id, master_key_2): (String, MasterKey2) =
-            key_gen(&client, auth_header.clone(), user_id_header.clone());
+        let (id, master_key_2): (String, MasterKey2) = key_gen(&client, &caller);
+
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
 
         let message = BigInt::from(1234);
 
-        let signature: party_one::SignatureRecid = sign(
-            &client,
-            id,
-            master_key_2,
-            message,
-            auth_header.clone(),
-            user_id_header.clone(),
-        );
+        let signature: party_one::SignatureRecid =
+            sign(&client, id, master_key_2, message, &child, &caller);
 
         println!(
             "s = (r: {}, s: {}, recid: {})",
@@ -343,6 +518,504 @@ mod test_suites {
         );
     }
 
+    /// Server on in-memory storage holding a completed wallet of `user-1`,
+    /// seeded from `local_master_keys` instead of running keygen over HTTP.
+    fn local_wallet() -> (Client, Caller, String, MasterKey2) {
+        let config = local_auth_config("sub");
+        let (master_key_1, master_key_2) = local_master_keys();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut batch = db::Batch::new();
+        batch
+            .insert(
+                "user-1",
+                &id,
+                &ecdsa::EcdsaStruct::Party1MasterKey,
+                &master_key_1,
+            )
+            .unwrap();
+        batch
+            .insert(
+                "user-1",
+                &id,
+                &ecdsa::EcdsaStruct::CC,
+                json!({ "chain_code": serde_json::to_value(&master_key_1).unwrap()["chain_code"] }),
+            )
+            .unwrap();
+        batch
+            .insert("user-1", &id, &ecdsa::EcdsaStruct::POS, json!({ "pos": 0 }))
+            .unwrap();
+        batch
+            .insert(
+                "user-1",
+                &id,
+                &ecdsa::EcdsaStruct::ChildrenTracked,
+                gc::now(),
+            )
+            .unwrap();
+        batch.index_wallet("user-1", &id, gc::now(), None);
+        session::start(&mut batch, "user-1", &id, SessionState::Complete).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(db::insert_many(config.db.as_ref(), batch))
+            .unwrap();
+
+        let client = Client::tracked(rocket::build().mount("/", server::routes()).manage(config))
+            .expect("valid rocket instance");
+        let caller = Caller::new(&user_token("user-1", &[]), "user-1");
+        (client, caller, id, master_key_2)
+    }
+
+    #[test]
+    fn only_issued_children_sign() {
+        let (client, caller, id, master_key_2) = local_wallet();
+
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let next_child = allocate_child(&client, &id, &master_key_2, &caller);
+        assert_eq!((child.x, child.y + 1), (next_child.x, next_child.y));
+
+        let pending = sign_first(&client, &id, &caller);
+        let session_id = pending.session_id.clone();
+        let unissued = ecdsa::HDChild {
+            y: next_child.y + 1,
+            ..next_child.clone()
+        };
+        let response = client
+            .post(format!("/ecdsa/sign/{}/{}/second", id, session_id))
+            .body(sign_second_body(
+                &master_key_2,
+                pending,
+                BigInt::from(1),
+                &unissued,
+            ))
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        sign(
+            &client,
+            id,
+            master_key_2,
+            BigInt::from(1),
+            &next_child,
+            &caller,
+        );
+    }
+
+    #[test]
+    fn wallet_summary_lists_the_issued_children() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+
+        let response = client
+            .get(format!("/ecdsa/wallets/{}", id))
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let wallet: ecdsa::WalletSummary =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(wallet.status, SessionState::Complete);
+        assert_eq!(
+            wallet.public_key,
+            Some(address::compressed_hex(&master_key_2.public.q))
+        );
+        assert!(wallet.has_chain_code);
+        assert_eq!(wallet.children, vec![child]);
+
+        let response = client
+            .get("/ecdsa/wallets")
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let wallets: Vec<ecdsa::WalletSummary> =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(wallets.len(), 1);
+        assert_eq!(wallets[0].id, id);
+        assert!(wallets[0].has_chain_code);
+        let listed = serde_json::to_value(&wallets[0]).unwrap();
+        assert!(listed.get("chain_code").is_none());
+    }
+
+    #[test]
+    fn child_public_key_matches_its_address() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+
+        let response = client
+            .get(format!("/ecdsa/{}/pubkey?path=0/{}", id, child.y))
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let public_key: ecdsa::PublicKeyResp =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(public_key.compressed, child.public_key);
+        assert_eq!(public_key.eth_address, child.address);
+
+        let response = client
+            .get(format!("/ecdsa/{}/pubkey?path=0", id))
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn signatures_of_a_wallet_can_be_in_flight_concurrently() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let next_child = allocate_child(&client, &id, &master_key_2, &caller);
+
+        let pending_a = sign_first(&client, &id, &caller);
+        let pending_b = sign_first(&client, &id, &caller);
+        sign_second(
+            &client,
+            &id,
+            &master_key_2,
+            pending_b,
+            BigInt::from(5678),
+            &next_child,
+            &caller,
+        );
+        sign_second(
+            &client,
+            &id,
+            &master_key_2,
+            pending_a,
+            BigInt::from(9012),
+            &child,
+            &caller,
+        );
+    }
+
+    fn goerli_transfer(to: Address) -> UnsignedTx {
+        UnsignedTx {
+            fee_strategy: FeeStrategy::Eip1559,
+            chain_id: 5,
+            nonce: U256::zero(),
+            to,
+            value: U256::exp10(15),
+            data: Bytes::default(),
+            gas: U256::from(21000),
+            max_fee_per_gas: U256::from(30_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(1_500_000_000u64),
+        }
+    }
+
+    /// Signs `message` in transaction mode for `tx` from `child`.
+    fn sign_tx<'c>(
+        client: &'c Client,
+        id: &str,
+        master_key_2: &MasterKey2,
+        child: &ecdsa::HDChild,
+        tx: &UnsignedTx,
+        message: BigInt,
+        caller: &Caller,
+    ) -> rocket::local::blocking::LocalResponse<'c> {
+        let pending = sign_first(client, id, caller);
+        let path = format!("/ecdsa/sign/{}/{}/second", id, pending.session_id);
+        let mut request = sign_second_request(master_key_2, pending, message, child);
+        request.tx = Some(tx.clone());
+        client
+            .post(path)
+            .body(serde_json::to_string(&request).unwrap())
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch()
+    }
+
+    #[test]
+    fn transaction_mode_signs_only_the_server_sighash() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let tx = goerli_transfer(Address::repeat_byte(0x42));
+
+        let response = sign_tx(
+            &client,
+            &id,
+            &master_key_2,
+            &child,
+            &tx,
+            BigInt::from(1),
+            &caller,
+        );
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = sign_tx(
+            &client,
+            &id,
+            &master_key_2,
+            &child,
+            &tx,
+            BigInt::from_bytes(&tx.sighash().0),
+            &caller,
+        );
+        assert_eq!(response.status(), Status::Ok);
+        let resp: ecdsa::SignSecondResp =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(
+            resp.raw_tx,
+            Some(tx.encode_signed(&resp.signature).unwrap())
+        );
+        assert_eq!(
+            signature::recover_address(&tx.sighash().0, &resp.signature).unwrap(),
+            child.address
+        );
+    }
+
+    #[test]
+    fn built_transactions_are_signed_only_by_their_wallet_and_child() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let other_child = allocate_child(&client, &id, &master_key_2, &caller);
+        let tx = goerli_transfer(Address::repeat_byte(0x42));
+
+        // Refused before any chain access, for an address of no issued child.
+        let response = client
+            .post("/eth/tx/build")
+            .body(
+                json!({
+                    "wallet_id": "other-wallet",
+                    "from_address": child.address,
+                    "to_address": tx.to,
+                    "value": "0.001",
+                    "unit": "ether",
+                })
+                .to_string(),
+            )
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let store = |tx_id: &str, wallet_id: &str, built_child: &ecdsa::HDChild| {
+            let built = eth::BuiltTx {
+                wallet_id: wallet_id.to_string(),
+                x: built_child.x,
+                y: built_child.y,
+                tx: tx.clone(),
+            };
+            let config = client.rocket().state::<AppConfig>().unwrap();
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(db::insert(
+                    &config.db,
+                    "user-1",
+                    tx_id,
+                    &eth::EthStruct::UnsignedTx,
+                    &built,
+                ))
+                .unwrap();
+        };
+        let sign = |tx_id: &str| {
+            let pending = sign_first(&client, &id, &caller);
+            let path = format!("/ecdsa/sign/{}/{}/second", id, pending.session_id);
+            let message = BigInt::from_bytes(&tx.sighash().0);
+            let mut request = sign_second_request(&master_key_2, pending, message, &child);
+            request.tx_id = Some(tx_id.to_string());
+            client
+                .post(path)
+                .body(serde_json::to_string(&request).unwrap())
+                .header(ContentType::JSON)
+                .header(caller.auth.clone())
+                .header(caller.user_id.clone())
+                .dispatch()
+                .status()
+        };
+        store("tx-other-wallet", "other-wallet", &child);
+        assert_eq!(sign("tx-other-wallet"), Status::Forbidden);
+        store("tx-other-child", &id, &other_child);
+        assert_eq!(sign("tx-other-child"), Status::Forbidden);
+        store("tx-1", &id, &child);
+        assert_eq!(sign("tx-1"), Status::Ok);
+    }
+
+    #[test]
+    fn wallet_policy_is_enforced_on_signing() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let denied = Address::repeat_byte(0x42);
+        let tx = goerli_transfer(denied);
+        let policy = Policy {
+            denied_recipients: vec![denied],
+            ..Policy::default()
+        };
+
+        // Owners cannot change their own policies, policy admins do.
+        let policy_path = format!("/ecdsa/{}/policy?owner=user-1", id);
+        let response = client
+            .put(policy_path.clone())
+            .body(serde_json::to_string(&policy).unwrap())
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let admin = bearer(&user_token("admin", &["policy_admin"]));
+        let response = client
+            .put(policy_path.clone())
+            .body(serde_json::to_string(&policy).unwrap())
+            .header(ContentType::JSON)
+            .header(admin.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/ecdsa/{}/policy", id))
+            .header(caller.auth.clone())
+            .dispatch();
+        let stored: Policy = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(stored, policy);
+
+        let sighash = BigInt::from_bytes(&tx.sighash().0);
+        let response = sign_tx(
+            &client,
+            &id,
+            &master_key_2,
+            &child,
+            &tx,
+            sighash.clone(),
+            &caller,
+        );
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(response.into_string().unwrap().contains("is denied"));
+
+        let response = client
+            .delete(policy_path.clone())
+            .header(caller.auth.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.delete(policy_path).header(admin).dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let response = sign_tx(&client, &id, &master_key_2, &child, &tx, sighash, &caller);
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn spends_of_signatures_not_produced_are_released() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let tx = goerli_transfer(Address::repeat_byte(0x42));
+        let sighash = BigInt::from_bytes(&tx.sighash().0);
+        // Room for a single transfer.
+        let policy = Policy {
+            daily_limit: Some(policy::max_cost(&tx)),
+            ..Policy::default()
+        };
+        let response = client
+            .put(format!("/ecdsa/{}/policy?owner=user-1", id))
+            .body(serde_json::to_string(&policy).unwrap())
+            .header(ContentType::JSON)
+            .header(bearer(&user_token("admin", &["policy_admin"])))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // Authorized, but party 2 signed another message: no signature.
+        let pending = sign_first(&client, &id, &caller);
+        let path = format!("/ecdsa/sign/{}/{}/second", id, pending.session_id);
+        let mut request = sign_second_request(&master_key_2, pending, BigInt::from(1), &child);
+        request.message = sighash.clone();
+        request.tx = Some(tx.clone());
+        let response = client
+            .post(path)
+            .body(serde_json::to_string(&request).unwrap())
+            .header(ContentType::JSON)
+            .header(caller.auth.clone())
+            .header(caller.user_id.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let response = sign_tx(
+            &client,
+            &id,
+            &master_key_2,
+            &child,
+            &tx,
+            sighash.clone(),
+            &caller,
+        );
+        assert_eq!(response.status(), Status::Ok);
+        let response = sign_tx(&client, &id, &master_key_2, &child, &tx, sighash, &caller);
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn approvals_keep_their_signing_session_until_they_expire() {
+        let (client, caller, id, master_key_2) = local_wallet();
+        let child = allocate_child(&client, &id, &master_key_2, &caller);
+        let tx = goerli_transfer(Address::repeat_byte(0x42));
+        let policy = Policy {
+            approval: Some(ApprovalRule {
+                threshold: U256::zero(),
+                required: 1,
+                approvers: vec![],
+            }),
+            ..Policy::default()
+        };
+        let response = client
+            .put(format!("/ecdsa/{}/policy?owner=user-1", id))
+            .body(serde_json::to_string(&policy).unwrap())
+            .header(ContentType::JSON)
+            .header(bearer(&user_token("admin", &["policy_admin"])))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let storage = client.rocket().state::<AppConfig>().unwrap().db.clone();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let pending = sign_first(&client, &id, &caller);
+        let session_id = pending.session_id.clone();
+        let sign_id = format!("{}_{}", id, session_id);
+        // The signing session was started almost a TTL ago.
+        let deadline = gc::now() + 60;
+        let mut batch = db::Batch::new();
+        for record in [
+            ecdsa::EcdsaStruct::Session,
+            ecdsa::EcdsaStruct::EphEcKeyPair,
+            ecdsa::EcdsaStruct::EphKeyGenFirstMsg,
+        ] {
+            batch.expire_at("user-1", &sign_id, &record, deadline);
+        }
+        runtime
+            .block_on(db::insert_many(storage.as_ref(), batch))
+            .unwrap();
+
+        let path = format!("/ecdsa/sign/{}/{}/second", id, session_id);
+        let mut request = sign_second_request(
+            &master_key_2,
+            pending,
+            BigInt::from_bytes(&tx.sighash().0),
+            &child,
+        );
+        request.tx = Some(tx);
+        let body = serde_json::to_string(&request).unwrap();
+        let sign = || {
+            client
+                .post(path.clone())
+                .body(body.clone())
+                .header(ContentType::JSON)
+                .header(caller.auth.clone())
+                .header(caller.user_id.clone())
+                .dispatch()
+                .status()
+        };
+        assert_eq!(sign(), Status::Accepted);
+
+        // Past the session's own TTL, before the approval's.
+        runtime
+            .block_on(gc::sweep(storage.as_ref(), deadline + 60))
+            .unwrap();
+        let response = client
+            .post(format!("/ecdsa/approvals/{}/approve", session_id))
+            .body("{}")
+            .header(ContentType::JSON)
+            .header(bearer(&user_token("checker", &["approver"])))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(sign(), Status::Ok);
+    }
+
     #[test]
     fn authentication_test_invalid_token() {
         let client = Client::tracked(server::get_server()).expect("valid rocket instance");
@@ -392,4 +1065,2853 @@ mod test_suites {
 
         assert_eq!(401, response.status().code);
     }
+
+    #[test]
+    fn every_protocol_route_validates_the_token() {
+        let client = Client::tracked(server::get_server()).expect("valid rocket instance");
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let sign_second_path = format!("/ecdsa/sign/id/{}/second", session_id);
+        let paths = [
+            "/ecdsa/keygen/first",
+            "/ecdsa/keygen/id/second",
+            "/ecdsa/keygen/id/third",
+            "/ecdsa/keygen/id/fourth",
+            "/ecdsa/keygen/id/chaincode/first",
+            "/ecdsa/keygen/id/chaincode/second",
+            "/ecdsa/sign/id/first",
+            sign_second_path.as_str(),
+            "/ecdsa/rotate/id/first",
+            "/ecdsa/rotate/id/second",
+            "/ecdsa/rotate/id/third",
+            "/ecdsa/rotate/id/finalize",
+            "/ecdsa/rotate/id/rollback",
+            "/ecdsa/id/recover",
+            "/ecdsa/id/children",
+            "/eth/tx/params",
+            "/eth/tx/build",
+            "/eth/tx/assemble",
+            "/eth/tx/send",
+        ];
+
+        for path in paths.iter() {
+            let response = client
+                .post(*path)
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", "Bearer a"))
+                .header(Header::new("user_id", "someone"))
+                .body("{}")
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized, "{}", path);
+        }
+
+        let policy_requests = [
+            client.get("/ecdsa/id/policy"),
+            client.put("/ecdsa/id/policy?owner=someone"),
+            client.delete("/ecdsa/id/policy?owner=someone"),
+            client.get("/ecdsa/approvals"),
+            client.get("/ecdsa/approvals/id"),
+            client.post("/ecdsa/approvals/id/approve"),
+            client.post("/ecdsa/approvals/id/reject"),
+            client.get("/audit"),
+        ];
+        for request in policy_requests {
+            let response = request
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", "Bearer a"))
+                .body("{}")
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn storage_round_trip_in_memory() {
+        let storage = MemoryStorage::new();
+        let pos = BigInt::from(42);
+
+        db::insert(&storage, "user", "id", &ecdsa::EcdsaStruct::POS, &pos)
+            .await
+            .unwrap();
+
+        let stored: Option<BigInt> = db::get(&storage, "user", "id", &ecdsa::EcdsaStruct::POS)
+            .await
+            .unwrap();
+        assert_eq!(stored, Some(pos));
+
+        let other_user: Option<BigInt> = db::get(&storage, "other", "id", &ecdsa::EcdsaStruct::POS)
+            .await
+            .unwrap();
+        assert_eq!(other_user, None);
+    }
+
+    #[rocket::async_test]
+    async fn encrypted_storage_seals_values_and_rewraps_on_rotation() {
+        let raw = Arc::new(MemoryStorage::new());
+        raw.put(b"legacy", b"{\"plain\":true}").await.unwrap();
+
+        let old_keyring = MasterKeyring::from_hex("k1", &"11".repeat(32)).unwrap();
+        let strict = EncryptedStorage::new(raw.clone(), old_keyring);
+        assert!(strict.get(b"legacy").await.is_err());
+        let old_keyring = MasterKeyring::from_hex("k1", &"11".repeat(32)).unwrap();
+        let storage = EncryptedStorage::new(raw.clone(), old_keyring).migrating_plaintext();
+        storage.put(b"secret", b"paillier p and q").await.unwrap();
+
+        let sealed = raw.get(b"secret").await.unwrap().unwrap();
+        assert!(!sealed.windows(b"paillier".len()).any(|w| w == b"paillier"));
+        assert_eq!(
+            storage.get(b"secret").await.unwrap().unwrap(),
+            b"paillier p and q".to_vec()
+        );
+        assert_eq!(
+            storage.get(b"legacy").await.unwrap().unwrap(),
+            b"{\"plain\":true}".to_vec()
+        );
+
+        // Swapping ciphertexts between keys must not decrypt.
+        raw.put(b"other", &sealed).await.unwrap();
+        assert!(storage.get(b"other").await.is_err());
+        raw.delete(b"other").await.unwrap();
+
+        let keyring_path =
+            std::env::temp_dir().join(format!("keyring-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &keyring_path,
+            json!({ "active": "k2", "keys": { "k1": "11".repeat(32), "k2": "22".repeat(32) } })
+                .to_string(),
+        )
+        .unwrap();
+        let new_keyring = MasterKeyring::from_file(keyring_path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&keyring_path).unwrap();
+
+        let rotated = EncryptedStorage::new(raw.clone(), new_keyring).migrating_plaintext();
+        assert_eq!(rotated.rewrap_all().await.unwrap(), 2);
+        assert!(!rotated.accepts_plaintext());
+        assert_eq!(rotated.rewrap_all().await.unwrap(), 0);
+        assert_eq!(
+            rotated.get(b"secret").await.unwrap().unwrap(),
+            b"paillier p and q".to_vec()
+        );
+        assert!(raw
+            .get(b"legacy")
+            .await
+            .unwrap()
+            .unwrap()
+            .starts_with(b"NYE1\x02k2"));
+
+        // Once everything is sealed, a planted plaintext value is refused and
+        // never sealed by a later pass.
+        raw.put(b"planted", b"{\"plain\":true}").await.unwrap();
+        assert!(rotated.get(b"planted").await.is_err());
+        let scanned: Vec<Vec<u8>> = rotated
+            .scan(b"")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(scanned, vec![b"legacy".to_vec(), b"secret".to_vec()]);
+        assert_eq!(rotated.refused_records(), 1);
+        assert_eq!(rotated.rewrap_all().await.unwrap(), 0);
+        assert_eq!(
+            raw.get(b"planted").await.unwrap().unwrap(),
+            b"{\"plain\":true}".to_vec()
+        );
+    }
+
+    /// In-memory backend that applies each write op by op and, once armed,
+    /// gives up on the `n`-th op, as a full disk or a crash halfway through
+    /// a `WriteBatch` would, or on every op touching a refused key class, as
+    /// an unavailable column family would. A failed batch is dropped as a
+    /// whole, like an uncommitted `WriteBatch`; separate writes that got in
+    /// before it stay. Reads yield first, so concurrent requests interleave
+    /// as they would on a real backend.
+    #[derive(Default)]
+    struct FailingStorage {
+        records: std::sync::Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+        /// Write ops left up to the injected failure, `None` when disarmed.
+        fail_in: std::sync::Mutex<Option<usize>>,
+        refused: std::sync::Mutex<Option<keys::KeyClass>>,
+    }
+
+    impl FailingStorage {
+        /// Fails the `n`-th write op from now on, counting from 1.
+        fn fail_at(&self, n: usize) {
+            *self.fail_in.lock().unwrap() = Some(n);
+        }
+
+        /// Fails every write op to a key of `class` from now on.
+        fn refuse_class(&self, class: keys::KeyClass) {
+            *self.refused.lock().unwrap() = Some(class);
+        }
+
+        fn snapshot(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+            self.records.lock().unwrap().clone()
+        }
+
+        fn apply(
+            &self,
+            records: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+            op: BatchOp,
+        ) -> anyhow::Result<()> {
+            let key = match &op {
+                BatchOp::Put { key, .. } | BatchOp::Delete { key } => key,
+            };
+            if let Some(class) = *self.refused.lock().unwrap() {
+                if keys::KeyClass::of(key) == Some(class) {
+                    return Err(anyhow::anyhow!("{:?} is unavailable", class));
+                }
+            }
+            let mut fail_in = self.fail_in.lock().unwrap();
+            if let Some(left) = fail_in.as_mut() {
+                *left -= 1;
+                if *left == 0 {
+                    *fail_in = None;
+                    return Err(anyhow::anyhow!("injected write failure"));
+                }
+            }
+            match op {
+                BatchOp::Put { key, value } => {
+                    records.insert(key, value);
+                }
+                BatchOp::Delete { key } => {
+                    records.remove(&key);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[rocket::async_trait]
+    impl Storage for FailingStorage {
+        async fn get(&self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            tokio::task::yield_now().await;
+            Ok(self.records.lock().unwrap().get(key).cloned())
+        }
+
+        async fn put(&self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+            let mut records = self.records.lock().unwrap();
+            self.apply(
+                &mut records,
+                BatchOp::Put {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                },
+            )
+        }
+
+        async fn delete(&self, key: &[u8]) -> anyhow::Result<()> {
+            let mut records = self.records.lock().unwrap();
+            self.apply(&mut records, BatchOp::Delete { key: key.to_vec() })
+        }
+
+        async fn take(&self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            let mut records = self.records.lock().unwrap();
+            let value = records.get(key).cloned();
+            if value.is_some() {
+                self.apply(&mut records, BatchOp::Delete { key: key.to_vec() })?;
+            }
+            Ok(value)
+        }
+
+        async fn scan(&self, prefix: &[u8]) -> anyhow::Result<Vec<KeyValue>> {
+            Ok(self
+                .records
+                .lock()
+                .unwrap()
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect())
+        }
+
+        async fn batch(&self, ops: Vec<BatchOp>) -> anyhow::Result<()> {
+            let mut records = self.records.lock().unwrap();
+            let mut staged = records.clone();
+            for op in ops {
+                self.apply(&mut staged, op)?;
+            }
+            *records = staged;
+            Ok(())
+        }
+
+        async fn batch_if(
+            &self,
+            expected: Vec<ExpectedValue>,
+            ops: Vec<BatchOp>,
+        ) -> anyhow::Result<bool> {
+            let mut records = self.records.lock().unwrap();
+            if expected
+                .iter()
+                .any(|(key, value)| records.get(key) != value.as_ref())
+            {
+                return Ok(false);
+            }
+            let mut staged = records.clone();
+            for op in ops {
+                self.apply(&mut staged, op)?;
+            }
+            *records = staged;
+            Ok(true)
+        }
+    }
+
+    #[rocket::async_test]
+    async fn failed_step_leaves_no_partial_records() {
+        let storage = Arc::new(FailingStorage::default());
+        let config = AppConfig {
+            db: storage.clone(),
+            ..local_auth_config("sub")
+        };
+        let client = local_client(config).await;
+        let token = user_token("user-1", &[]);
+
+        let response = client
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let (id, _): (String, party_one::KeyGenFirstMsg) =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        let (party2_first, _) = MasterKey2::key_gen_first_message();
+        let body = serde_json::to_string(&party2_first.d_log_proof).unwrap();
+
+        // Fail each write of `/ecdsa/keygen/<id>/second` in turn: records,
+        // expiry entries and the session are one batch, none of them may
+        // stay behind, and the step can simply be retried.
+        let mut failing_op = 1;
+        loop {
+            let before = storage.snapshot();
+            storage.fail_at(failing_op);
+            let response = client
+                .post(format!("/ecdsa/keygen/{}/second", id))
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .body(body.clone())
+                .dispatch()
+                .await;
+            if response.status() == Status::Ok {
+                break;
+            }
+            assert_eq!(
+                storage.snapshot(),
+                before,
+                "failing write {} left records behind",
+                failing_op
+            );
+            failing_op += 1;
+        }
+        assert!(failing_op > 3);
+        assert_eq!(
+            session::current(storage.as_ref(), "user-1", &id)
+                .await
+                .unwrap(),
+            SessionState::KeygenDlogDone
+        );
+    }
+
+    async fn run_step(storage: &dyn Storage, id: &str, step: Step) -> anyhow::Result<()> {
+        session::expect(storage, "user", id, step).await?;
+        let mut batch = db::Batch::new();
+        session::advance(&mut batch, "user", id, step)?;
+        db::insert_many(storage, batch).await
+    }
+
+    async fn start_signing(storage: &dyn Storage, sign_id: &str) -> anyhow::Result<()> {
+        session::expect(storage, "user", "id", Step::SignFirst).await?;
+        let mut batch = db::Batch::new();
+        session::advance(&mut batch, "user", sign_id, Step::SignFirst)?;
+        db::insert_many(storage, batch).await
+    }
+
+    fn assert_conflict(result: anyhow::Result<()>) {
+        let err = result.expect_err("out-of-order step must be rejected");
+        let http_error = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(http_error.status, Status::Conflict);
+    }
+
+    #[rocket::async_test]
+    async fn session_steps_are_enforced_in_order() {
+        let storage = MemoryStorage::new();
+        assert_conflict(run_step(&storage, "id", Step::KeygenSecond).await);
+
+        let mut batch = db::Batch::new();
+        session::start(&mut batch, "user", "id", SessionState::KeygenStarted).unwrap();
+        db::insert_many(&storage, batch).await.unwrap();
+
+        assert_conflict(run_step(&storage, "id", Step::ChainCodeSecond).await);
+        assert_conflict(start_signing(&storage, "id_s1").await);
+        run_step(&storage, "id", Step::KeygenSecond).await.unwrap();
+        assert_conflict(run_step(&storage, "id", Step::KeygenSecond).await);
+        assert_conflict(run_step(&storage, "id", Step::ChainCodeFirst).await);
+        assert_conflict(run_step(&storage, "id", Step::KeygenFourth).await);
+        run_step(&storage, "id", Step::KeygenThird).await.unwrap();
+        run_step(&storage, "id", Step::KeygenFourth).await.unwrap();
+        run_step(&storage, "id", Step::ChainCodeFirst)
+            .await
+            .unwrap();
+        run_step(&storage, "id", Step::ChainCodeSecond)
+            .await
+            .unwrap();
+
+        // Signing sessions are independent of each other.
+        assert_conflict(run_step(&storage, "id_s1", Step::SignSecond).await);
+        start_signing(&storage, "id_s1").await.unwrap();
+        start_signing(&storage, "id_s2").await.unwrap();
+        run_step(&storage, "id_s2", Step::SignSecond).await.unwrap();
+        run_step(&storage, "id_s1", Step::SignSecond).await.unwrap();
+        // Replaying the second signing round needs a fresh first round.
+        assert_conflict(run_step(&storage, "id_s1", Step::SignSecond).await);
+
+        assert_conflict(run_step(&storage, "id", Step::RotateSecond).await);
+        run_step(&storage, "id", Step::RotateFirst).await.unwrap();
+        assert_conflict(start_signing(&storage, "id_s3").await);
+        assert_conflict(run_step(&storage, "id", Step::RotateThird).await);
+        run_step(&storage, "id", Step::RotateSecond).await.unwrap();
+        // The rotated key is pending until party 2 confirmed it.
+        assert_conflict(start_signing(&storage, "id_s3").await);
+        assert_conflict(run_step(&storage, "id", Step::RotateSecond).await);
+        run_step(&storage, "id", Step::RotateThird).await.unwrap();
+        // The rotated key signs, but no new rotation starts before the
+        // client finalized or rolled back.
+        start_signing(&storage, "id_s3").await.unwrap();
+        assert_conflict(run_step(&storage, "id", Step::RotateFirst).await);
+        run_step(&storage, "id", Step::RotateFinalize)
+            .await
+            .unwrap();
+        assert_conflict(run_step(&storage, "id", Step::RotateRollback).await);
+        run_step(&storage, "id", Step::RotateFirst).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn wallets_without_a_session_need_a_master_key() {
+        let storage = MemoryStorage::new();
+        let missing = run_step(&storage, "id", Step::RotateFirst).await;
+        assert_eq!(http_status(missing.unwrap_err()), Status::NotFound);
+        assert_conflict(run_step(&storage, "id_s1", Step::SignSecond).await);
+
+        // Wallets created before sessions were tracked are complete.
+        db::insert(
+            &storage,
+            "user",
+            "id",
+            &ecdsa::EcdsaStruct::Party1MasterKey,
+            &BigInt::from(1),
+        )
+        .await
+        .unwrap();
+        start_signing(&storage, "id_s1").await.unwrap();
+        run_step(&storage, "id", Step::RotateFirst).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn concurrent_requests_for_one_step_advance_once() {
+        let client = local_client(local_auth_config("sub")).await;
+        let token = user_token("user-1", &[]);
+        let response = client
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        let (id, _): (String, party_one::KeyGenFirstMsg) =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        let (party2_first, _) = MasterKey2::key_gen_first_message();
+        let body = serde_json::to_string(&party2_first.d_log_proof).unwrap();
+
+        let second = || {
+            client
+                .post(format!("/ecdsa/keygen/{}/second", id))
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .body(body.clone())
+                .dispatch()
+        };
+        let (a, b) = futures::future::join(second(), second()).await;
+        let mut statuses = vec![a.status().code, b.status().code];
+        statuses.sort_unstable();
+        assert_eq!(statuses, vec![200, 409]);
+    }
+
+    async fn rotate_to(storage: &dyn Storage, current: u32, rotated: u32) -> rotation::KeyVersion {
+        run_step(storage, "id", Step::RotateFirst).await.unwrap();
+        run_step(storage, "id", Step::RotateSecond).await.unwrap();
+        session::expect(storage, "user", "id", Step::RotateThird)
+            .await
+            .unwrap();
+        let mut batch = db::Batch::new();
+        let version = rotation::swap_in(
+            storage,
+            &mut batch,
+            "user",
+            "id",
+            &BigInt::from(current),
+            &BigInt::from(rotated),
+        )
+        .await
+        .unwrap();
+        session::advance(&mut batch, "user", "id", Step::RotateThird).unwrap();
+        db::insert_many(storage, batch).await.unwrap();
+        version
+    }
+
+    async fn stored_key(storage: &dyn Storage, name: &ecdsa::EcdsaStruct) -> Option<BigInt> {
+        db::get(storage, "user", "id", name).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn rotation_keeps_the_previous_version_until_confirmed() {
+        let storage = MemoryStorage::new();
+        db::insert(
+            &storage,
+            "user",
+            "id",
+            &ecdsa::EcdsaStruct::Party1MasterKey,
+            &BigInt::from(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rotation::current(&storage, "user", "id").await.unwrap(),
+            rotation::KeyVersion::default()
+        );
+
+        let version = rotate_to(&storage, 1, 2).await;
+        assert_eq!((version.version, version.latest), (2, 2));
+        let unconfirmed = version.unconfirmed.clone().unwrap();
+        assert_eq!(unconfirmed.previous, 1);
+        assert_eq!(
+            stored_key(&storage, &ecdsa::EcdsaStruct::Party1MasterKey).await,
+            Some(BigInt::from(2))
+        );
+        let previous: Option<rotation::VersionedKey<BigInt>> = db::get(
+            &storage,
+            "user",
+            "id",
+            &ecdsa::EcdsaStruct::Party1MasterKeyPrevious,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            previous,
+            Some(rotation::VersionedKey {
+                version: 1,
+                master_key: BigInt::from(1)
+            })
+        );
+
+        // Rolling back waits for the timeout.
+        let early = rotation::rollback::<BigInt>(&storage, "user", "id").await;
+        assert_eq!(http_status(early.unwrap_err()), Status::Conflict);
+        let timed_out = version
+            .rolled_back("id", unconfirmed.rollback_after)
+            .unwrap();
+        assert_eq!((timed_out.version, timed_out.latest), (1, 2));
+        assert_eq!(timed_out.unconfirmed, None);
+
+        // Past it, the previous key is restored.
+        let mut expired = version.clone();
+        expired.unconfirmed.as_mut().unwrap().rollback_after = gc::now() - 1;
+        db::insert(
+            &storage,
+            "user",
+            "id",
+            &ecdsa::EcdsaStruct::KeyVersion,
+            &expired,
+        )
+        .await
+        .unwrap();
+        let (restored_version, restored) = rotation::rollback::<BigInt>(&storage, "user", "id")
+            .await
+            .unwrap();
+        assert_eq!(restored, BigInt::from(1));
+        rotation::restore(&storage, "user", "id", &restored_version, &restored)
+            .await
+            .unwrap();
+        assert_eq!(
+            stored_key(&storage, &ecdsa::EcdsaStruct::Party1MasterKey).await,
+            Some(BigInt::from(1))
+        );
+        assert_eq!(
+            stored_key(&storage, &ecdsa::EcdsaStruct::Party1MasterKeyPrevious).await,
+            None
+        );
+        assert_eq!(
+            session::current(&storage, "user", "id").await.unwrap(),
+            SessionState::Complete
+        );
+        let finalize_rolled_back = rotation::finalize(&storage, "user", "id").await;
+        assert_eq!(
+            http_status(finalize_rolled_back.unwrap_err()),
+            Status::Conflict
+        );
+
+        // The rolled back version is not reused, a finalized rotation drops
+        // the previous key.
+        let version = rotate_to(&storage, 1, 3).await;
+        assert_eq!((version.version, version.latest), (3, 3));
+        let finalized = rotation::finalize(&storage, "user", "id").await.unwrap();
+        assert_eq!((finalized.version, finalized.unconfirmed), (3, None));
+        assert_eq!(
+            stored_key(&storage, &ecdsa::EcdsaStruct::Party1MasterKeyPrevious).await,
+            None
+        );
+        assert_eq!(
+            rotation::current(&storage, "user", "id").await.unwrap(),
+            finalized
+        );
+        let rollback_finalized = rotation::rollback::<BigInt>(&storage, "user", "id").await;
+        assert_eq!(
+            http_status(rollback_finalized.unwrap_err()),
+            Status::Conflict
+        );
+    }
+
+    #[test]
+    fn rotation_is_confirmed_by_a_proof_of_the_new_share() {
+        use curv::cryptographic_primitives::proofs::sigma_dlog::{DLogProof, ProveDLog};
+
+        let x2: FE = ECScalar::new_random();
+        let p2 = GE::generator().scalar_mul(&x2.get_element());
+        let proof = DLogProof::<GE>::prove(&x2);
+        ecdsa::expect_party2_share(&p2, &proof).unwrap();
+
+        // Neither a proof for another share nor one relabelled with the
+        // expected share confirms the rotation.
+        let other: FE = ECScalar::new_random();
+        let other_p2 = GE::generator().scalar_mul(&other.get_element());
+        assert_eq!(
+            http_status(ecdsa::expect_party2_share(&other_p2, &proof).unwrap_err()),
+            Status::BadRequest
+        );
+        let mut forged = DLogProof::<GE>::prove(&other);
+        forged.pk = p2;
+        assert_eq!(
+            http_status(ecdsa::expect_party2_share(&p2, &forged).unwrap_err()),
+            Status::BadRequest
+        );
+    }
+
+    /// Both master keys of a wallet, generated in-process without a server.
+    fn local_master_keys() -> (MasterKey1, MasterKey2) {
+        let (kg_party_one_first_message, comm_witness, ec_key_pair_party1) =
+            MasterKey1::key_gen_first_message();
+        let party1_public = comm_witness.public_share;
+        let (kg_party_two_first_message, ec_key_pair_party2) = MasterKey2::key_gen_first_message();
+        let (kg_party_one_second_message, paillier_key_pair, party_one_private) =
+            MasterKey1::key_gen_second_message(
+                comm_witness,
+                &ec_key_pair_party1,
+                &kg_party_two_first_message.d_log_proof,
+            );
+        let (_, party_two_paillier) = MasterKey2::key_gen_second_message(
+            &kg_party_one_first_message,
+            &kg_party_one_second_message,
+            SALT_STRING,
+        )
+        .unwrap();
+
+        let (_, cc_comm_witness, cc_ec_key_pair1) =
+            chain_code::party1::ChainCode1::chain_code_first_message();
+        let (cc_party_two_first_message, cc_ec_key_pair2) =
+            chain_code::party2::ChainCode2::chain_code_first_message();
+        let cc_party_one_second_message = chain_code::party1::ChainCode1::chain_code_second_message(
+            cc_comm_witness,
+            &cc_party_two_first_message.d_log_proof,
+        );
+        let party1_cc = chain_code::party1::ChainCode1::compute_chain_code(
+            &cc_ec_key_pair1,
+            &cc_party_two_first_message.d_log_proof.pk,
+        );
+        let party2_cc = chain_code::party2::ChainCode2::compute_chain_code(
+            &cc_ec_key_pair2,
+            &cc_party_one_second_message.comm_witness.public_share,
+        );
+
+        (
+            MasterKey1::set_master_key(
+                &party1_cc.chain_code,
+                party_one_private,
+                &party1_public,
+                &kg_party_two_first_message.d_log_proof.pk,
+                paillier_key_pair,
+            ),
+            MasterKey2::set_master_key(
+                &party2_cc.chain_code,
+                &ec_key_pair_party2,
+                &party1_public,
+                &party_two_paillier,
+            ),
+        )
+    }
+
+    /// Party 2's secret share, which `MasterKey2` only exposes serialized.
+    fn party2_share(master_key: &MasterKey2) -> FE {
+        let master_key = serde_json::to_value(master_key).unwrap();
+        serde_json::from_value(master_key["private"]["x2"].clone()).unwrap()
+    }
+
+    #[test]
+    fn kms_rotation_is_confirmed_by_party_2() {
+        use curv::cryptographic_primitives::proofs::sigma_dlog::{DLogProof, ProveDLog};
+        use kms::rotation::two_party::party1::Rotation1;
+        use kms::rotation::two_party::party2::Rotation2;
+
+        let (master_key1, master_key2) = local_master_keys();
+        let old_share = party2_share(&master_key2);
+
+        let (party1_first, m1, r1) = Rotation1::key_rotate_first_message();
+        let party2_first = Rotation2::key_rotate_first_message(&party1_first);
+        let (party1_second, random1) =
+            Rotation1::key_rotate_second_message(&party2_first, &m1, &r1);
+        let random2 =
+            Rotation2::key_rotate_second_message(&party1_second, &party2_first, &party1_first);
+        let (rotation_message, rotated1) = master_key1.rotation_first_message(&random1);
+        let rotated2 = master_key2
+            .rotate_first_message(&random2, &rotation_message)
+            .expect("party 2 accepts the proofs of the new Paillier key");
+
+        // Both shares moved, the wallet did not.
+        assert_eq!(rotated1.public.q, master_key1.public.q);
+        assert_eq!(rotated2.public.q, master_key1.public.q);
+        assert!(rotated1.public.p2 != master_key1.public.p2);
+        assert_eq!(rotated2.public.p2, rotated1.public.p2);
+
+        // Party 2 proves its new share, a proof of the old one is refused.
+        let new_share = party2_share(&rotated2);
+        ecdsa::expect_party2_share(&rotated1.public.p2, &DLogProof::<GE>::prove(&new_share))
+            .unwrap();
+        assert_eq!(
+            http_status(
+                ecdsa::expect_party2_share(
+                    &rotated1.public.p2,
+                    &DLogProof::<GE>::prove(&old_share)
+                )
+                .unwrap_err()
+            ),
+            Status::BadRequest
+        );
+
+        // The rotated shares still sign for the same key.
+        let message = BigInt::from(1234);
+        let (party2_eph_first, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        let (party1_eph_first, eph_ec_key_pair_party1) = MasterKey1::sign_first_message();
+        let party2_sign_message = rotated2.sign_second_message(
+            &eph_ec_key_pair_party2,
+            eph_comm_witness,
+            &party1_eph_first,
+            &message,
+        );
+        rotated1
+            .sign_second_message(
+                &party2_sign_message,
+                &party2_eph_first,
+                &eph_ec_key_pair_party1,
+                &message,
+            )
+            .expect("a signature valid under the unchanged public key");
+    }
+
+    #[test]
+    fn keygen_proofs_reject_a_wrong_c_key() {
+        let (kg_party_one_first_message, comm_witness, ec_key_pair_party1) =
+            MasterKey1::key_gen_first_message();
+        let (kg_party_two_first_message, _) = MasterKey2::key_gen_first_message();
+        let (kg_party_one_second_message, _, _) = MasterKey1::key_gen_second_message(
+            comm_witness,
+            &ec_key_pair_party1,
+            &kg_party_two_first_message.d_log_proof,
+        );
+        assert!(MasterKey2::key_gen_second_message(
+            &kg_party_one_first_message,
+            &kg_party_one_second_message,
+            SALT_STRING,
+        )
+        .is_ok());
+
+        // A malicious party 1 sends Enc(x1 + 1) = Enc(x1) * (1 + N), with or
+        // without restating it in the PDL statement.
+        let body = serde_json::to_string(&kg_party_one_second_message).unwrap();
+        let mut forged: party1::KeyGenParty1Message2 = serde_json::from_str(&body).unwrap();
+        let one_plus_n = forged.ek.n.clone() + BigInt::from(1);
+        forged.c_key = BigInt::mod_mul(&forged.c_key, &one_plus_n, &forged.ek.nn);
+        assert!(MasterKey2::key_gen_second_message(
+            &kg_party_one_first_message,
+            &forged,
+            SALT_STRING,
+        )
+        .is_err());
+        forged.pdl_statement.ciphertext = forged.c_key.clone();
+        assert!(MasterKey2::key_gen_second_message(
+            &kg_party_one_first_message,
+            &forged,
+            SALT_STRING,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn pdl_rounds_catch_a_c_key_encrypting_the_wrong_value() {
+        let (_, comm_witness, ec_key_pair_party1) = MasterKey1::key_gen_first_message();
+        let (kg_party_two_first_message, _) = MasterKey2::key_gen_first_message();
+        let (kg_party_one_second_message, paillier_key_pair, _) =
+            MasterKey1::key_gen_second_message(
+                comm_witness,
+                &ec_key_pair_party1,
+                &kg_party_two_first_message.d_log_proof,
+            );
+        let prover = pdl::PDLProver::new(&ec_key_pair_party1, &paillier_key_pair).unwrap();
+        let n = &kg_party_one_second_message.ek.n;
+        let q1 = &kg_party_one_second_message
+            .ecdh_second_message
+            .comm_witness
+            .public_share;
+
+        let verifier = pdl::PDLVerifier::new(n, &kg_party_one_second_message.c_key, q1);
+        let challenge = verifier.first_message();
+        let (commitment, decommit, alpha) = prover.first_message(&challenge);
+        let decommit = prover
+            .second_message(&challenge, &verifier.second_message(), &alpha, decommit)
+            .unwrap();
+        assert_eq!(verifier.verify(&commitment, &decommit), Ok(()));
+
+        // Party 1 only opens Q^ for the a and b party 2 committed to.
+        let (_, decommit, alpha) = prover.first_message(&challenge);
+        let mut opening = verifier.second_message();
+        opening.a = opening.a + BigInt::from(1);
+        assert_eq!(
+            prover.second_message(&challenge, &opening, &alpha, decommit),
+            Err(pdl::PDLError::ChallengeMismatch)
+        );
+
+        // A malicious server sends c_key = Enc(x1 + 1) = Enc(x1) * (1 + N).
+        let one_plus_n = n.clone() + BigInt::from(1);
+        let forged_c_key = BigInt::mod_mul(
+            &kg_party_one_second_message.c_key,
+            &one_plus_n,
+            &kg_party_one_second_message.ek.nn,
+        );
+        let verifier = pdl::PDLVerifier::new(n, &forged_c_key, q1);
+        let challenge = verifier.first_message();
+        let (commitment, decommit, alpha) = prover.first_message(&challenge);
+        assert_eq!(
+            prover.second_message(
+                &challenge,
+                &verifier.second_message(),
+                &alpha,
+                decommit.clone()
+            ),
+            Err(pdl::PDLError::AlphaMismatch)
+        );
+        // Opening Q^ regardless does not get past party 2, nor does opening
+        // the Q' party 2 expects, which party 1 did not commit to.
+        assert_eq!(
+            verifier.verify(&commitment, &decommit),
+            Err(pdl::PDLError::QHatMismatch)
+        );
+        let fe = |n: &BigInt| -> FE { ECScalar::from(&BigInt::modulus(n, &Secp256k1Scalar::q())) };
+        let opening = verifier.second_message();
+        let expected = pdl::PDLDecommit {
+            q_hat: q1.scalar_mul(&fe(&opening.a).get_element()).add_point(
+                &GE::generator()
+                    .scalar_mul(&fe(&opening.b).get_element())
+                    .get_element(),
+            ),
+            blindness: decommit.blindness,
+        };
+        assert_eq!(
+            verifier.verify(&commitment, &expected),
+            Err(pdl::PDLError::CommitmentMismatch)
+        );
+    }
+
+    #[rocket::async_test]
+    async fn failed_pdl_checks_abort_keygen() {
+        let client = local_client(local_auth_config("sub")).await;
+        let token = user_token("user-1", &[]);
+        let response = client
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        let (id, _): (String, party_one::KeyGenFirstMsg) =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        let post = |path: String, body: String| {
+            client
+                .post(path)
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .body(body)
+                .dispatch()
+        };
+
+        let (party2_first, _) = MasterKey2::key_gen_first_message();
+        let response = post(
+            format!("/ecdsa/keygen/{}/second", id),
+            serde_json::to_string(&party2_first.d_log_proof).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Ok);
+        let party1_second: party1::KeyGenParty1Message2 =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        let verifier = pdl::PDLVerifier::new(
+            &party1_second.ek.n,
+            &party1_second.c_key,
+            &party1_second.ecdh_second_message.comm_witness.public_share,
+        );
+
+        let fourth = format!("/ecdsa/keygen/{}/fourth", id);
+        let opening = serde_json::to_string(&verifier.second_message()).unwrap();
+        let response = post(fourth.clone(), opening.clone()).await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        let response = post(
+            format!("/ecdsa/keygen/{}/third", id),
+            serde_json::to_string(&verifier.first_message()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let mut wrong = verifier.second_message();
+        wrong.b = wrong.b + BigInt::from(1);
+        let response = post(fourth.clone(), serde_json::to_string(&wrong).unwrap()).await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // The PDL state is gone, keygen has to start over.
+        let response = post(fourth, opening).await;
+        assert_eq!(response.status(), Status::Conflict);
+        let response = post(
+            format!("/ecdsa/keygen/{}/chaincode/first", id),
+            String::new(),
+        )
+        .await;
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[rocket::async_test]
+    async fn ephemeral_key_is_taken_once_with_its_spent_marker() {
+        let storage = Arc::new(FailingStorage::default());
+        db::insert(
+            storage.as_ref(),
+            "user",
+            "id_session",
+            &ecdsa::EcdsaStruct::EphEcKeyPair,
+            &BigInt::from(7),
+        )
+        .await
+        .unwrap();
+        let spent_marker = || {
+            let mut batch = db::Batch::new();
+            batch
+                .insert(
+                    "user",
+                    "id_session",
+                    &ecdsa::EcdsaStruct::EphConsumed,
+                    &1u64,
+                )
+                .unwrap();
+            batch
+        };
+        let spent = |storage: Arc<FailingStorage>| async move {
+            let consumed_at: Option<u64> = db::get(
+                storage.as_ref(),
+                "user",
+                "id_session",
+                &ecdsa::EcdsaStruct::EphConsumed,
+            )
+            .await
+            .unwrap();
+            consumed_at.is_some()
+        };
+
+        // A take whose marker cannot be written leaves the key unspent.
+        storage.fail_at(3);
+        assert!(db::take_with::<BigInt>(
+            storage.as_ref(),
+            "user",
+            "id_session",
+            &ecdsa::EcdsaStruct::EphEcKeyPair,
+            spent_marker(),
+        )
+        .await
+        .is_err());
+        assert!(!spent(storage.clone()).await);
+
+        let takes = futures::future::join_all((0..8).map(|_| {
+            let storage = storage.clone();
+            async move {
+                db::take_with::<BigInt>(
+                    storage.as_ref(),
+                    "user",
+                    "id_session",
+                    &ecdsa::EcdsaStruct::EphEcKeyPair,
+                    spent_marker(),
+                )
+                .await
+                .unwrap()
+            }
+        }))
+        .await;
+
+        assert_eq!(takes.iter().filter(|t| t.is_some()).count(), 1);
+        assert!(spent(storage.clone()).await);
+    }
+
+    #[rocket::async_test]
+    async fn sweep_purges_only_expired_protocol_state() {
+        let storage = MemoryStorage::new();
+        let mut batch = db::Batch::new();
+        batch
+            .insert(
+                "user",
+                "id",
+                &ecdsa::EcdsaStruct::EcKeyPair,
+                &BigInt::from(1),
+            )
+            .unwrap();
+        batch
+            .insert(
+                "user",
+                "id",
+                &ecdsa::EcdsaStruct::Party1MasterKey,
+                &BigInt::from(2),
+            )
+            .unwrap();
+        db::insert_many(&storage, batch).await.unwrap();
+
+        assert_eq!(
+            gc::sweep(&storage, gc::now()).await.unwrap(),
+            gc::Sweep {
+                purged: 0,
+                tracked: 1
+            }
+        );
+        let later = gc::now() + gc::PROTOCOL_STATE_TTL.as_secs() + 1;
+        assert_eq!(
+            gc::sweep(&storage, later).await.unwrap(),
+            gc::Sweep {
+                purged: 1,
+                tracked: 0
+            }
+        );
+
+        let transient: Option<BigInt> =
+            db::get(&storage, "user", "id", &ecdsa::EcdsaStruct::EcKeyPair)
+                .await
+                .unwrap();
+        let master_key: Option<BigInt> =
+            db::get(&storage, "user", "id", &ecdsa::EcdsaStruct::Party1MasterKey)
+                .await
+                .unwrap();
+        assert!(transient.is_none());
+        assert_eq!(master_key, Some(BigInt::from(2)));
+    }
+
+    /// Backend on which a writer renews `record` right after every scan, as
+    /// a protocol step running while the sweeper works through its list.
+    struct RenewedAfterScan {
+        inner: MemoryStorage,
+        record: ecdsa::EcdsaStruct,
+    }
+
+    #[rocket::async_trait]
+    impl Storage for RenewedAfterScan {
+        async fn get(&self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.get(key).await
+        }
+
+        async fn put(&self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+            self.inner.put(key, value).await
+        }
+
+        async fn delete(&self, key: &[u8]) -> anyhow::Result<()> {
+            self.inner.delete(key).await
+        }
+
+        async fn take(&self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.take(key).await
+        }
+
+        async fn scan(&self, prefix: &[u8]) -> anyhow::Result<Vec<KeyValue>> {
+            let entries = self.inner.scan(prefix).await?;
+            db::insert(&self.inner, "user", "id", &self.record, &BigInt::from(3)).await?;
+            Ok(entries)
+        }
+
+        async fn batch(&self, ops: Vec<BatchOp>) -> anyhow::Result<()> {
+            self.inner.batch(ops).await
+        }
+
+        async fn batch_if(
+            &self,
+            expected: Vec<ExpectedValue>,
+            ops: Vec<BatchOp>,
+        ) -> anyhow::Result<bool> {
+            self.inner.batch_if(expected, ops).await
+        }
+    }
+
+    #[rocket::async_test]
+    async fn sweep_keeps_records_renewed_since_its_scan() {
+        let storage = RenewedAfterScan {
+            inner: MemoryStorage::new(),
+            record: ecdsa::EcdsaStruct::EcKeyPair,
+        };
+        let mut batch = db::Batch::new();
+        batch
+            .insert(
+                "user",
+                "id",
+                &ecdsa::EcdsaStruct::EcKeyPair,
+                &BigInt::from(1),
+            )
+            .unwrap();
+        batch.expire(
+            "user",
+            "id",
+            &ecdsa::EcdsaStruct::EcKeyPair,
+            Some(std::time::Duration::from_secs(0)),
+        );
+        db::insert_many(&storage.inner, batch).await.unwrap();
+
+        let sweep = gc::sweep(&storage, gc::now() + 1).await.unwrap();
+        assert_eq!(
+            sweep,
+            gc::Sweep {
+                purged: 0,
+                tracked: 1
+            }
+        );
+        let renewed: Option<BigInt> =
+            db::get(&storage, "user", "id", &ecdsa::EcdsaStruct::EcKeyPair)
+                .await
+                .unwrap();
+        assert_eq!(renewed, Some(BigInt::from(3)));
+    }
+
+    const TEST_ISSUER: &str = "https://issuer.test";
+    const TEST_AUDIENCE: &str = "newyork-server";
+
+    fn test_verifier() -> JwtVerifier {
+        JwtVerifier::new(
+            JwksSource::File(format!(
+                "{}/src/auth/testdata/jwks.json",
+                env!("CARGO_MANIFEST_DIR")
+            )),
+            TEST_ISSUER.to_string(),
+            TEST_AUDIENCE.to_string(),
+        )
+    }
+
+    fn test_token(alg: Algorithm, kid: &str, iss: &str, aud: &str, exp: u64) -> String {
+        let claims = json!({ "sub": "user-1", "iss": iss, "aud": aud, "exp": exp });
+        sign_claims(alg, kid, &claims)
+    }
+
+    fn sign_claims(alg: Algorithm, kid: &str, claims: &serde_json::Value) -> String {
+        let mut header = JwtHeader::new(alg);
+        header.kid = Some(kid.to_string());
+        let key = match alg {
+            Algorithm::RS256 => {
+                EncodingKey::from_rsa_pem(include_bytes!("auth/testdata/rs256.pem")).unwrap()
+            }
+            Algorithm::ES256 => {
+                EncodingKey::from_ec_pem(include_bytes!("auth/testdata/es256.pem")).unwrap()
+            }
+            _ => EncodingKey::from_secret(b"secret"),
+        };
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn jwt_verified_locally_against_jwks() {
+        let verifier = test_verifier();
+        let exp = get_current_timestamp() + 600;
+
+        for (alg, kid) in [
+            (Algorithm::RS256, "test-rs256"),
+            (Algorithm::ES256, "test-es256"),
+        ] {
+            let token = test_token(alg, kid, TEST_ISSUER, TEST_AUDIENCE, exp);
+            let claims: Claims = verifier.verify(&token).await.unwrap();
+            assert_eq!(claims.sub, "user-1");
+        }
+
+        let rejected = [
+            // expired
+            test_token(
+                Algorithm::RS256,
+                "test-rs256",
+                TEST_ISSUER,
+                TEST_AUDIENCE,
+                1000,
+            ),
+            test_token(
+                Algorithm::RS256,
+                "test-rs256",
+                "https://other",
+                TEST_AUDIENCE,
+                exp,
+            ),
+            test_token(Algorithm::RS256, "test-rs256", TEST_ISSUER, "other", exp),
+            test_token(Algorithm::RS256, "unknown", TEST_ISSUER, TEST_AUDIENCE, exp),
+            // ES256 token claiming the RSA key
+            test_token(
+                Algorithm::ES256,
+                "test-rs256",
+                TEST_ISSUER,
+                TEST_AUDIENCE,
+                exp,
+            ),
+            test_token(
+                Algorithm::HS256,
+                "test-rs256",
+                TEST_ISSUER,
+                TEST_AUDIENCE,
+                exp,
+            ),
+            "a".to_string(),
+        ];
+        for token in rejected.iter() {
+            let err = verifier.verify(token).await.unwrap_err();
+            assert!(!err.is::<JwksUnavailable>(), "{:#}", err);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn missing_jwks_is_reported_as_unavailable() {
+        let verifier = JwtVerifier::new(
+            JwksSource::File("/nonexistent/jwks.json".to_string()),
+            TEST_ISSUER.to_string(),
+            TEST_AUDIENCE.to_string(),
+        );
+        let token = test_token(
+            Algorithm::RS256,
+            "test-rs256",
+            TEST_ISSUER,
+            TEST_AUDIENCE,
+            get_current_timestamp() + 600,
+        );
+        assert!(verifier
+            .verify(&token)
+            .await
+            .unwrap_err()
+            .is::<JwksUnavailable>());
+    }
+
+    fn local_auth_config(user_claim: &str) -> AppConfig {
+        AppConfig {
+            db: Arc::new(MemoryStorage::new()),
+            hcmc_api: String::new(),
+            auth: AuthMode::Local(Arc::new(test_verifier())),
+            user_claim: user_claim.to_string(),
+            role_claim: "groups".to_string(),
+            approver_role: "approver".to_string(),
+            policy_admin_role: "policy_admin".to_string(),
+            alchemy_api: String::new(),
+            gc_stats: Arc::new(gc::GcStats::default()),
+            session_locks: KeyedLocks::new(),
+            hd_locks: KeyedLocks::new(),
+            policy_locks: KeyedLocks::new(),
+            rotation_locks: KeyedLocks::new(),
+        }
+    }
+
+    /// A valid token of `user_id` in the given groups, for `local_auth_config`.
+    fn user_token(user_id: &str, groups: &[&str]) -> String {
+        let claims = json!({
+            "sub": user_id,
+            "groups": groups,
+            "iss": TEST_ISSUER,
+            "aud": TEST_AUDIENCE,
+            "exp": get_current_timestamp() + 600,
+        });
+        sign_claims(Algorithm::RS256, "test-rs256", &claims)
+    }
+
+    fn bearer(token: &str) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", token))
+    }
+
+    async fn local_client(config: AppConfig) -> AsyncClient {
+        AsyncClient::tracked(rocket::build().mount("/", server::routes()).manage(config))
+            .await
+            .expect("valid rocket instance")
+    }
+
+    async fn wallets_status(client: &AsyncClient, token: &str, user_id: Option<&str>) -> Status {
+        let mut request = client.get("/ecdsa/wallets").header(bearer(token));
+        if let Some(user_id) = user_id {
+            request = request.header(Header::new("user_id", user_id.to_string()));
+        }
+        request.dispatch().await.status()
+    }
+
+    #[rocket::async_test]
+    async fn user_id_is_bound_to_the_token() {
+        let client = local_client(local_auth_config("sub")).await;
+        let token = user_token("user-1", &[]);
+
+        assert_eq!(
+            wallets_status(&client, &token, Some("user-1")).await,
+            Status::Ok
+        );
+        assert_eq!(wallets_status(&client, &token, None).await, Status::Ok);
+        // Another user's namespace cannot be reached by changing the header.
+        assert_eq!(
+            wallets_status(&client, &token, Some("user-2")).await,
+            Status::Forbidden
+        );
+        assert_eq!(
+            wallets_status(&client, "a", Some("user-1")).await,
+            Status::Unauthorized
+        );
+
+        // Tokens without the configured identity claim identify nobody.
+        let client = local_client(local_auth_config("email")).await;
+        assert_eq!(
+            wallets_status(&client, &token, Some("user-1")).await,
+            Status::Unauthorized
+        );
+    }
+
+    #[rocket::async_test]
+    async fn wallet_index_is_per_user() {
+        let storage = MemoryStorage::new();
+        let mut batch = db::Batch::new();
+        batch.index_wallet("alice", "wallet-1", 10, None);
+        batch.index_wallet("alice", "wallet-2", 20, None);
+        batch.index_wallet("alice_x", "wallet-3", 30, None);
+        db::insert_many(&storage, batch).await.unwrap();
+
+        assert_eq!(
+            db::wallets(&storage, "alice").await.unwrap(),
+            vec![("wallet-1".to_string(), 10), ("wallet-2".to_string(), 20)]
+        );
+        assert_eq!(
+            db::wallet_created_at(&storage, "alice", "wallet-3")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[rocket::async_test]
+    async fn legacy_wallets_register_the_children_they_use() {
+        let config = local_auth_config("sub");
+        let storage = config.db.clone();
+        let (master_key, _) = local_master_keys();
+        for id in ["legacy", "current"] {
+            db::insert(
+                storage.as_ref(),
+                "user-1",
+                id,
+                &ecdsa::EcdsaStruct::Party1MasterKey,
+                &master_key,
+            )
+            .await
+            .unwrap();
+            db::insert(
+                storage.as_ref(),
+                "user-1",
+                id,
+                &ecdsa::EcdsaStruct::POS,
+                json!({ "pos": 0 }),
+            )
+            .await
+            .unwrap();
+        }
+        db::insert(
+            storage.as_ref(),
+            "user-1",
+            "current",
+            &ecdsa::EcdsaStruct::ChildrenTracked,
+            gc::now(),
+        )
+        .await
+        .unwrap();
+        let client = local_client(config).await;
+        let token = user_token("user-1", &[]);
+        let post = |path: String| {
+            client
+                .post(path)
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .dispatch()
+        };
+
+        // A path the wallet used before children were tracked.
+        let response = post("/ecdsa/legacy/children/0/21".to_string()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let child: ecdsa::HDChild = response.into_json().await.unwrap();
+        let child_key = master_key.get_child(vec![BigInt::from(0), BigInt::from(21)]);
+        assert_eq!((child.x, child.y), (0, 21));
+        assert_eq!(child.address, address::eth_address(&child_key.public.q));
+        assert_eq!(
+            post("/ecdsa/legacy/children/0/21".to_string())
+                .await
+                .status(),
+            Status::Conflict
+        );
+
+        // Allocation continues after the registered path.
+        let response = post("/ecdsa/legacy/children".to_string()).await;
+        let allocated: ecdsa::HDChild = response.into_json().await.unwrap();
+        assert_eq!((allocated.x, allocated.y), (0, 22));
+        let children: Vec<ecdsa::HDChild> = db::get(
+            storage.as_ref(),
+            "user-1",
+            "legacy",
+            &ecdsa::EcdsaStruct::Children,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(children, vec![child, allocated]);
+
+        // Wallets created since then only get allocated children.
+        assert_eq!(
+            post("/ecdsa/current/children/0/21".to_string())
+                .await
+                .status(),
+            Status::Forbidden
+        );
+    }
+
+    #[rocket::async_test]
+    async fn legacy_string_keys_are_migrated() {
+        let storage = MemoryStorage::new();
+        let id = uuid::Uuid::new_v4().to_string();
+        let sign_id = format!("{}_{}", id, uuid::Uuid::new_v4());
+        let legacy = |id: &str, name: &str| format!("bob_smith@example.com_{}_{}", id, name);
+        storage
+            .put(legacy(&id, "Party1MasterKey").as_bytes(), b"1")
+            .await
+            .unwrap();
+        storage
+            .put(legacy(&id, "EcKeyPair ").as_bytes(), b"2")
+            .await
+            .unwrap();
+        storage
+            .put(legacy(&sign_id, "EphEcKeyPair").as_bytes(), b"3")
+            .await
+            .unwrap();
+        storage
+            .put(legacy(&id, "Policy").as_bytes(), b"5")
+            .await
+            .unwrap();
+        storage
+            .put(legacy(&id, "UnsignedTx").as_bytes(), b"6")
+            .await
+            .unwrap();
+        storage.put(b"unrelated", b"4").await.unwrap();
+
+        let master_key = ecdsa::EcdsaStruct::Party1MasterKey;
+        assert_eq!(schema::version(&storage).await.unwrap(), 1);
+        assert_eq!(
+            schema::migrate(&storage, &server::record_class, &master_key)
+                .await
+                .unwrap(),
+            5
+        );
+        assert_eq!(
+            schema::version(&storage).await.unwrap(),
+            keys::SCHEMA_VERSION
+        );
+
+        let user = "bob_smith@example.com";
+        let get = |id: String, name: ecdsa::EcdsaStruct| {
+            let storage = &storage;
+            async move { db::get::<u32>(storage, user, &id, &name).await.unwrap() }
+        };
+        assert_eq!(
+            get(id.clone(), ecdsa::EcdsaStruct::Party1MasterKey).await,
+            Some(1)
+        );
+        assert_eq!(
+            get(id.clone(), ecdsa::EcdsaStruct::EcKeyPair).await,
+            Some(2)
+        );
+        assert_eq!(
+            get(sign_id, ecdsa::EcdsaStruct::EphEcKeyPair).await,
+            Some(3)
+        );
+        let policy: Option<u32> = db::get(&storage, user, &id, &PolicyStruct::Policy)
+            .await
+            .unwrap();
+        assert_eq!(policy, Some(5));
+        assert!(storage
+            .get(&keys::record(keys::KeyClass::Policy, user, &id, "Policy"))
+            .await
+            .unwrap()
+            .is_some());
+        let unsigned_tx: Option<u32> = db::get(&storage, user, &id, &eth::EthStruct::UnsignedTx)
+            .await
+            .unwrap();
+        assert_eq!(unsigned_tx, Some(6));
+        assert_eq!(db::wallets(&storage, user).await.unwrap(), vec![(id, 0)]);
+
+        // Only keys the migration does not know stay in the legacy layout.
+        let left: Vec<Vec<u8>> = storage
+            .scan(&[])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| keys::KeyClass::of(key).is_none())
+            .collect();
+        assert_eq!(left, vec![b"unrelated".to_vec()]);
+
+        assert_eq!(
+            schema::migrate(&storage, &server::record_class, &master_key)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn keys_are_unambiguous() {
+        assert_ne!(
+            keys::record(keys::KeyClass::MasterKey, "a_b", "c", "POS"),
+            keys::record(keys::KeyClass::MasterKey, "a", "b_c", "POS")
+        );
+        let key = keys::record(keys::KeyClass::Protocol, "user", "id", "EcKeyPair");
+        let (class, parts) = keys::decode(&key).unwrap();
+        assert_eq!(class, keys::KeyClass::Protocol);
+        assert_eq!(parts, vec![&b"user"[..], b"id", b"EcKeyPair"]);
+    }
+
+    #[test]
+    fn addresses_of_the_generator_point() {
+        let g = GE::generator();
+        assert_eq!(
+            address::compressed_hex(&g),
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert!(address::uncompressed_hex(&g).starts_with("0479be667ef9dcbb"));
+        assert_eq!(
+            format!("{:?}", address::eth_address(&g)),
+            "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+        // BIP-173 test vectors.
+        assert_eq!(
+            address::p2wpkh_address(&g, "bc").unwrap(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert_eq!(
+            address::p2wpkh_address(&g, "tb").unwrap(),
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+        );
+    }
+
+    /// Plain ECDSA with private key 1, so `Q` is the generator. `s` is not
+    /// normalised.
+    fn sign_with_key_one(message: &BigInt) -> party_one::SignatureRecid {
+        let q = Secp256k1Scalar::q();
+        let k: FE = ECScalar::new_random();
+        let big_r = GE::generator().scalar_mul(&k.get_element());
+        let r = BigInt::modulus(&big_r.x_coor().unwrap(), &q);
+        let s = BigInt::mod_mul(
+            &k.invert().to_big_int(),
+            &BigInt::mod_add(message, &r, &q),
+            &q,
+        );
+        party_one::SignatureRecid {
+            r,
+            s,
+            recid: big_r.pk_to_key_slice()[64] & 1,
+        }
+    }
+
+    #[test]
+    fn signatures_are_low_s_and_recoverable() {
+        let q = Secp256k1Scalar::q();
+        let g = GE::generator();
+        let message = BigInt::from(1234);
+        let party_one::SignatureRecid { r, s, recid } = sign_with_key_one(&message);
+        let negated_s = BigInt::mod_sub(&q, &s, &q);
+        let (low_s, high_s, low_recid) = if s < negated_s {
+            (s, negated_s, recid)
+        } else {
+            (negated_s, s, recid ^ 1)
+        };
+
+        let high = party_one::SignatureRecid {
+            r: r.clone(),
+            s: high_s,
+            recid: low_recid ^ 1,
+        };
+        let signature = signature::finalize(&g, &message, high).unwrap();
+        assert_eq!(signature.s, low_s);
+        assert_eq!(signature.recid, low_recid);
+
+        let rsv = signature::to_rsv(&signature).unwrap();
+        assert_eq!(&rsv[..32], &signature::to_32_bytes(&r).unwrap()[..]);
+        assert_eq!(&rsv[32..64], &signature::to_32_bytes(&low_s).unwrap()[..]);
+        assert_eq!(rsv[64], low_recid);
+
+        let wrong_recid = party_one::SignatureRecid {
+            r: r.clone(),
+            s: low_s.clone(),
+            recid: low_recid ^ 1,
+        };
+        assert!(signature::finalize(&g, &message, wrong_recid).is_err());
+        let wrong_message = party_one::SignatureRecid {
+            r,
+            s: low_s,
+            recid: low_recid,
+        };
+        assert!(signature::finalize(&g, &BigInt::from(5678), wrong_message).is_err());
+    }
+
+    #[test]
+    fn eth_amounts_are_parsed_exactly() {
+        let wei = |value: &str, unit| amount::parse_units(value, unit);
+        assert_eq!(wei("1", EthUnit::Ether), Ok(U256::exp10(18)));
+        assert_eq!(wei("0.1", EthUnit::Ether), Ok(U256::exp10(17)));
+        assert_eq!(wei("0.000000000000000001", EthUnit::Ether), Ok(U256::one()));
+        assert_eq!(wei("1.5", EthUnit::Gwei), Ok(U256::from(1_500_000_000u64)));
+        assert_eq!(wei("007", EthUnit::Wei), Ok(U256::from(7)));
+        assert_eq!(
+            wei("1.50000000000000000000", EthUnit::Ether),
+            Ok(U256::from(15) * U256::exp10(17))
+        );
+        assert_eq!(wei(&U256::MAX.to_string(), EthUnit::Wei), Ok(U256::MAX));
+
+        assert_eq!(wei("", EthUnit::Ether), Err(AmountError::Empty));
+        assert_eq!(wei("-1", EthUnit::Ether), Err(AmountError::Negative));
+        for invalid in ["1e18", "1.", ".5", "0x10", " 1", "+1", "1,5", "1.2.3"] {
+            assert_eq!(
+                wei(invalid, EthUnit::Ether),
+                Err(AmountError::Invalid(invalid.to_string())),
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(
+            wei("1.0000000000000000001", EthUnit::Ether),
+            Err(AmountError::TooManyDecimals {
+                unit: EthUnit::Ether,
+                given: 19
+            })
+        );
+        assert_eq!(
+            wei("0.5", EthUnit::Wei),
+            Err(AmountError::TooManyDecimals {
+                unit: EthUnit::Wei,
+                given: 1
+            })
+        );
+        let too_big = format!("{}0", U256::MAX);
+        assert_eq!(wei(&too_big, EthUnit::Wei), Err(AmountError::Overflow));
+        let max_ether = U256::MAX / EthUnit::Ether.wei();
+        assert_eq!(
+            wei(&(max_ether + 1).to_string(), EthUnit::Ether),
+            Err(AmountError::Overflow)
+        );
+
+        let amount = |body: serde_json::Value| serde_json::from_value::<EthAmount>(body);
+        assert_eq!(
+            amount(json!({"value": 5, "unit": "gwei"}))
+                .unwrap()
+                .to_wei(),
+            Ok(U256::from(5_000_000_000u64))
+        );
+        assert_eq!(
+            amount(json!({"value": "0.25", "unit": "ether"}))
+                .unwrap()
+                .to_wei(),
+            Ok(U256::from(25) * U256::exp10(16))
+        );
+        assert_eq!(
+            amount(json!({"value": -1, "unit": "wei"}))
+                .unwrap()
+                .to_wei(),
+            Err(AmountError::Negative)
+        );
+        assert_eq!(
+            amount(json!({"value": 0.1, "unit": "ether"}))
+                .unwrap()
+                .to_wei(),
+            Err(AmountError::NotAnInteger("0.1".to_string()))
+        );
+        assert!(amount(json!({"value": "1", "unit": "finney"})).is_err());
+        assert!(amount(json!({"value": "1"})).is_err());
+
+        let body: eth::EthTxParamsReqBody = serde_json::from_value(json!({
+            "from_address": "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+            "to_address": "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+            "value": "0.015",
+            "unit": "ether",
+        }))
+        .unwrap();
+        assert_eq!(body.amount.to_wei(), Ok(U256::from(15) * U256::exp10(15)));
+    }
+
+    #[test]
+    fn fee_tiers_follow_the_fee_history() {
+        let gwei = |n: u64| U256::from(n) * U256::exp10(9);
+        // Four sampled blocks, the third one empty; the last base fee is the
+        // next block's.
+        let base_fee_per_gas = [gwei(10), gwei(11), gwei(12), gwei(11), gwei(12)];
+        let gas_used_ratio = [0.5, 0.9, 0.0, 0.6];
+        let reward = vec![
+            vec![gwei(1), gwei(2), gwei(3)],
+            vec![gwei(1), gwei(3), gwei(5)],
+            vec![U256::zero(), U256::zero(), U256::zero()],
+            vec![gwei(2), gwei(2), gwei(8)],
+        ];
+        let tiers = fees::eip1559_tiers(&base_fee_per_gas, &gas_used_ratio, &reward).unwrap();
+        assert_eq!(tiers.slow.max_priority_fee_per_gas, gwei(1));
+        assert_eq!(tiers.normal.max_priority_fee_per_gas, gwei(2));
+        assert_eq!(tiers.fast.max_priority_fee_per_gas, gwei(5));
+        assert_eq!(tiers.normal.max_fee_per_gas, gwei(24) + gwei(2));
+        for speed in FeeSpeed::ALL {
+            let tier = tiers.get(speed);
+            assert!(tier.max_fee_per_gas >= tier.max_priority_fee_per_gas);
+        }
+
+        // Tiers never invert, and empty chains still tip something.
+        let inverted = vec![vec![gwei(4), gwei(2), gwei(1)]];
+        let tiers = fees::eip1559_tiers(&[gwei(1), gwei(1)], &[0.5], &inverted).unwrap();
+        assert_eq!(tiers.normal.max_priority_fee_per_gas, gwei(4));
+        assert_eq!(tiers.fast.max_priority_fee_per_gas, gwei(4));
+        let tiers = fees::eip1559_tiers(&[gwei(1), gwei(1)], &[0.0], &[vec![]]).unwrap();
+        assert_eq!(
+            tiers.slow.max_priority_fee_per_gas,
+            U256::from(fees::DEFAULT_PRIORITY_FEE)
+        );
+        assert!(fees::eip1559_tiers(&[], &[], &[]).is_none());
+        // A bogus fee history saturates instead of overflowing.
+        let tiers = fees::eip1559_tiers(&[U256::MAX, U256::MAX], &[0.5], &inverted).unwrap();
+        assert_eq!(tiers.fast.max_fee_per_gas, U256::MAX);
+
+        let tiers = fees::legacy_tiers(gwei(20));
+        assert_eq!(tiers.slow.max_fee_per_gas, gwei(18));
+        assert_eq!(tiers.normal.max_fee_per_gas, gwei(20));
+        assert_eq!(tiers.fast.max_fee_per_gas, gwei(25));
+        assert_eq!(tiers.fast.max_priority_fee_per_gas, gwei(25));
+
+        let body: eth::EthTxParamsReqBody = serde_json::from_value(json!({
+            "from_address": "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+            "to_address": "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+            "value": "1",
+            "unit": "gwei",
+            "speed": "fast",
+        }))
+        .unwrap();
+        assert_eq!(body.speed, FeeSpeed::Fast);
+    }
+
+    fn any_unit() -> impl Strategy<Value = EthUnit> {
+        prop::sample::select(EthUnit::ALL.to_vec())
+    }
+
+    proptest! {
+        #[test]
+        fn formatted_amounts_parse_back(
+            words in any::<[u64; 4]>(),
+            unit in any_unit(),
+        ) {
+            let wei = U256(words);
+            let formatted = amount::format_units(wei, unit);
+            prop_assert_eq!(amount::parse_units(&formatted, unit), Ok(wei));
+        }
+
+        #[test]
+        fn integers_scale_by_the_unit(value in any::<u64>(), unit in any_unit()) {
+            prop_assert_eq!(
+                amount::parse_units(&value.to_string(), unit),
+                Ok(U256::from(value) * unit.wei())
+            );
+        }
+
+        #[test]
+        fn sub_wei_digits_are_rejected(
+            value in any::<u64>(),
+            digit in 1u8..10,
+            unit in any_unit(),
+        ) {
+            let value = format!("{}.{}{}", value, "0".repeat(unit.decimals()), digit);
+            prop_assert_eq!(
+                amount::parse_units(&value, unit),
+                Err(AmountError::TooManyDecimals {
+                    unit,
+                    given: unit.decimals() + 1,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_transactions_match_eip155() {
+        // The example transaction of EIP-155.
+        let tx = UnsignedTx {
+            fee_strategy: FeeStrategy::Legacy,
+            chain_id: 1,
+            nonce: U256::from(9),
+            to: "0x3535353535353535353535353535353535353535"
+                .parse()
+                .unwrap(),
+            value: U256::exp10(18),
+            data: Bytes::default(),
+            gas: U256::from(21000),
+            max_fee_per_gas: U256::from(20_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(20_000_000_000u64),
+        };
+        assert_eq!(
+            hex::encode(tx.signing_payload()),
+            "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080"
+        );
+        assert_eq!(
+            format!("{:?}", tx.sighash()),
+            "0xdaf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+        );
+
+        let signature = party_one::SignatureRecid {
+            r: BigInt::from_bytes(
+                &hex::decode("28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276")
+                    .unwrap(),
+            ),
+            s: BigInt::from_bytes(
+                &hex::decode("67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83")
+                    .unwrap(),
+            ),
+            recid: 0,
+        };
+        assert_eq!(
+            format!(
+                "{:?}",
+                signature::recover_address(&tx.sighash().0, &signature).unwrap()
+            ),
+            "0x9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f"
+        );
+        assert_eq!(
+            hex::encode(tx.encode_signed(&signature).unwrap().0),
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        );
+    }
+
+    #[test]
+    fn eip1559_transactions_are_typed_and_signed_over_the_sighash() {
+        let tx = UnsignedTx {
+            fee_strategy: FeeStrategy::Eip1559,
+            chain_id: 5,
+            nonce: U256::from(3),
+            to: "0x3535353535353535353535353535353535353535"
+                .parse()
+                .unwrap(),
+            value: U256::exp10(15),
+            data: Bytes(vec![0xde, 0xad]),
+            gas: U256::from(21000),
+            max_fee_per_gas: U256::from(30_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(1_500_000_000u64),
+        };
+        let payload = tx.signing_payload();
+        assert_eq!(payload[0], transaction::EIP1559_TX_TYPE);
+        let unsigned = rlp::Rlp::new(&payload[1..]);
+        assert_eq!(unsigned.item_count().unwrap(), 9);
+
+        let message = BigInt::from_bytes(&tx.sighash().0);
+        let signature = signature::normalize_low_s(sign_with_key_one(&message));
+        let raw_tx = tx.encode_signed(&signature).unwrap();
+        assert_eq!(raw_tx.0[0], transaction::EIP1559_TX_TYPE);
+        let signed = rlp::Rlp::new(&raw_tx.0[1..]);
+        assert_eq!(signed.item_count().unwrap(), 12);
+        for i in 0..9 {
+            assert_eq!(
+                signed.at(i).unwrap().as_raw(),
+                unsigned.at(i).unwrap().as_raw()
+            );
+        }
+        assert_eq!(signed.val_at::<u8>(9).unwrap(), signature.recid);
+        assert_eq!(
+            signed.val_at::<U256>(11).unwrap(),
+            U256::from_big_endian(&signature::to_32_bytes(&signature.s).unwrap())
+        );
+        assert_eq!(
+            signature::recover_address(&tx.sighash().0, &signature).unwrap(),
+            address::eth_address(&GE::generator())
+        );
+        assert_eq!(
+            transaction::tx_hash(&raw_tx),
+            H256(web3::signing::keccak256(&raw_tx.0))
+        );
+    }
+
+    #[rocket::async_test]
+    async fn transactions_to_sign_are_resolved_per_user() {
+        let storage = MemoryStorage::new();
+        let tx = UnsignedTx {
+            fee_strategy: FeeStrategy::Legacy,
+            chain_id: 1,
+            nonce: U256::from(9),
+            to: "0x3535353535353535353535353535353535353535"
+                .parse()
+                .unwrap(),
+            value: U256::exp10(18),
+            data: Bytes::default(),
+            gas: U256::from(21000),
+            max_fee_per_gas: U256::from(20_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(20_000_000_000u64),
+        };
+        let built = eth::BuiltTx {
+            wallet_id: "wallet-1".to_string(),
+            x: 0,
+            y: 3,
+            tx: tx.clone(),
+        };
+        db::insert(
+            &storage,
+            "user-1",
+            "tx-1",
+            &eth::EthStruct::UnsignedTx,
+            &built,
+        )
+        .await
+        .unwrap();
+
+        let resolve = |user_id: &'static str, tx: Option<UnsignedTx>, tx_id: Option<&str>| {
+            let tx_id = tx_id.map(str::to_string);
+            let storage = &storage;
+            async move {
+                let path = (&BigInt::from(0), &BigInt::from(3));
+                eth::tx_to_sign(storage, user_id, "wallet-1", path, &tx, &tx_id).await
+            }
+        };
+        assert_eq!(resolve("user-1", None, None).await.unwrap(), None);
+        assert_eq!(
+            resolve("user-1", None, Some("tx-1")).await.unwrap(),
+            Some(tx.clone())
+        );
+        assert_eq!(
+            resolve("user-1", Some(tx.clone()), None).await.unwrap(),
+            Some(tx.clone())
+        );
+        let status = |result: anyhow::Result<Option<UnsignedTx>>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<HttpError>()
+                .expect("an HTTP error")
+                .status
+        };
+        // Another user's transactions are not visible.
+        assert_eq!(
+            status(resolve("user-2", None, Some("tx-1")).await),
+            Status::NotFound
+        );
+        assert_eq!(
+            status(resolve("user-1", Some(tx.clone()), Some("tx-1")).await),
+            Status::BadRequest
+        );
+        // Nor are they signed by another wallet or child of the same user.
+        let tx_id = Some("tx-1".to_string());
+        let other_wallet = (&BigInt::from(0), &BigInt::from(3));
+        assert_eq!(
+            status(
+                eth::tx_to_sign(&storage, "user-1", "wallet-2", other_wallet, &None, &tx_id).await
+            ),
+            Status::Forbidden
+        );
+        let other_child = (&BigInt::from(0), &BigInt::from(4));
+        assert_eq!(
+            status(
+                eth::tx_to_sign(&storage, "user-1", "wallet-1", other_child, &None, &tx_id).await
+            ),
+            Status::Forbidden
+        );
+    }
+
+    fn policy_test_tx(to: &str, value: u64, data: Vec<u8>) -> UnsignedTx {
+        UnsignedTx {
+            fee_strategy: FeeStrategy::Eip1559,
+            chain_id: 1,
+            nonce: U256::zero(),
+            to: to.parse().unwrap(),
+            value: U256::from(value),
+            data: Bytes(data),
+            gas: U256::from(10),
+            max_fee_per_gas: U256::from(100),
+            max_priority_fee_per_gas: U256::from(1),
+        }
+    }
+
+    #[test]
+    fn policy_rules_are_enforced() {
+        const ALICE: &str = "0x1111111111111111111111111111111111111111";
+        const BOB: &str = "0x2222222222222222222222222222222222222222";
+        // 2021-01-01 12:00 UTC.
+        let noon = 1_609_502_400;
+        let transfer = policy_test_tx(ALICE, 1000, vec![]);
+        assert_eq!(policy::max_cost(&transfer), U256::from(2000));
+
+        let open = Policy::default();
+        assert_eq!(
+            policy::evaluate(&open, None, &[], noon),
+            Err(Rejection::RawMessage)
+        );
+        let raw = Policy {
+            allow_raw_messages: true,
+            ..Policy::default()
+        };
+        assert_eq!(policy::evaluate(&raw, None, &[], noon), Ok(()));
+        assert_eq!(policy::evaluate(&open, Some(&transfer), &[], noon), Ok(()));
+
+        let recipients = Policy {
+            allowed_recipients: Some(vec![ALICE.parse().unwrap(), BOB.parse().unwrap()]),
+            denied_recipients: vec![BOB.parse().unwrap()],
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy::evaluate(&recipients, Some(&transfer), &[], noon),
+            Ok(())
+        );
+        assert_eq!(
+            policy::evaluate(
+                &recipients,
+                Some(&policy_test_tx(BOB, 1, vec![])),
+                &[],
+                noon
+            ),
+            Err(Rejection::RecipientDenied(BOB.parse().unwrap()))
+        );
+        let carol = "0x3333333333333333333333333333333333333333";
+        assert_eq!(
+            policy::evaluate(
+                &recipients,
+                Some(&policy_test_tx(carol, 1, vec![])),
+                &[],
+                noon
+            ),
+            Err(Rejection::RecipientNotAllowed(carol.parse().unwrap()))
+        );
+
+        let selectors = Policy {
+            allowed_selectors: Some(vec![Bytes(vec![0xa9, 0x05, 0x9c, 0xbb])]),
+            ..Policy::default()
+        };
+        let erc20_transfer = policy_test_tx(ALICE, 0, vec![0xa9, 0x05, 0x9c, 0xbb, 0, 1]);
+        let approve = policy_test_tx(ALICE, 0, vec![0x09, 0x5e, 0xa7, 0xb3, 0, 1]);
+        assert_eq!(
+            policy::evaluate(&selectors, Some(&transfer), &[], noon),
+            Ok(())
+        );
+        assert_eq!(
+            policy::evaluate(&selectors, Some(&erc20_transfer), &[], noon),
+            Ok(())
+        );
+        assert_eq!(
+            policy::evaluate(&selectors, Some(&approve), &[], noon),
+            Err(Rejection::SelectorNotAllowed("0x095ea7b3".to_string()))
+        );
+
+        let gas = Policy {
+            max_gas_price: Some(U256::from(99)),
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy::evaluate(&gas, Some(&transfer), &[], noon),
+            Err(Rejection::GasPriceTooHigh {
+                max: U256::from(99),
+                given: U256::from(100)
+            })
+        );
+
+        // 22:00 - 02:00 UTC wraps past midnight.
+        let night = Policy {
+            time_windows: vec![TimeWindow {
+                start_minute: 22 * 60,
+                end_minute: 2 * 60,
+            }],
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy::evaluate(&night, Some(&transfer), &[], noon),
+            Err(Rejection::OutsideTimeWindow { minute: 12 * 60 })
+        );
+        assert_eq!(
+            policy::evaluate(&night, Some(&transfer), &[], noon + 13 * 3600),
+            Ok(())
+        );
+
+        let limits = Policy {
+            daily_limit: Some(U256::from(5000)),
+            rolling_limit: Some(RollingLimit {
+                wei: U256::from(7000),
+                window_secs: 2 * 24 * 3600,
+            }),
+            ..Policy::default()
+        };
+        let spend = |at: u64, wei: u64| Spend {
+            at,
+            wei: U256::from(wei),
+            session_id: None,
+        };
+        // Spent yesterday only counts against the rolling limit.
+        let spent = [spend(noon - 86400, 4000), spend(noon - 60, 3000)];
+        assert_eq!(
+            policy::evaluate(&limits, Some(&transfer), &spent[1..], noon),
+            Ok(())
+        );
+        assert_eq!(
+            policy::evaluate(&limits, Some(&transfer), &[spend(noon - 60, 3001)], noon),
+            Err(Rejection::DailyLimit {
+                limit: U256::from(5000),
+                spent: U256::from(3001),
+                cost: U256::from(2000)
+            })
+        );
+        assert_eq!(
+            policy::evaluate(&limits, Some(&transfer), &spent, noon),
+            Err(Rejection::RollingLimit {
+                limit: U256::from(7000),
+                spent: U256::from(7000),
+                cost: U256::from(2000)
+            })
+        );
+
+        assert!(Policy {
+            allowed_selectors: Some(vec![Bytes(vec![1, 2, 3])]),
+            ..Policy::default()
+        }
+        .validate()
+        .is_err());
+        assert!(Policy {
+            time_windows: vec![TimeWindow {
+                start_minute: 60,
+                end_minute: 24 * 60,
+            }],
+            ..Policy::default()
+        }
+        .validate()
+        .is_err());
+        assert!(limits.validate().is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn policy_spends_are_booked_when_authorized() {
+        let config = local_auth_config("sub");
+        let tx = policy_test_tx("0x1111111111111111111111111111111111111111", 1000, vec![]);
+
+        // No policy, no limits.
+        policy::authorize(&config, "user", "id", "s0", None, false)
+            .await
+            .unwrap();
+
+        let limited = Policy {
+            daily_limit: Some(U256::from(5000)),
+            ..Policy::default()
+        };
+        db::insert(
+            config.db.as_ref(),
+            "user",
+            "id",
+            &PolicyStruct::Policy,
+            &limited,
+        )
+        .await
+        .unwrap();
+
+        policy::authorize(&config, "user", "id", "s1", Some(&tx), false)
+            .await
+            .unwrap();
+        policy::authorize(&config, "user", "id", "s2", Some(&tx), false)
+            .await
+            .unwrap();
+        let refused = policy::authorize(&config, "user", "id", "s3", Some(&tx), false).await;
+        let error = refused.unwrap_err();
+        let http_error = error.downcast_ref::<HttpError>().expect("an HTTP error");
+        assert_eq!(http_error.status, Status::Forbidden);
+        assert!(http_error.message.contains("daily limit"));
+
+        let storage = config.db.as_ref();
+        let spent = || async move {
+            let spent: Vec<Spend> = db::get(storage, "user", "id", &PolicyStruct::Spent)
+                .await
+                .unwrap()
+                .unwrap();
+            spent
+        };
+        assert_eq!(spent().await.len(), 2);
+        // Authorizing a session again replaces its booking.
+        policy::authorize(&config, "user", "id", "s2", Some(&tx), false)
+            .await
+            .unwrap();
+        assert_eq!(spent().await.len(), 2);
+        // A signature that was not produced gives its allowance back.
+        policy::release(&config, "user", "id", "s2").await.unwrap();
+        policy::release(&config, "user", "id", "s2").await.unwrap();
+        let left = spent().await;
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].session_id.as_deref(), Some("s1"));
+        policy::authorize(&config, "user", "id", "s3", Some(&tx), false)
+            .await
+            .unwrap();
+        // Limits are per wallet.
+        policy::authorize(&config, "user", "other", "s1", Some(&tx), false)
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn owners_cannot_lift_their_own_limits() {
+        let config = local_auth_config("sub");
+        let storage = config.db.clone();
+        let mut batch = db::Batch::new();
+        batch.index_wallet("user-1", "wallet", gc::now(), None);
+        batch.index_wallet("admin-1", "wallet", gc::now(), None);
+        db::insert_many(storage.as_ref(), batch).await.unwrap();
+        let client = local_client(config).await;
+        let owner = user_token("user-1", &[]);
+        let admin = user_token("admin-1", &["policy_admin"]);
+        let limited = Policy {
+            daily_limit: Some(U256::from(5000)),
+            ..Policy::default()
+        };
+        let put = |token: &str, owner: &str, policy: &Policy| {
+            client
+                .put(format!("/ecdsa/wallet/policy?owner={}", owner))
+                .header(ContentType::JSON)
+                .header(bearer(token))
+                .body(serde_json::to_string(policy).unwrap())
+                .dispatch()
+        };
+        let delete = |token: &str| {
+            client
+                .delete("/ecdsa/wallet/policy?owner=user-1")
+                .header(bearer(token))
+                .dispatch()
+        };
+
+        assert_eq!(put(&admin, "user-1", &limited).await.status(), Status::Ok);
+        // Neither a weaker policy nor none at all, from the owner's token.
+        assert_eq!(
+            put(&owner, "user-1", &Policy::default()).await.status(),
+            Status::Forbidden
+        );
+        assert_eq!(delete(&owner).await.status(), Status::Forbidden);
+        let response = client
+            .get("/ecdsa/wallet/policy")
+            .header(bearer(&owner))
+            .dispatch()
+            .await;
+        assert_eq!(response.into_json::<Policy>().await, Some(limited.clone()));
+        // Policy admins do not set the policies of their own wallets either.
+        assert_eq!(
+            put(&admin, "admin-1", &Policy::default()).await.status(),
+            Status::Forbidden
+        );
+
+        assert_eq!(delete(&admin).await.status(), Status::NoContent);
+        let (entries, _) = audit::read_all(storage.as_ref()).await.unwrap();
+        let changes: Vec<(AuditOp, Option<String>)> = entries
+            .into_iter()
+            .map(|entry| (entry.event.operation, entry.event.detail))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    AuditOp::PolicySet,
+                    Some(format!(
+                        "set by admin-1: {}",
+                        serde_json::to_string(&limited).unwrap()
+                    ))
+                ),
+                (
+                    AuditOp::PolicyRemoved,
+                    Some("removed by admin-1".to_string())
+                ),
+            ]
+        );
+    }
+
+    fn test_user(user_id: &str, roles: &[&str]) -> ValidatedUser {
+        ValidatedUser {
+            user_id: user_id.to_string(),
+            token: String::new(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn http_status(error: anyhow::Error) -> Status {
+        error
+            .downcast_ref::<HttpError>()
+            .expect("an HTTP error")
+            .status
+    }
+
+    #[rocket::async_test]
+    async fn signatures_above_the_threshold_wait_for_approval() {
+        let config = local_auth_config("sub");
+        let db = config.db.as_ref();
+        let tx = policy_test_tx("0x1111111111111111111111111111111111111111", 1000, vec![]);
+        let rule = ApprovalRule {
+            threshold: U256::from(500),
+            required: 2,
+            approvers: vec![],
+        };
+        let approval_policy = Policy {
+            daily_limit: Some(U256::from(5000)),
+            approval: Some(rule.clone()),
+            ..Policy::default()
+        };
+        db::insert(db, "maker", "id", &PolicyStruct::Policy, &approval_policy)
+            .await
+            .unwrap();
+
+        let session = uuid::Uuid::new_v4().to_string();
+        let sign_id = format!("id_{}", session);
+        // Above the threshold: parked, nothing booked yet.
+        assert_eq!(
+            policy::authorize(&config, "maker", "id", &session, Some(&tx), false)
+                .await
+                .unwrap(),
+            Authorization::NeedsApproval(rule.clone())
+        );
+        let spent: Option<Vec<Spend>> = db::get(db, "maker", "id", &PolicyStruct::Spent)
+            .await
+            .unwrap();
+        assert!(spent.is_none());
+
+        let target = SignTarget {
+            message: BigInt::from_bytes(&tx.sighash().0),
+            x_pos_child_key: BigInt::from(0),
+            y_pos_child_key: BigInt::from(1),
+        };
+        let pending = approval::park(db, "maker", "id", &session, &target, &tx, rule.clone())
+            .await
+            .unwrap();
+        assert_eq!(pending.status, ApprovalStatus::PendingApproval);
+        assert_eq!(pending.cost, policy::max_cost(&tx));
+        // Parking again returns the same request.
+        assert_eq!(
+            approval::park(db, "maker", "id", &session, &target, &tx, rule)
+                .await
+                .unwrap(),
+            pending
+        );
+        assert!(!approval::approved_for(db, "maker", &sign_id, &target)
+            .await
+            .unwrap());
+
+        let maker = test_user("maker", &["approver"]);
+        let checker = test_user("checker-1", &["approver"]);
+        let other_checker = test_user("checker-2", &["approver"]);
+        let outsider = test_user("outsider", &[]);
+        assert_eq!(approval::list(&config, &maker).await.unwrap().len(), 1);
+        assert_eq!(approval::list(&config, &checker).await.unwrap().len(), 1);
+        assert!(approval::list(&config, &outsider).await.unwrap().is_empty());
+        assert_eq!(
+            http_status(
+                approval::get(&config, &outsider, &session)
+                    .await
+                    .unwrap_err()
+            ),
+            Status::NotFound
+        );
+
+        // The maker never approves their own signature, nobody votes twice.
+        assert_eq!(
+            http_status(
+                approval::decide(&config, &maker, &session, true, None)
+                    .await
+                    .unwrap_err()
+            ),
+            Status::Forbidden
+        );
+        let pending = approval::decide(&config, &checker, &session, true, None)
+            .await
+            .unwrap();
+        assert_eq!(pending.status, ApprovalStatus::PendingApproval);
+        assert_eq!(
+            http_status(
+                approval::decide(&config, &checker, &session, true, None)
+                    .await
+                    .unwrap_err()
+            ),
+            Status::Conflict
+        );
+        let pending = approval::decide(
+            &config,
+            &other_checker,
+            &session,
+            true,
+            Some("ok".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pending.status, ApprovalStatus::Approved);
+        assert_eq!(pending.decisions.len(), 2);
+        assert_eq!(pending.decisions[1].comment.as_deref(), Some("ok"));
+
+        // Approved for this message only, and then booked against the limits.
+        let other_target = SignTarget {
+            message: BigInt::from(1),
+            ..target.clone()
+        };
+        assert_eq!(
+            http_status(
+                approval::approved_for(db, "maker", &sign_id, &other_target)
+                    .await
+                    .unwrap_err()
+            ),
+            Status::Conflict
+        );
+        assert!(approval::approved_for(db, "maker", &sign_id, &target)
+            .await
+            .unwrap());
+        assert_eq!(
+            policy::authorize(&config, "maker", "id", &session, Some(&tx), true)
+                .await
+                .unwrap(),
+            Authorization::Granted
+        );
+        let spent: Vec<Spend> = db::get(db, "maker", "id", &PolicyStruct::Spent)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(spent.len(), 1);
+
+        // A single rejection rejects.
+        let rejected_session = uuid::Uuid::new_v4().to_string();
+        approval::park(
+            db,
+            "maker",
+            "id",
+            &rejected_session,
+            &target,
+            &tx,
+            approval_policy.approval.clone().unwrap(),
+        )
+        .await
+        .unwrap();
+        let rejected = approval::decide(&config, &checker, &rejected_session, false, None)
+            .await
+            .unwrap();
+        assert_eq!(rejected.status, ApprovalStatus::Rejected);
+        assert_eq!(
+            http_status(
+                approval::approved_for(db, "maker", &format!("id_{}", rejected_session), &target)
+                    .await
+                    .unwrap_err()
+            ),
+            Status::Forbidden
+        );
+
+        // The sweeper drops requests nobody decided on, decisions stay.
+        let undecided_session = uuid::Uuid::new_v4().to_string();
+        approval::park(
+            db,
+            "maker",
+            "id",
+            &undecided_session,
+            &target,
+            &tx,
+            approval_policy.approval.unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(approval::list(&config, &checker).await.unwrap().len(), 1);
+        let later = gc::now() + approval::APPROVAL_TTL.as_secs() + 1;
+        // The request, its index entry and its pending index entry.
+        assert_eq!(gc::sweep(db, later).await.unwrap().purged, 3);
+        assert_eq!(
+            http_status(
+                approval::get(&config, &checker, &undecided_session)
+                    .await
+                    .unwrap_err()
+            ),
+            Status::NotFound
+        );
+        // Decided requests are no longer listed, only fetched by id.
+        assert!(approval::list(&config, &checker).await.unwrap().is_empty());
+        assert_eq!(
+            approval::get(&config, &checker, &session)
+                .await
+                .unwrap()
+                .status,
+            ApprovalStatus::Approved
+        );
+        assert_eq!(
+            approval::get(&config, &checker, &rejected_session)
+                .await
+                .unwrap()
+                .status,
+            ApprovalStatus::Rejected
+        );
+    }
+
+    #[rocket::async_test]
+    async fn audit_log_is_hash_chained_and_verifiable() {
+        let config = local_auth_config("sub");
+        let db = config.db.as_ref();
+        assert_eq!(audit::verify(&[], None), Ok(0));
+
+        let first = audit::append(
+            &config,
+            AuditEvent::new("user-1", None, AuditOp::KeyGenFirst),
+        )
+        .await
+        .unwrap();
+        audit::append(
+            &config,
+            AuditEvent::new("user-2", Some("id-2"), AuditOp::KeyGenFirst),
+        )
+        .await
+        .unwrap();
+        let last = audit::append(
+            &config,
+            AuditEvent::new("user-1", Some("id-1"), AuditOp::SignSecond)
+                .message(&BigInt::from(0xbeef))
+                .policy(PolicyOutcome::Refused {
+                    reason: "recipient denied".to_string(),
+                }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.prev_hash, H256::zero());
+        assert_eq!(last.seq, 2);
+        assert_eq!(last.event.message_hash.as_deref(), Some("0xbeef"));
+
+        // Pages only hold the caller's entries.
+        let page = audit::page(db, "user-1", None, 1).await.unwrap();
+        assert_eq!(page.entries, vec![first.clone()]);
+        assert_eq!(page.next, Some(0));
+        let page = audit::page(db, "user-1", page.next, 1).await.unwrap();
+        assert_eq!(page.entries, vec![last.clone()]);
+        assert_eq!(page.next, None);
+        assert!(audit::page(db, "nobody", None, 10)
+            .await
+            .unwrap()
+            .entries
+            .is_empty());
+
+        let (entries, head) = audit::read_all(db).await.unwrap();
+        assert_eq!(audit::verify(&entries, head.as_ref()), Ok(3));
+
+        let mut edited = entries.clone();
+        edited[1].event.user_id = "someone-else".to_string();
+        assert_eq!(
+            audit::verify(&edited, head.as_ref()),
+            Err(ChainError::Tampered { seq: 1 })
+        );
+        let mut rehashed: Vec<AuditEntry> = edited;
+        rehashed[1].hash = rehashed[1].compute_hash().unwrap();
+        assert_eq!(
+            audit::verify(&rehashed, head.as_ref()),
+            Err(ChainError::BrokenLink { seq: 2 })
+        );
+        let mut dropped = entries.clone();
+        dropped.remove(1);
+        assert_eq!(
+            audit::verify(&dropped, head.as_ref()),
+            Err(ChainError::Gap {
+                expected: 1,
+                found: 2
+            })
+        );
+        assert!(matches!(
+            audit::verify(&entries[..2], head.as_ref()),
+            Err(ChainError::HeadMismatch { .. })
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn concurrent_audit_appends_chain_one_after_the_other() {
+        let config = AppConfig {
+            db: Arc::new(FailingStorage::default()),
+            ..local_auth_config("sub")
+        };
+        // Every append reads the same head before any of them writes.
+        let appends = (0..8).map(|n| {
+            audit::append(
+                &config,
+                AuditEvent::new(&format!("user-{}", n), None, AuditOp::KeyGenFirst),
+            )
+        });
+        let mut seqs: Vec<u64> = futures::future::join_all(appends)
+            .await
+            .into_iter()
+            .map(|entry| entry.unwrap().seq)
+            .collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, (0..8).collect::<Vec<u64>>());
+        let (entries, head) = audit::read_all(config.db.as_ref()).await.unwrap();
+        assert_eq!(audit::verify(&entries, head.as_ref()), Ok(8));
+    }
+
+    #[rocket::async_test]
+    async fn signing_and_rotation_fail_closed_without_the_audit_log() {
+        let storage = Arc::new(FailingStorage::default());
+        storage.refuse_class(keys::KeyClass::Audit);
+        let config = AppConfig {
+            db: storage,
+            ..local_auth_config("sub")
+        };
+        db::insert(
+            config.db.as_ref(),
+            "user-1",
+            "wallet",
+            &ecdsa::EcdsaStruct::Party1MasterKey,
+            &BigInt::from(1),
+        )
+        .await
+        .unwrap();
+        let client = local_client(config).await;
+        let token = user_token("user-1", &[]);
+
+        let response = client
+            .post("/ecdsa/rotate/wallet/first")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        // Reads are still served.
+        let response = client
+            .get("/ecdsa/wallets")
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn refused_rotation_proofs_are_audited() {
+        use curv::cryptographic_primitives::proofs::sigma_dlog::{DLogProof, ProveDLog};
+
+        let config = local_auth_config("sub");
+        let storage = config.db.clone();
+        let (master_key, _) = local_master_keys();
+        let mut batch = db::Batch::new();
+        batch
+            .insert(
+                "user-1",
+                "wallet",
+                &ecdsa::EcdsaStruct::RotatePrivateNew,
+                &master_key,
+            )
+            .unwrap();
+        session::start(
+            &mut batch,
+            "user-1",
+            "wallet",
+            SessionState::RotationPending,
+        )
+        .unwrap();
+        db::insert_many(storage.as_ref(), batch).await.unwrap();
+        let client = local_client(config).await;
+
+        let other: FE = ECScalar::new_random();
+        let response = client
+            .post("/ecdsa/rotate/wallet/third")
+            .header(ContentType::JSON)
+            .header(bearer(&user_token("user-1", &[])))
+            .body(serde_json::to_string(&DLogProof::<GE>::prove(&other)).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let (entries, _) = audit::read_all(storage.as_ref()).await.unwrap();
+        let refused = &entries.last().unwrap().event;
+        assert_eq!(refused.operation, AuditOp::RotateThird);
+        assert_eq!(refused.wallet_id.as_deref(), Some("wallet"));
+        let detail = refused.detail.as_deref().unwrap();
+        assert!(detail.starts_with("refused:"), "{}", detail);
+        assert!(detail.ends_with("not for the rotated share of party 2"));
+        assert_eq!(
+            session::current(storage.as_ref(), "user-1", "wallet")
+                .await
+                .unwrap(),
+            SessionState::RotationPending
+        );
+    }
+
+    /// Stand-in for the HCMC vault, keeping the last master key it was sent.
+    #[derive(Default)]
+    struct FakeVault(std::sync::Mutex<Option<serde_json::Value>>);
+
+    #[rocket::post("/api/v1/storage/secret", data = "<secret>")]
+    fn vault_put(
+        vault: &rocket::State<Arc<FakeVault>>,
+        secret: rocket::serde::json::Json<serde_json::Value>,
+    ) -> Status {
+        *vault.0.lock().unwrap() = Some(secret.0["master_key"].clone());
+        Status::Ok
+    }
+
+    #[rocket::get("/api/v1/storage/secret")]
+    fn vault_get(vault: &rocket::State<Arc<FakeVault>>) -> String {
+        match vault.0.lock().unwrap().as_ref() {
+            Some(master_key) => master_key.to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Serves a `FakeVault` on a free local port, returns its base URL.
+    async fn fake_vault() -> (String, Arc<FakeVault>) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let vault = Arc::new(FakeVault::default());
+        let config = rocket::Config {
+            address: std::net::Ipv4Addr::LOCALHOST.into(),
+            port,
+            log_level: rocket::config::LogLevel::Off,
+            ..rocket::Config::debug_default()
+        };
+        tokio::spawn(
+            rocket::custom(config)
+                .mount("/", rocket::routes![vault_put, vault_get])
+                .manage(vault.clone())
+                .launch(),
+        );
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .is_ok()
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        (format!("http://127.0.0.1:{}", port), vault)
+    }
+
+    #[rocket::async_test]
+    async fn rotation_routes_swap_in_the_proven_share() {
+        use curv::cryptographic_primitives::proofs::sigma_dlog::{DLogProof, ProveDLog};
+        use curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+        use kms::rotation::two_party::party2::Rotation2;
+
+        let (hcmc_api, vault) = fake_vault().await;
+        let config = AppConfig {
+            hcmc_api,
+            ..local_auth_config("sub")
+        };
+        let storage = config.db.clone();
+        let (master_key1, master_key2) = local_master_keys();
+        db::insert(
+            storage.as_ref(),
+            "user-1",
+            "wallet",
+            &ecdsa::EcdsaStruct::Party1MasterKey,
+            &master_key1,
+        )
+        .await
+        .unwrap();
+        let client = local_client(config).await;
+        let token = user_token("user-1", &[]);
+
+        let response = client
+            .post("/ecdsa/rotate/wallet/first")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let party1_first: coin_flip_optimal_rounds::Party1FirstMessage<GE> =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+
+        let party2_first = Rotation2::key_rotate_first_message(&party1_first);
+        let response = client
+            .post("/ecdsa/rotate/wallet/second")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(serde_json::to_string(&party2_first).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let (party1_second, rotation_message): (
+            coin_flip_optimal_rounds::Party1SecondMessage<GE>,
+            party1::RotationParty1Message1,
+        ) = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+
+        let random2 =
+            Rotation2::key_rotate_second_message(&party1_second, &party2_first, &party1_first);
+        let rotated2 = master_key2
+            .rotate_first_message(&random2, &rotation_message)
+            .expect("party 2 accepts the proofs of the new Paillier key");
+        // The current key stays in use until party 2 proved its new share.
+        let current: MasterKey1 = db::get(
+            storage.as_ref(),
+            "user-1",
+            "wallet",
+            &ecdsa::EcdsaStruct::Party1MasterKey,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(current.public.p2, master_key1.public.p2);
+
+        let response = client
+            .post("/ecdsa/rotate/wallet/third")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(serde_json::to_string(&DLogProof::<GE>::prove(&party2_share(&rotated2))).unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let version: rotation::KeyVersion =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(version.version, 2);
+        assert_eq!(version.unconfirmed.as_ref().unwrap().previous, 1);
+
+        // The vault and the DB hold the rotated key, matching party 2's.
+        let vaulted: MasterKey1 =
+            serde_json::from_value(vault.0.lock().unwrap().clone().unwrap()).unwrap();
+        let swapped: MasterKey1 = db::get(
+            storage.as_ref(),
+            "user-1",
+            "wallet",
+            &ecdsa::EcdsaStruct::Party1MasterKey,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        for rotated in [&vaulted, &swapped] {
+            assert_eq!(rotated.public.q, master_key1.public.q);
+            assert_eq!(rotated.public.p2, rotated2.public.p2);
+        }
+
+        let response = client
+            .post("/ecdsa/rotate/wallet/finalize")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let version: rotation::KeyVersion =
+            serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(version.version, 2);
+        assert_eq!(version.unconfirmed, None);
+        let previous: Option<rotation::VersionedKey<MasterKey1>> = db::get(
+            storage.as_ref(),
+            "user-1",
+            "wallet",
+            &ecdsa::EcdsaStruct::Party1MasterKeyPrevious,
+        )
+        .await
+        .unwrap();
+        assert!(previous.is_none());
+
+        let (entries, _) = audit::read_all(storage.as_ref()).await.unwrap();
+        let operations: Vec<AuditOp> = entries.iter().map(|e| e.event.operation).collect();
+        assert_eq!(
+            operations,
+            vec![
+                AuditOp::RotateFirst,
+                AuditOp::RotateSecond,
+                AuditOp::RotateThird,
+                AuditOp::RotateFinalize
+            ]
+        );
+    }
 }
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use bech32::{u5, ToBase32, Variant};
+use crypto::digest::Digest;
+use crypto::ripemd160::Ripemd160;
+use crypto::sha2::Sha256;
+use curv::elliptic::curves::secp256_k1::GE;
+use curv::elliptic::curves::traits::ECPoint;
+use web3::signing::keccak256;
+use web3::types::Address;
+
+/// SEC1 compressed public key, hex encoded.
+pub fn compressed_hex(q: &GE) -> String {
+    hex::encode(&q.get_element().serialize()[..])
+}
+
+/// Last 20 bytes of the keccak hash of the uncompressed point, without the
+/// `0x04` prefix.
+pub fn eth_address(q: &GE) -> Address {
+    let uncompressed = q.pk_to_key_slice();
+    Address::from_slice(&keccak256(&uncompressed[1..])[12..])
+}
+
+/// SEC1 uncompressed public key (`0x04 | x | y`), hex encoded.
+pub fn uncompressed_hex(q: &GE) -> String {
+    hex::encode(q.pk_to_key_slice())
+}
+
+/// Native segwit v0 address paying to `hash160` of the compressed key. `hrp`
+/// is `bc` on mainnet, `tb` on testnet.
+pub fn p2wpkh_address(q: &GE, hrp: &str) -> Result<String> {
+    let mut sha = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(&q.get_element().serialize()[..]);
+    hasher.result(&mut sha);
+
+    let mut hash160 = [0u8; 20];
+    let mut hasher = Ripemd160::new();
+    hasher.input(&sha);
+    hasher.result(&mut hash160);
+
+    let mut data = vec![u5::try_from_u8(0)?];
+    data.extend(hash160.to_base32());
+    Ok(bech32::encode(hrp, data, Variant::Bech32)?)
+}
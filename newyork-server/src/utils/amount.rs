@@ -0,0 +1,160 @@
+use std::fmt;
+
+use web3::types::U256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EthUnit {
+    Wei,
+    Gwei,
+    Ether,
+}
+
+impl EthUnit {
+    pub const ALL: [EthUnit; 3] = [EthUnit::Wei, EthUnit::Gwei, EthUnit::Ether];
+
+    pub fn decimals(&self) -> usize {
+        match self {
+            EthUnit::Wei => 0,
+            EthUnit::Gwei => 9,
+            EthUnit::Ether => 18,
+        }
+    }
+
+    pub fn wei(&self) -> U256 {
+        U256::exp10(self.decimals())
+    }
+}
+
+impl fmt::Display for EthUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EthUnit::Wei => "wei",
+            EthUnit::Gwei => "gwei",
+            EthUnit::Ether => "ether",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A JSON amount. Fractional values have to be sent as decimal strings, JSON
+/// numbers are only exact as integers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AmountValue {
+    Number(serde_json::Number),
+    Decimal(String),
+}
+
+/// `{"value": "0.015", "unit": "ether"}`, converted to wei without going
+/// through floating point.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EthAmount {
+    pub value: AmountValue,
+    pub unit: EthUnit,
+}
+
+impl EthAmount {
+    pub fn to_wei(&self) -> Result<U256, AmountError> {
+        match &self.value {
+            AmountValue::Decimal(value) => parse_units(value, self.unit),
+            AmountValue::Number(number) => match number.as_u64() {
+                Some(value) => parse_units(&value.to_string(), self.unit),
+                None if number.as_i64().is_some() => Err(AmountError::Negative),
+                None => Err(AmountError::NotAnInteger(number.to_string())),
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AmountError {
+    Empty,
+    Negative,
+    Invalid(String),
+    /// A JSON number with a fraction or exponent.
+    NotAnInteger(String),
+    TooManyDecimals {
+        unit: EthUnit,
+        given: usize,
+    },
+    Overflow,
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmountError::Empty => write!(f, "Amount is empty"),
+            AmountError::Negative => write!(f, "Amount is negative"),
+            AmountError::Invalid(value) => write!(f, "Amount {:?} is not a decimal number", value),
+            AmountError::NotAnInteger(value) => write!(
+                f,
+                "Amount {} is not an integer, send fractional amounts as decimal strings",
+                value
+            ),
+            AmountError::TooManyDecimals { unit, given } => write!(
+                f,
+                "Amount has {} decimals, {} allows at most {}",
+                given,
+                unit,
+                unit.decimals()
+            ),
+            AmountError::Overflow => write!(f, "Amount does not fit in 256 bits of wei"),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+/// Exact conversion of a plain decimal (`"12"`, `"0.5"`, no sign, exponent or
+/// separators) in `unit` to wei. Trailing zeros past the unit's precision are
+/// accepted, any other digit there is an error rather than being rounded.
+pub fn parse_units(value: &str, unit: EthUnit) -> Result<U256, AmountError> {
+    if value.is_empty() {
+        return Err(AmountError::Empty);
+    }
+    if value.starts_with('-') {
+        return Err(AmountError::Negative);
+    }
+    let (integer, fraction) = match value.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (value, ""),
+    };
+    let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(integer) || (value.contains('.') && !is_digits(fraction)) {
+        return Err(AmountError::Invalid(value.to_string()));
+    }
+
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > unit.decimals() {
+        return Err(AmountError::TooManyDecimals {
+            unit,
+            given: fraction.len(),
+        });
+    }
+
+    let integer = U256::from_dec_str(integer).map_err(|_| AmountError::Overflow)?;
+    let fraction = match fraction {
+        "" => U256::zero(),
+        digits => {
+            // At most 18 digits, always fits.
+            U256::from_dec_str(digits).map_err(|_| AmountError::Invalid(value.to_string()))?
+                * U256::exp10(unit.decimals() - digits.len())
+        }
+    };
+    integer
+        .checked_mul(unit.wei())
+        .and_then(|wei| wei.checked_add(fraction))
+        .ok_or(AmountError::Overflow)
+}
+
+/// Inverse of `parse_units`, without trailing zeros: `1500000000000000000`
+/// wei is `"1.5"` ether.
+pub fn format_units(wei: U256, unit: EthUnit) -> String {
+    let (integer, fraction) = wei.div_mod(unit.wei());
+    if fraction.is_zero() {
+        return integer.to_string();
+    }
+    let fraction = format!("{:0>width$}", fraction.to_string(), width = unit.decimals());
+    format!("{}.{}", integer, fraction.trim_end_matches('0'))
+}
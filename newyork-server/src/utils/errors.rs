@@ -0,0 +1,74 @@
+use std::fmt;
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, status, Debug, Responder};
+use rocket::serde::json::Json;
+
+/// Error that should reach the client with a specific status instead of the
+/// generic 500, e.g. an out-of-order protocol call.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn new(status: Status, message: String) -> HttpError {
+        HttpError { status, message }
+    }
+
+    pub fn conflict(message: String) -> HttpError {
+        HttpError::new(Status::Conflict, message)
+    }
+
+    pub fn unauthorized(message: String) -> HttpError {
+        HttpError::new(Status::Unauthorized, message)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Route error type: an `HttpError` anywhere in the chain is answered with its
+/// status and message, everything else is logged and forwarded to the 500
+/// catcher like `rocket::response::Debug`.
+#[derive(Debug)]
+pub struct AnyhowError(pub anyhow::Error);
+
+impl<E> From<E> for AnyhowError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(e: E) -> AnyhowError {
+        AnyhowError(e.into())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AnyhowError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self.0.downcast_ref::<HttpError>() {
+            Some(e) => {
+                warn!("{}", e);
+                status::Custom(
+                    e.status,
+                    Json(ErrorBody {
+                        error: e.message.clone(),
+                    }),
+                )
+                .respond_to(req)
+            }
+            None => Debug(self.0).respond_to(req),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use web3::types::U256;
+
+/// Blocks of `eth_feeHistory` the priority fee tiers are sampled from.
+pub const FEE_HISTORY_BLOCKS: u64 = 20;
+
+/// Tip used when the sampled blocks were all empty.
+pub const DEFAULT_PRIORITY_FEE: u64 = 1_000_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeSpeed {
+    Slow,
+    Normal,
+    Fast,
+}
+
+impl Default for FeeSpeed {
+    fn default() -> FeeSpeed {
+        FeeSpeed::Normal
+    }
+}
+
+impl FeeSpeed {
+    pub const ALL: [FeeSpeed; 3] = [FeeSpeed::Slow, FeeSpeed::Normal, FeeSpeed::Fast];
+
+    /// Percentile of the per-block tips this tier pays.
+    pub fn reward_percentile(&self) -> f64 {
+        match self {
+            FeeSpeed::Slow => 10.0,
+            FeeSpeed::Normal => 50.0,
+            FeeSpeed::Fast => 90.0,
+        }
+    }
+}
+
+/// How the fees were estimated. `Legacy` is used on chains without London
+/// (no base fee) or when `eth_feeHistory` is not available.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeStrategy {
+    Eip1559,
+    Legacy,
+}
+
+/// For `Legacy` both fields are the gas price.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeTier {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeTiers {
+    pub slow: FeeTier,
+    pub normal: FeeTier,
+    pub fast: FeeTier,
+}
+
+impl FeeTiers {
+    pub fn get(&self, speed: FeeSpeed) -> &FeeTier {
+        match speed {
+            FeeSpeed::Slow => &self.slow,
+            FeeSpeed::Normal => &self.normal,
+            FeeSpeed::Fast => &self.fast,
+        }
+    }
+}
+
+fn median(mut samples: Vec<U256>) -> Option<U256> {
+    samples.sort();
+    samples.get(samples.len() / 2).copied()
+}
+
+/// Tiers from an `eth_feeHistory` response requested with the
+/// `FeeSpeed::ALL` reward percentiles. `base_fee_per_gas` holds one entry
+/// more than the sampled blocks, the base fee of the next block. Each tier
+/// tips the median of its percentile over the non-empty blocks and allows
+/// the base fee to double, which covers six full blocks in a row.
+pub fn eip1559_tiers(
+    base_fee_per_gas: &[U256],
+    gas_used_ratio: &[f64],
+    reward: &[Vec<U256>],
+) -> Option<FeeTiers> {
+    let next_base_fee = *base_fee_per_gas.last()?;
+    let mut floor = U256::zero();
+    let mut tiers = (0..FeeSpeed::ALL.len()).map(|i| {
+        let samples = reward
+            .iter()
+            .zip(gas_used_ratio)
+            .filter(|(_, ratio)| **ratio > 0.0)
+            .filter_map(|(rewards, _)| rewards.get(i).copied())
+            .collect();
+        let priority_fee = median(samples)
+            .unwrap_or_else(|| U256::from(DEFAULT_PRIORITY_FEE))
+            .max(floor);
+        floor = priority_fee;
+        FeeTier {
+            max_fee_per_gas: next_base_fee
+                .saturating_mul(U256::from(2))
+                .saturating_add(priority_fee),
+            max_priority_fee_per_gas: priority_fee,
+        }
+    });
+    Some(FeeTiers {
+        slow: tiers.next()?,
+        normal: tiers.next()?,
+        fast: tiers.next()?,
+    })
+}
+
+/// Tiers around `eth_gasPrice`: 90%, 100% and 125% of it.
+pub fn legacy_tiers(gas_price: U256) -> FeeTiers {
+    let tier = |numerator: u64, denominator: u64| {
+        let price = gas_price.saturating_mul(U256::from(numerator)) / U256::from(denominator);
+        FeeTier {
+            max_fee_per_gas: price,
+            max_priority_fee_per_gas: price,
+        }
+    };
+    FeeTiers {
+        slow: tier(9, 10),
+        normal: tier(1, 1),
+        fast: tier(5, 4),
+    }
+}
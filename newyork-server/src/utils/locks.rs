@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+type Key = (String, String);
+
+/// One async lock per `(user_id, id)`, so requests for different wallets
+/// never wait on each other. A lock is dropped from the map once nobody
+/// holds or waits for it.
+#[derive(Default)]
+pub struct KeyedLocks {
+    locks: Mutex<HashMap<Key, Arc<AsyncMutex<()>>>>,
+}
+
+impl KeyedLocks {
+    pub fn new() -> KeyedLocks {
+        KeyedLocks::default()
+    }
+
+    pub async fn lock(&self, user_id: &str, id: &str) -> KeyedGuard<'_> {
+        let key = (user_id.to_string(), id.to_string());
+        let lock = self
+            .locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        KeyedGuard {
+            locks: self,
+            key,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+pub struct KeyedGuard<'a> {
+    locks: &'a KeyedLocks,
+    key: Key,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<'a> Drop for KeyedGuard<'a> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self
+            .locks
+            .locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Only the map still refers to it, no one holds or waits for it.
+        if locks
+            .get(&self.key)
+            .map_or(false, |lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
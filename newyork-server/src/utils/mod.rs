@@ -1,2 +1,9 @@
+pub mod address;
+pub mod amount;
+pub mod errors;
+pub mod fees;
+pub mod locks;
 pub mod requests;
 pub mod settings;
+pub mod signature;
+pub mod transaction;
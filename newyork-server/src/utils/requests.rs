@@ -1,7 +1,9 @@
 use anyhow::{anyhow, Result};
 use reqwest::RequestBuilder;
-use rocket::State;
+use rocket::http::Status;
 
+use crate::auth::jwt::{unverified_claims, AuthMode, Claims, JwksUnavailable};
+use crate::utils::errors::HttpError;
 use crate::{auth::guards::AuthPayload, AppConfig};
 
 pub struct HttpClient {
@@ -26,24 +28,84 @@ pub async fn post(client: &HttpClient, path: &str) -> RequestBuilder {
     client.c.post(format!("{}{}", client.base_url, path))
 }
 
-pub async fn validate_auth_token(
-    state: &State<AppConfig>,
-    auth_payload: &AuthPayload,
-) -> Result<()> {
+/// Who a validated token was issued to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    /// Values of the `role_claim`.
+    pub roles: Vec<String>,
+}
+
+/// Validates the bearer token and returns who it was issued to: the
+/// `user_claim` of its claims plus the roles of the user. A `user_id` header
+/// naming anyone else is rejected, so a token only ever opens its own user's
+/// namespace.
+pub async fn authenticate(state: &AppConfig, auth_payload: &AuthPayload) -> Result<Identity> {
+    let claims = token_claims(state, &auth_payload.token).await?;
+    let user_id = claims.get(&state.user_claim).ok_or_else(|| {
+        anyhow!(HttpError::unauthorized(format!(
+            "Token has no {} claim",
+            state.user_claim
+        )))
+    })?;
+
+    if !auth_payload.user_id.is_empty() && auth_payload.user_id != user_id {
+        warn!(
+            "Token of user {} used for user_id {}",
+            user_id, auth_payload.user_id
+        );
+        return Err(anyhow!(HttpError::new(
+            Status::Forbidden,
+            "user_id does not match the authenticated user".to_string()
+        )));
+    }
+    Ok(Identity {
+        user_id: user_id.to_string(),
+        roles: claims.strings(&state.role_claim),
+    })
+}
+
+async fn token_claims(state: &AppConfig, token: &str) -> Result<Claims> {
+    let verifier = match &state.auth {
+        AuthMode::Remote => return validate_auth_token_remote(state, token).await,
+        AuthMode::Local(verifier) | AuthMode::LocalWithRemoteFallback(verifier) => verifier,
+    };
+    match verifier.verify(token).await {
+        Ok(claims) => Ok(claims),
+        Err(e)
+            if e.is::<JwksUnavailable>()
+                && matches!(state.auth, AuthMode::LocalWithRemoteFallback(_)) =>
+        {
+            warn!("{}, falling back to remote token validation", e);
+            validate_auth_token_remote(state, token).await
+        }
+        Err(e) => Err(anyhow!(HttpError::unauthorized(format!(
+            "Invalid token: {}",
+            e
+        )))),
+    }
+}
+
+async fn validate_auth_token_remote(state: &AppConfig, token: &str) -> Result<Claims> {
+    // Malformed tokens are turned away without a round trip. The claims are
+    // only trusted once HCMC vouched for the token below.
+    let claims = unverified_claims(token)
+        .map_err(|e| anyhow!(HttpError::unauthorized(format!("Invalid token: {}", e))))?;
+
     let http_client = HttpClient::new(state.hcmc_api.clone());
 
     let check_token_resp = get(&http_client, "/api/v1/storage/valid")
         .await
-        .bearer_auth(&auth_payload.token)
+        .bearer_auth(token)
         .send()
         .await?;
 
     if !check_token_resp.status().is_success() {
-        return Err(anyhow!(
+        return Err(anyhow!(HttpError::unauthorized(format!(
             "Failed to validate user's token {:#?}",
             check_token_resp.text().await?
-        ));
+        ))));
     }
 
-    Ok(())
+    Ok(claims)
 }
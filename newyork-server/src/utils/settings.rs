@@ -2,6 +2,24 @@
 pub struct AppEnv {
     pub hcmc_host: String,
     pub alchemy_api: String,
+    pub db_backend: Option<String>,
+    pub db_path: Option<String>,
+    pub master_key_file: Option<String>,
+    pub master_key: Option<String>,
+    pub master_key_id: Option<String>,
+    pub key_rewrap_interval_secs: Option<u64>,
+    pub encryption_migrate_plaintext: Option<bool>,
+    pub gc_interval_secs: Option<u64>,
+    pub auth_mode: Option<String>,
+    pub jwks_file: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub jwt_user_claim: Option<String>,
+    pub jwt_role_claim: Option<String>,
+    pub approver_role: Option<String>,
+    pub policy_admin_role: Option<String>,
+    pub jwks_refresh_interval_secs: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use curv::arithmetic::traits::{Converter, Modulo};
+use curv::elliptic::curves::secp256_k1::{Secp256k1Scalar, GE};
+use curv::elliptic::curves::traits::ECScalar;
+use curv::BigInt;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+use web3::signing::recover;
+use web3::types::Address;
+
+use super::address;
+
+/// `r || s || v`, the layout Ethereum and most wallets expect.
+pub const RSV_LEN: usize = 65;
+
+/// Big endian, left padded to 32 bytes.
+pub fn to_32_bytes(n: &BigInt) -> Result<[u8; 32]> {
+    let bytes = n.to_bytes();
+    if bytes.len() > 32 {
+        return Err(anyhow!("{} does not fit in 32 bytes", n.to_hex()));
+    }
+    let mut padded = [0u8; 32];
+    padded[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(padded)
+}
+
+/// Flips `s` into the lower half of the curve order, as Ethereum (EIP-2) and
+/// Bitcoin (BIP-62) require. Negating `s` mirrors `R`, so the parity bit of
+/// the recovery id flips with it.
+pub fn normalize_low_s(signature: party_one::SignatureRecid) -> party_one::SignatureRecid {
+    let q = Secp256k1Scalar::q();
+    let negated_s = BigInt::mod_sub(&q, &signature.s, &q);
+    if signature.s <= negated_s {
+        return signature;
+    }
+    party_one::SignatureRecid {
+        s: negated_s,
+        r: signature.r,
+        recid: signature.recid ^ 1,
+    }
+}
+
+/// Low-S normalises the signature and checks it against `q` before it leaves
+/// the server: the ECDSA equation itself, and that `recid` recovers exactly
+/// `q` (compared via its ETH address) from `message`.
+pub fn finalize(
+    q: &GE,
+    message: &BigInt,
+    signature: party_one::SignatureRecid,
+) -> Result<party_one::SignatureRecid> {
+    let signature = normalize_low_s(signature);
+
+    party_one::verify(
+        &party_one::Signature {
+            r: signature.r.clone(),
+            s: signature.s.clone(),
+        },
+        q,
+        message,
+    )
+    .map_err(|e| anyhow!("Signature does not verify against the child key: {:?}", e))?;
+
+    let recovered = recover_address(&to_32_bytes(message)?, &signature)?;
+    if recovered != address::eth_address(q) {
+        return Err(anyhow!(
+            "Recovery id {} does not recover the child key",
+            signature.recid
+        ));
+    }
+    Ok(signature)
+}
+
+/// Ethereum address of the key that produced `signature` over `message`.
+pub fn recover_address(
+    message: &[u8; 32],
+    signature: &party_one::SignatureRecid,
+) -> Result<Address> {
+    let rsv = to_rsv(signature)?;
+    recover(message, &rsv[..64], signature.recid as i32)
+        .map_err(|e| anyhow!("Public key recovery failed: {:?}", e))
+}
+
+/// 65-byte `r || s || v` with `v` the raw recovery id, callers add
+/// 27 or the EIP-155 chain offset themselves.
+pub fn to_rsv(signature: &party_one::SignatureRecid) -> Result<[u8; RSV_LEN]> {
+    let mut rsv = [0u8; RSV_LEN];
+    rsv[..32].copy_from_slice(&to_32_bytes(&signature.r)?);
+    rsv[32..64].copy_from_slice(&to_32_bytes(&signature.s)?);
+    rsv[64] = signature.recid;
+    Ok(rsv)
+}
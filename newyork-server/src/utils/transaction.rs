@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Result};
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+use rlp::RlpStream;
+use web3::signing::keccak256;
+use web3::types::{Address, Bytes, H256, U256};
+
+use super::fees::FeeStrategy;
+use super::signature;
+
+/// EIP-2718 type byte of EIP-1559 transactions.
+pub const EIP1559_TX_TYPE: u8 = 2;
+
+/// An Ethereum transfer as built by the server. `Eip1559` transactions are
+/// typed (EIP-2718 type 2, empty access list), `Legacy` ones are EIP-155
+/// replay protected and pay `max_fee_per_gas` as their gas price.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnsignedTx {
+    pub fee_strategy: FeeStrategy,
+    pub chain_id: u64,
+    pub nonce: U256,
+    pub to: Address,
+    pub value: U256,
+    pub data: Bytes,
+    pub gas: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+impl UnsignedTx {
+    fn append_fields(&self, stream: &mut RlpStream) {
+        match self.fee_strategy {
+            FeeStrategy::Eip1559 => {
+                stream.append(&self.chain_id);
+                stream.append(&self.nonce);
+                stream.append(&self.max_priority_fee_per_gas);
+                stream.append(&self.max_fee_per_gas);
+                stream.append(&self.gas);
+                stream.append(&self.to);
+                stream.append(&self.value);
+                stream.append(&self.data.0);
+                stream.begin_list(0);
+            }
+            FeeStrategy::Legacy => {
+                stream.append(&self.nonce);
+                stream.append(&self.max_fee_per_gas);
+                stream.append(&self.gas);
+                stream.append(&self.to);
+                stream.append(&self.value);
+                stream.append(&self.data.0);
+            }
+        }
+    }
+
+    fn typed(&self, stream: RlpStream) -> Vec<u8> {
+        let body = stream.out().to_vec();
+        match self.fee_strategy {
+            FeeStrategy::Eip1559 => [&[EIP1559_TX_TYPE][..], &body].concat(),
+            FeeStrategy::Legacy => body,
+        }
+    }
+
+    /// The bytes whose keccak hash gets signed.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(9);
+        self.append_fields(&mut stream);
+        if self.fee_strategy == FeeStrategy::Legacy {
+            // EIP-155: chain id, then empty r and s.
+            stream.append(&self.chain_id);
+            stream.append(&0u8);
+            stream.append(&0u8);
+        }
+        self.typed(stream)
+    }
+
+    /// The message to sign, as `sign_second` expects it.
+    pub fn sighash(&self) -> H256 {
+        H256(keccak256(&self.signing_payload()))
+    }
+
+    /// The raw transaction for `eth_sendRawTransaction`. The signature is
+    /// low-S normalised first, a recovery id of 2 or 3 cannot be encoded.
+    pub fn encode_signed(&self, signature: &party_one::SignatureRecid) -> Result<Bytes> {
+        let signature = signature::normalize_low_s(signature.clone());
+        if signature.recid > 1 {
+            return Err(anyhow!(
+                "Recovery id {} cannot be encoded in a transaction",
+                signature.recid
+            ));
+        }
+        let r = U256::from_big_endian(&signature::to_32_bytes(&signature.r)?);
+        let s = U256::from_big_endian(&signature::to_32_bytes(&signature.s)?);
+
+        let mut stream = match self.fee_strategy {
+            FeeStrategy::Eip1559 => RlpStream::new_list(12),
+            FeeStrategy::Legacy => RlpStream::new_list(9),
+        };
+        self.append_fields(&mut stream);
+        match self.fee_strategy {
+            FeeStrategy::Eip1559 => stream.append(&signature.recid),
+            FeeStrategy::Legacy => {
+                stream.append(&(signature.recid as u64 + 35 + self.chain_id * 2))
+            }
+        };
+        stream.append(&r);
+        stream.append(&s);
+        Ok(Bytes(self.typed(stream)))
+    }
+}
+
+/// Hash of a signed raw transaction, as the node reports it.
+pub fn tx_hash(raw_tx: &Bytes) -> H256 {
+    H256(keccak256(&raw_tx.0))
+}